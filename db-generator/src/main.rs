@@ -31,7 +31,8 @@ fn main() {
 
     let job_queue_for_worker = job_queue.clone();
     thread::spawn(move || {
-        let mut worker = Worker::new(job_queue_for_worker, db);
+        // the snapshot is taken explicitly once the db is filled
+        let mut worker = Worker::new(job_queue_for_worker, db, vec![]);
         worker.run();
     });
 
@@ -42,7 +43,7 @@ fn main() {
     for _ in 0..args.db_len {
         let key = Alphanumeric.sample_string(&mut rng, args.key_len);
         let data = Alphanumeric.sample_string(&mut rng, args.data_len);
-        match handler.set(key, 0, 0, data) {
+        match handler.set(key, 0, 0, data.into()) {
             Ok(_) => {}
             Err(_) => {
                 println!("Failed to set key");
@@ -0,0 +1,301 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use types::types::HorcruxError;
+
+use crate::db::Value;
+use crate::snapshot::{get_key_value_from_bytes, put_key_value, Summary};
+
+// entry: <len: u32><crc32: u32><op: u8><key_len: u16><key>[<flags: u32>
+//        <exptime: u32><cas: u64><data_len: u32><data>]
+// the length and crc cover everything from the op on
+const HEADER_LEN: usize = 8;
+
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+const EVERYSEC_INTERVAL: Duration = Duration::from_secs(1);
+
+// when the log is flushed to disk, as in redis' appendfsync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // after every mutation
+    Always,
+    // at most once per second
+    EverySec,
+    // whenever the OS decides to
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!("Invalid fsync policy: {}", s)),
+        }
+    }
+}
+
+// the effect of a mutation, replaying the same entry twice is harmless
+#[derive(Debug)]
+pub enum Entry {
+    Set(String, Value),
+    Delete(String),
+}
+
+// the log is split in segments named <snapshot_path>.aof.<seq>, a new
+// segment is started right before a snapshot so that the snapshot covers
+// every older segment
+pub struct Aof {
+    snapshot_path: String,
+    seq: u64,
+    file: File,
+    // bytes of the segment that hold complete entries
+    len: u64,
+    policy: FsyncPolicy,
+    last_sync: Instant,
+    dirty: bool,
+}
+
+impl Aof {
+    // opens a segment after every existing one
+    pub fn open(snapshot_path: &str, policy: FsyncPolicy) -> Result<Self, std::io::Error> {
+        let seq = segments(snapshot_path)?
+            .last()
+            .map_or(1, |(seq, _)| seq + 1);
+        let file = create_segment(snapshot_path, seq)?;
+        Ok(Aof {
+            snapshot_path: snapshot_path.to_string(),
+            seq,
+            len: file.metadata()?.len(),
+            file,
+            policy,
+            last_sync: Instant::now(),
+            dirty: false,
+        })
+    }
+
+    // sequence number of the segment being written
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> Result<(), std::io::Error> {
+        let mut payload = BytesMut::new();
+        match entry {
+            Entry::Set(key, value) => {
+                payload.put_u8(OP_SET);
+                put_key_value(&mut payload, key, value);
+            }
+            Entry::Delete(key) => {
+                payload.put_u8(OP_DELETE);
+                payload.put_u16(key.len() as u16);
+                payload.put(key.as_bytes());
+            }
+        }
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+        buf.put_u32(payload.len() as u32);
+        buf.put_u32(crc32fast::hash(&payload));
+        buf.put(payload);
+        if let Err(err) = self.file.write_all(&buf) {
+            self.drop_partial();
+            return Err(err);
+        }
+        self.len += buf.len() as u64;
+        self.dirty = true;
+
+        match self.policy {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::EverySec if self.last_sync.elapsed() >= EVERYSEC_INTERVAL => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    // called periodically so that everysec also covers idle periods
+    pub fn tick(&mut self) -> Result<(), std::io::Error> {
+        if self.policy == FsyncPolicy::EverySec && self.dirty {
+            return self.sync();
+        }
+        Ok(())
+    }
+
+    // finishes the current segment and starts the next one
+    pub fn rotate(&mut self) -> Result<(), std::io::Error> {
+        if self.policy != FsyncPolicy::No {
+            self.sync()?;
+        }
+        self.file = create_segment(&self.snapshot_path, self.seq + 1)?;
+        self.seq += 1;
+        self.len = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    // cuts off what a failed write left of an entry, so that the next entries
+    // are not appended after it. if the segment cannot be cut the log goes on
+    // in a new one, the damaged segment then fails a strict restore
+    fn drop_partial(&mut self) {
+        if self.file.set_len(self.len).is_ok() {
+            return;
+        }
+        if let Ok(file) = create_segment(&self.snapshot_path, self.seq + 1) {
+            println!("Failed to truncate append-only log segment {}", self.seq);
+            self.file = file;
+            self.seq += 1;
+            self.len = 0;
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn segment_path(snapshot_path: &str, seq: u64) -> String {
+    format!("{}.aof.{}", snapshot_path, seq)
+}
+
+fn create_segment(snapshot_path: &str, seq: u64) -> Result<File, std::io::Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(snapshot_path, seq))
+}
+
+// existing segments of a snapshot, in the order they were written
+pub fn segments(snapshot_path: &str) -> Result<Vec<(u64, String)>, std::io::Error> {
+    let path = Path::new(snapshot_path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.aof.", name.to_string_lossy()),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut segments = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(segments),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Some(seq) = name
+            .strip_prefix(&prefix)
+            .and_then(|seq| seq.parse::<u64>().ok())
+        {
+            segments.push((seq, segment_path(snapshot_path, seq)));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// deletes the segments that a finished snapshot already covers
+pub fn remove_segments_before(snapshot_path: &str, seq: u64) -> Result<(), std::io::Error> {
+    for (_, path) in segments(snapshot_path)?
+        .into_iter()
+        .filter(|(s, _)| *s < seq)
+    {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// reads a segment, calling on_entry for every intact entry. an entry cut
+// short by a crash is ignored at the end of the newest segment, where the
+// log was being written. any other damage fails a strict read, otherwise
+// the damaged entries are skipped and the problem is reported in the summary
+pub fn read_segment<F>(
+    path: &str,
+    strict: bool,
+    newest: bool,
+    mut on_entry: F,
+) -> Result<Summary, HorcruxError>
+where
+    F: FnMut(Entry),
+{
+    let mut mem = Bytes::from(
+        fs::read(path)
+            .map_err(|err| HorcruxError::RestoreDB(format!("Failed to read {}: {}", path, err)))?,
+    );
+    let mut summary = Summary::default();
+    let damaged = |summary: &mut Summary, msg: &str| {
+        let err = HorcruxError::RestoreDB(format!("Corrupted append-only log {}: {}", path, msg));
+        if strict {
+            return Err(err);
+        }
+        summary.skipped += 1;
+        summary.error.get_or_insert(err);
+        Ok(())
+    };
+    while mem.has_remaining() {
+        let len = match mem.remaining() {
+            remaining if remaining < HEADER_LEN => None,
+            _ => Some(u32::from_be_bytes(mem[..4].try_into().unwrap()) as usize),
+        };
+        let len = match len {
+            Some(len) if mem.remaining() - HEADER_LEN >= len => len,
+            // the length may be damaged too, nothing after it can be trusted
+            _ if newest => {
+                println!("Ignoring a torn entry at the end of {}", path);
+                break;
+            }
+            _ => {
+                damaged(&mut summary, "truncated entry")?;
+                break;
+            }
+        };
+        mem.advance(4);
+        let crc = mem.get_u32();
+        let payload = mem.split_to(len);
+        if crc32fast::hash(&payload) != crc {
+            damaged(&mut summary, "checksum mismatch")?;
+            continue;
+        }
+        match decode(payload) {
+            Some(entry) => {
+                summary.loaded += 1;
+                on_entry(entry);
+            }
+            None => damaged(&mut summary, "invalid entry")?,
+        }
+    }
+    Ok(summary)
+}
+
+fn decode(mut payload: Bytes) -> Option<Entry> {
+    if !payload.has_remaining() {
+        return None;
+    }
+    let entry = match payload.get_u8() {
+        OP_SET => {
+            let (key, value) = get_key_value_from_bytes(&mut payload).ok()?;
+            Entry::Set(key, value)
+        }
+        OP_DELETE if payload.remaining() >= 2 => {
+            let key_len = payload.get_u16() as usize;
+            if payload.remaining() < key_len {
+                return None;
+            }
+            Entry::Delete(String::from_utf8(payload.split_to(key_len).to_vec()).ok()?)
+        }
+        _ => return None,
+    };
+    // the entry must fill the whole payload
+    if payload.has_remaining() {
+        return None;
+    }
+    Some(entry)
+}
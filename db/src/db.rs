@@ -1,94 +1,1170 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::Bytes;
 use chrono::Utc;
-use std::collections::HashMap;
+use std::borrow::Cow;
 use std::fs::{rename, File};
 use std::io::prelude::*;
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use types::types::HorcruxError;
 
+use crate::aof::{self, Aof, Entry, FsyncPolicy};
+use crate::eviction::EvictionPolicy;
+use crate::generation::{self, Manifest, Retention};
+use crate::map::{Map, Walk};
+use crate::memory::{Memory, MemoryStats};
+use crate::shard;
+use crate::snapshot;
+use crate::store::{LocalStore, SnapshotStore};
+
+// exptime values above this are absolute Unix timestamps, as in memcached
+const MAX_RELATIVE_EXPTIME: u32 = 60 * 60 * 24 * 30;
+
+// snapshots are read and written through buffers of this size
+const IO_CHUNK_SIZE: usize = 1024 * 1024;
+
+// the expiry sweep checks the keys in batches of this size and goes on with
+// the next batch while more than a quarter of the last one had expired, as
+// redis' active expire cycle does
+const SWEEP_BATCH: usize = 100;
+// how long one expiry sweep may take
+const SWEEP_BUDGET: Duration = Duration::from_millis(25);
+
 #[derive(Debug, Clone)]
 pub struct Value {
     pub flags: u32,
-    // exptime: u32,
-    pub data: String,
+    // absolute Unix time in seconds, 0 means the value never expires
+    pub exptime: u32,
+    // changes on every mutation, compared by the cas command
+    pub cas: u64,
+    pub data: Bytes,
+    // set by a meta delete with the I flag, cleared when the value is replaced
+    pub stale: bool,
+    // whether a client already won the right to recache this value
+    pub token_sent: bool,
+}
+
+// outcome of a read for clients that recache with the meta protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lease {
+    // the value is fresh, nobody needs to recache it
+    None,
+    // the caller is the one that should recache the value
+    Win,
+    // another client already won the right to recache the value
+    Lost,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LeaseOptions {
+    // new absolute deadline of the value
+    pub touch: Option<u32>,
+    // creates an empty value with this absolute deadline on a miss
+    pub vivify: Option<u32>,
+    // hands out the win to a client when fewer seconds than this are left
+    pub recache: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CounterResult {
+    Value(u64),
+    NotFound,
+    NonNumeric,
+    // the memory limit is reached and eviction is disabled
+    OutOfMemory,
+    // the value was updated but could not be written to the append-only log
+    LogFailed,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoreResult {
+    Stored,
+    NotStored,
+    Exists,
+    NotFound,
+    // the memory limit is reached and eviction is disabled
+    OutOfMemory,
+    // the value was stored but could not be written to the append-only log
+    LogFailed,
+}
+
+impl Value {
+    pub fn is_expired(&self, now: u32) -> bool {
+        self.exptime != 0 && self.exptime <= now
+    }
+}
+
+// converts the exptime of a request into an absolute deadline
+pub fn deadline(exptime: u32) -> u32 {
+    if exptime == 0 || exptime > MAX_RELATIVE_EXPTIME {
+        return exptime;
+    }
+    unix_now().saturating_add(exptime)
+}
+
+pub fn unix_now() -> u32 {
+    Utc::now().timestamp() as u32
+}
+
+pub const MAX_KEY_LEN: usize = 250;
+
+// memcached keys are at most 250 bytes with no spaces or control characters
+pub fn check_key(key: &[u8]) -> Result<(), &'static str> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err("invalid key length");
+    }
+    if key.iter().any(|&b| b <= b' ' || b == 0x7f) {
+        return Err("key contains space or control character");
+    }
+    Ok(())
+}
+
+// what to do when the snapshot cannot be read at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreMode {
+    // refuse to start
+    Strict,
+    // load every intact record and move the damaged file aside
+    Salvage,
+    // ignore the snapshot and the log
+    Empty,
+}
+
+impl FromStr for RestoreMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(RestoreMode::Strict),
+            "salvage" => Ok(RestoreMode::Salvage),
+            "empty" => Ok(RestoreMode::Empty),
+            _ => Err(format!("Invalid restore mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreStats {
+    // records loaded from the snapshot
+    pub loaded: usize,
+    // damaged records that could not be loaded
+    pub skipped: usize,
+    // records that expired while the server was down
+    pub expired: usize,
+    // entries replayed from the append-only log
+    pub replayed: usize,
+    // the snapshot generation restored, none for an unversioned snapshot
+    pub generation: Option<u64>,
+    // deltas applied on top of the generation
+    pub deltas: usize,
 }
 
 pub struct DB {
-    db: HashMap<String, Value>,
+    db: Map<Value>,
+    // the append-only log lives next to this path, snapshots are stored
+    // under its file name
     snapshot_path: String,
+    base: String,
+    store: Arc<dyn SnapshotStore>,
+    last_cas: u64,
+    log: Option<Aof>,
+    // counts every mutation, never decreases
+    mutations: u64,
+    // mutations covered by the last successful snapshot
+    saved: u64,
+    // keys changed since the last successful snapshot, with the mutation
+    // count at their last change
+    changed: Map<u64>,
+    retention: Retention,
+    // deltas written on top of a full snapshot before the next full one
+    max_deltas: usize,
+    // set when the restored state differs from the snapshot files
+    needs_full: bool,
+    memory: Memory,
+    // the keys the expiry sweep has yet to check, as they were when the walk
+    // started
+    sweep: Option<Walk>,
 }
 
 impl DB {
     pub fn new(snapshot_path: String) -> Self {
+        let base = generation::base_name(&snapshot_path);
         DB {
-            db: HashMap::new(),
-            snapshot_path: snapshot_path,
+            db: Map::default(),
+            store: Arc::new(LocalStore::for_snapshot(&snapshot_path)),
+            snapshot_path,
+            base,
+            last_cas: 0,
+            log: None,
+            mutations: 0,
+            saved: 0,
+            changed: Map::default(),
+            retention: Retention::default(),
+            max_deltas: 0,
+            needs_full: false,
+            memory: Memory::default(),
+            sweep: None,
         }
     }
 
-    pub fn insert(&mut self, key: String, value: Value) {
-        self.db.insert(key, value);
+    // where snapshots are kept, the directory of the snapshot path by default
+    pub fn set_store(&mut self, store: Box<dyn SnapshotStore>) {
+        self.store = Arc::from(store);
     }
 
-    pub fn get(&self, key: &str) -> Option<&Value> {
-        self.db.get(key)
+    // keeps the values in persistent maps, which freeze copies in constant
+    // time but which are slower and bigger than the plain ones
+    pub fn set_persistent(&mut self, persistent: bool) {
+        self.db.set_persistent(persistent);
+        self.changed.set_persistent(persistent);
+        self.memory.set_persistent(persistent);
+        self.sweep = None;
+        self.recount();
     }
 
-    pub fn snapshot(&self) -> Result<(), std::io::Error> {
-        let dumped = dump(self);
+    // which snapshot generations survive a new snapshot
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    // how many deltas may follow a full snapshot, 0 always writes full ones
+    pub fn set_max_deltas(&mut self, max_deltas: usize) {
+        self.max_deltas = max_deltas;
+    }
+
+    // bounds the memory taken by the values, evicting some to make room
+    // unless no_evict is set
+    pub fn set_memory_limit(&mut self, limit: Option<usize>, no_evict: bool) {
+        self.memory.set_limit(limit, no_evict);
+    }
+
+    // which keys are evicted first when the memory limit is reached
+    pub fn set_eviction_policy(&mut self, policy: Box<dyn EvictionPolicy>) {
+        self.memory.set_policy(policy);
+        self.recount();
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.memory.stats()
+    }
+
+    pub fn mutations(&self) -> u64 {
+        self.mutations
+    }
+
+    // mutations not yet covered by a successful snapshot
+    pub fn dirty(&self) -> u64 {
+        self.mutations - self.saved
+    }
+
+    // forgets the changes covered by a successful snapshot, given the
+    // mutation count when it started. changes made while it was being
+    // written stay dirty
+    pub fn mark_saved(&mut self, covered: u64) {
+        self.saved = self.saved.max(covered.min(self.mutations));
+        let memory = &mut self.memory;
+        self.changed.retain(|key, at| {
+            if *at > covered {
+                return true;
+            }
+            memory.remove_changed(key);
+            false
+        });
+        self.needs_full = false;
+    }
+
+    // starts recording mutations in the append-only log, call it after
+    // restore so that the replayed entries are not logged twice
+    pub fn open_log(&mut self, policy: FsyncPolicy) -> Result<(), std::io::Error> {
+        self.log = Some(Aof::open(&self.snapshot_path, policy)?);
+        Ok(())
+    }
+
+    // starts a new log segment, call it right before forking a snapshot
+    pub fn rotate_log(&mut self) -> Result<(), std::io::Error> {
+        match self.log.as_mut() {
+            Some(log) => log.rotate(),
+            None => Ok(()),
+        }
+    }
+
+    // fsyncs the log when the policy asks for it, called once per second
+    pub fn sync_log(&mut self) -> Result<(), std::io::Error> {
+        match self.log.as_mut() {
+            Some(log) => log.tick(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, mut value: Value) -> StoreResult {
+        if !self.reserve(&key, self.memory.item_size(&key, &value)) {
+            return StoreResult::OutOfMemory;
+        }
+        value.cas = self.next_cas();
+        let prev = self.db.insert(key.clone(), value);
+        if self.record_set(&key, prev).is_err() {
+            return StoreResult::LogFailed;
+        }
+        StoreResult::Stored
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Value> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    // stores the value only if the key does not exist yet
+    pub fn add(&mut self, key: String, value: Value) -> StoreResult {
+        if self.get(&key).is_some() {
+            return StoreResult::NotStored;
+        }
+        self.insert(key, value)
+    }
+
+    // stores the value only if the key already exists
+    pub fn replace(&mut self, key: String, value: Value) -> StoreResult {
+        if self.get(&key).is_none() {
+            return StoreResult::NotStored;
+        }
+        self.insert(key, value)
+    }
+
+    // appends data to an existing value, keeping its flags and exptime
+    pub fn append(&mut self, key: &str, data: &[u8]) -> StoreResult {
+        if self.get(key).is_none() {
+            return StoreResult::NotStored;
+        }
+        if !self.reserve(key, self.memory.size(key) + data.len()) {
+            return StoreResult::OutOfMemory;
+        }
+        let cas = self.next_cas();
+        let prev = match self.get_mut(key) {
+            Some(value) => {
+                let prev = value.clone();
+                value.data = [&value.data[..], data].concat().into();
+                value.cas = cas;
+                prev
+            }
+            None => return StoreResult::NotStored,
+        };
+        if self.record_set(key, Some(prev)).is_err() {
+            return StoreResult::LogFailed;
+        }
+        StoreResult::Stored
+    }
+
+    // prepends data to an existing value, keeping its flags and exptime
+    pub fn prepend(&mut self, key: &str, data: &[u8]) -> StoreResult {
+        if self.get(key).is_none() {
+            return StoreResult::NotStored;
+        }
+        if !self.reserve(key, self.memory.size(key) + data.len()) {
+            return StoreResult::OutOfMemory;
+        }
+        let cas = self.next_cas();
+        let prev = match self.get_mut(key) {
+            Some(value) => {
+                let prev = value.clone();
+                value.data = [data, &value.data[..]].concat().into();
+                value.cas = cas;
+                prev
+            }
+            None => return StoreResult::NotStored,
+        };
+        if self.record_set(key, Some(prev)).is_err() {
+            return StoreResult::LogFailed;
+        }
+        StoreResult::Stored
+    }
+
+    // stores the value only if nobody has modified the key since it was read
+    pub fn cas(&mut self, key: String, value: Value, cas: u64) -> StoreResult {
+        match self.get(&key) {
+            None => return StoreResult::NotFound,
+            Some(current) if current.cas != cas => return StoreResult::Exists,
+            Some(_) => {}
+        }
+        self.insert(key, value)
+    }
+
+    // returns false if the key does not exist, an error if the deletion
+    // could not be written to the append-only log
+    pub fn delete(&mut self, key: &str) -> Result<bool, std::io::Error> {
+        if self.get(key).is_none() {
+            return Ok(false);
+        }
+        match self.db.remove(key) {
+            Some(prev) => self.record_delete(key, prev)?,
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // adds delta to a decimal value, wrapping around at 64 bits like memcached
+    pub fn incr(&mut self, key: &str, delta: u64) -> CounterResult {
+        self.update_counter(key, |n| n.wrapping_add(delta))
+    }
+
+    // subtracts delta from a decimal value, stopping at 0 like memcached
+    pub fn decr(&mut self, key: &str, delta: u64) -> CounterResult {
+        self.update_counter(key, |n| n.saturating_sub(delta))
+    }
+
+    // updates the deadline of an existing key, exptime is absolute
+    pub fn touch(&mut self, key: &str, exptime: u32) -> Result<bool, std::io::Error> {
+        let prev = match self.get_mut(key) {
+            Some(value) => {
+                let prev = value.clone();
+                value.exptime = exptime;
+                prev
+            }
+            None => return Ok(false),
+        };
+        self.record_set(key, Some(prev))?;
+        Ok(true)
+    }
+
+    // reads a value for a client that recaches with the meta protocol, making
+    // sure that only one client is asked to recache a stale or missing value
+    pub fn get_with_lease(
+        &mut self,
+        key: &str,
+        opts: LeaseOptions,
+    ) -> Result<Option<(Value, Lease)>, std::io::Error> {
+        let now = unix_now();
+        let value = match self.get_mut(key) {
+            Some(value) => value,
+            None => {
+                let exptime = match opts.vivify {
+                    Some(exptime) => exptime,
+                    None => return Ok(None),
+                };
+                let mut value = Value {
+                    flags: 0,
+                    exptime,
+                    cas: 0,
+                    data: Bytes::new(),
+                    stale: false,
+                    token_sent: true,
+                };
+                // a miss when there is no room for the empty value
+                if !self.reserve(key, self.memory.item_size(key, &value)) {
+                    return Ok(None);
+                }
+                value.cas = self.next_cas();
+                self.db.insert(key.to_string(), value.clone());
+                self.record_set(key, None)?;
+                return Ok(Some((value, Lease::Win)));
+            }
+        };
+        let prev = value.clone();
+        if let Some(exptime) = opts.touch {
+            value.exptime = exptime;
+        }
+
+        let expiring = match opts.recache {
+            Some(ttl) => value.exptime != 0 && value.exptime.saturating_sub(now) < ttl,
+            None => false,
+        };
+        let lease = if value.token_sent {
+            Lease::Lost
+        } else if value.stale || expiring {
+            value.token_sent = true;
+            Lease::Win
+        } else {
+            Lease::None
+        };
+        let value = value.clone();
+        if opts.touch.is_some() {
+            self.record_set(key, Some(prev))?;
+        }
+        Ok(Some((value, lease)))
+    }
+
+    // marks a value as stale instead of deleting it, so that clients keep
+    // being served while one of them recaches it
+    pub fn invalidate(&mut self, key: &str, exptime: Option<u32>) -> Result<bool, std::io::Error> {
+        let cas = self.next_cas();
+        let prev = match self.get_mut(key) {
+            Some(value) => {
+                let prev = value.clone();
+                value.stale = true;
+                value.token_sent = false;
+                value.cas = cas;
+                if let Some(exptime) = exptime {
+                    value.exptime = exptime;
+                }
+                prev
+            }
+            None => return Ok(false),
+        };
+        self.record_set(key, Some(prev))?;
+        Ok(true)
+    }
 
-        let tmp_suffix = Utc::now().format("%+").to_string();
-        let tmp_path = format!("{}-{}", self.snapshot_path, tmp_suffix);
+    fn update_counter<F>(&mut self, key: &str, update: F) -> CounterResult
+    where
+        F: Fn(u64) -> u64,
+    {
+        let value = match self.get(key) {
+            Some(value) => value,
+            None => return CounterResult::NotFound,
+        };
+        let n = match std::str::from_utf8(&value.data).map(|data| data.parse::<u64>()) {
+            Ok(Ok(n)) => update(n),
+            _ => return CounterResult::NonNumeric,
+        };
+        let old_len = value.data.len();
+        let data = Bytes::from(n.to_string());
+        let size = self.memory.size(key) - old_len + data.len();
+        if !self.reserve(key, size) {
+            return CounterResult::OutOfMemory;
+        }
+        let cas = self.next_cas();
+        // the key was just read and reserve never evicts it
+        let value = self.db.get_mut(key).unwrap();
+        let prev = value.clone();
+        value.data = data;
+        value.cas = cas;
+        if self.record_set(key, Some(prev)).is_err() {
+            return CounterResult::LogFailed;
+        }
+        CounterResult::Value(n)
+    }
 
-        let mut f = match File::create(tmp_path.as_str()) {
-            Ok(f) => f,
-            Err(err) => {
-                println("Failed to create snapshot file");
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        // expire lazily so that a dead key is never returned
+        if self.db.get(key)?.is_expired(unix_now()) {
+            self.db.remove(key);
+            self.memory.remove(key);
+            return None;
+        }
+        self.memory.touch(key);
+        self.db.get_mut(key)
+    }
+
+    // makes room for key to take size bytes, evicting the keys the policy
+    // picks if allowed. returns false if the key does not fit
+    fn reserve(&mut self, key: &str, size: usize) -> bool {
+        // a key changed for the first time since the last snapshot is also
+        // kept among the changed keys
+        let changed = match self.changed.get(key) {
+            Some(_) => 0,
+            None => self.memory.changed_size(key),
+        };
+        self.make_room(key, size + changed)
+    }
+
+    fn make_room(&mut self, key: &str, size: usize) -> bool {
+        // evicting would empty the DB without making room
+        if !self.memory.can_hold(size) {
+            return false;
+        }
+        while !self.memory.fits(key, size) {
+            let victim = match self.memory.victim(key) {
+                Some(victim) if self.memory.can_evict() => victim,
+                _ => return false,
+            };
+            self.evict(&victim);
+        }
+        true
+    }
+
+    // deletes a key to make room, the next snapshot leaves it out like a
+    // deleted one
+    fn evict(&mut self, key: &str) {
+        self.db.remove(key);
+        self.mark_changed(key);
+        self.memory.remove(key);
+        self.memory.record_eviction();
+        // the key must go to make room even if that cannot be logged, the
+        // failure is reported by the mutation that follows
+        if let Some(log) = self.log.as_mut() {
+            let _ = log.append(&Entry::Delete(key.to_string()));
+        }
+    }
+
+    // accounts for the values loaded by a restore, evicting if they do not
+    // fit in the limit
+    fn recount(&mut self) {
+        self.memory.clear();
+        for (key, value) in self.db.iter() {
+            self.memory.set(key, value);
+        }
+        for key in self.changed.keys() {
+            self.memory.add_changed(key);
+        }
+        if !self.make_room("", 0) {
+            let stats = self.memory.stats();
+            println!(
+                "Restored {} bytes of values, over the memory limit of {} bytes",
+                stats.used,
+                stats.limit.unwrap_or(0)
+            );
+        }
+    }
+
+    // logs the value just written to key and counts the mutation. when the
+    // log cannot be written the key is put back as it was, prev, so that
+    // neither the client nor the next snapshot sees a write reported as failed
+    fn record_set(&mut self, key: &str, prev: Option<Value>) -> Result<(), std::io::Error> {
+        if let (Some(log), Some(value)) = (self.log.as_mut(), self.db.get(key)) {
+            let entry = Entry::Set(key.to_string(), value.clone());
+            if let Err(err) = log.append(&entry) {
+                println!("Failed to write to append-only log: {}", err);
+                match prev {
+                    Some(prev) => self.db.insert(key.to_string(), prev),
+                    None => self.db.remove(key),
+                };
                 return Err(err);
             }
+        }
+        self.mark_changed(key);
+        if let Some(value) = self.db.get(key) {
+            self.memory.set(key, value);
+        }
+        Ok(())
+    }
+
+    // logs the deletion of key, putting prev back if it cannot be logged
+    fn record_delete(&mut self, key: &str, prev: Value) -> Result<(), std::io::Error> {
+        if let Some(log) = self.log.as_mut() {
+            if let Err(err) = log.append(&Entry::Delete(key.to_string())) {
+                println!("Failed to write to append-only log: {}", err);
+                self.db.insert(key.to_string(), prev);
+                return Err(err);
+            }
+        }
+        self.mark_changed(key);
+        self.memory.remove(key);
+        Ok(())
+    }
+
+    fn mark_changed(&mut self, key: &str) {
+        self.mutations += 1;
+        if self
+            .changed
+            .insert(key.to_string(), self.mutations)
+            .is_none()
+        {
+            self.memory.add_changed(key);
+        }
+    }
+
+    fn next_cas(&mut self) -> u64 {
+        self.last_cas += 1;
+        self.last_cas
+    }
+
+    // removes some of the expired keys, checking a few batches of keys from
+    // where the last sweep stopped, and returns how many were removed. the
+    // keys a sweep does not reach are still expired lazily when read
+    pub fn remove_expired(&mut self) -> usize {
+        let started = Instant::now();
+        let now = unix_now();
+        let mut removed = 0;
+        loop {
+            let sweep = self.sweep.get_or_insert_with(|| self.db.walk());
+            let keys = sweep.take(SWEEP_BATCH).collect::<Vec<_>>();
+            // the next sweep starts a new walk
+            if keys.len() < SWEEP_BATCH {
+                self.sweep = None;
+            }
+
+            let mut expired = 0;
+            for key in keys.iter() {
+                // the key may have been deleted since the walk started
+                if self.db.get(key).is_some_and(|value| value.is_expired(now)) {
+                    self.db.remove(key);
+                    self.memory.remove(key);
+                    expired += 1;
+                }
+            }
+            removed += expired;
+            if self.sweep.is_none()
+                || expired * 4 <= keys.len()
+                || started.elapsed() >= SWEEP_BUDGET
+            {
+                return removed;
+            }
+        }
+    }
+
+    // the values that have not expired, in no particular order
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Value)> {
+        let now = unix_now();
+        self.db
+            .iter()
+            .filter(move |(_, value)| !value.is_expired(now))
+    }
+
+    // stores a value taken from another DB, keeping its cas so that tokens
+    // held by clients stay valid. a value that does not fit is refused, the
+    // caller still holds it and can give up the move
+    pub fn import(&mut self, key: String, value: Value) -> StoreResult {
+        if !self.reserve(&key, self.memory.item_size(&key, &value)) {
+            return StoreResult::OutOfMemory;
+        }
+        self.last_cas = self.last_cas.max(value.cas);
+        let prev = self.db.insert(key.clone(), value);
+        if self.record_set(&key, prev).is_err() {
+            return StoreResult::LogFailed;
+        }
+        StoreResult::Stored
+    }
+
+    // deletes the values this DB does not own as shard_id of `shards` and
+    // returns how many were deleted
+    pub fn drop_foreign(&mut self, shard_id: usize, shards: usize) -> usize {
+        let foreign = self
+            .entries()
+            .filter(|(key, _)| shard::shard_for(key, shards) != shard_id)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in foreign.iter() {
+            // the snapshot taken after a reshard no longer holds them
+            let _ = self.delete(key);
+        }
+        foreign.len()
+    }
+
+    // deletes every snapshot and log segment stored for this DB
+    pub fn discard(&self) -> Result<(), std::io::Error> {
+        generation::remove_all(self.store.as_ref(), &self.base)?;
+        aof::remove_segments_before(&self.snapshot_path, u64::MAX)
+    }
+
+    // stored snapshot generations, oldest first
+    pub fn generations(&self) -> Result<Vec<(u64, String)>, std::io::Error> {
+        generation::generations(self.store.as_ref(), &self.base)
+    }
+
+    // deltas stored on top of a generation, in the order they apply
+    pub fn deltas(&self, generation: &str) -> Result<Vec<(u64, String)>, std::io::Error> {
+        generation::deltas(self.store.as_ref(), generation)
+    }
+
+    // the DB as it is now, for a snapshot written while it keeps changing.
+    // the copy takes constant time when the maps are persistent, and time and
+    // memory in proportion to the DB otherwise
+    pub fn freeze(&self) -> FrozenDB<'static> {
+        self.frozen(
+            Cow::Owned(self.db.clone()),
+            Cow::Owned(self.changed.clone()),
+        )
+    }
+
+    // writes a snapshot of the DB as it is, without copying it
+    pub fn snapshot(&self) -> Result<(), std::io::Error> {
+        self.frozen(Cow::Borrowed(&self.db), Cow::Borrowed(&self.changed))
+            .snapshot()
+    }
+
+    fn frozen<'a>(&self, db: Cow<'a, Map<Value>>, changed: Cow<'a, Map<u64>>) -> FrozenDB<'a> {
+        FrozenDB {
+            db,
+            changed,
+            snapshot_path: self.snapshot_path.clone(),
+            base: self.base.clone(),
+            store: self.store.clone(),
+            retention: self.retention,
+            max_deltas: self.max_deltas,
+            needs_full: self.needs_full,
+            log_seq: self.log.as_ref().map_or(u64::MAX, |log| log.seq()),
+        }
+    }
+
+    pub fn restore(&mut self, mode: RestoreMode) -> Result<RestoreStats, HorcruxError> {
+        let mut stats = RestoreStats::default();
+        if mode == RestoreMode::Empty {
+            println!("{:?}: Starting with an empty DB", now());
+            return Ok(stats);
+        }
+        match self.latest_generation(mode)? {
+            Some((id, name)) => {
+                stats.generation = Some(id);
+                self.restore_generation(&name, mode, &mut stats)?;
+            }
+            None => self.restore_unversioned(mode, &mut stats)?,
+        }
+        self.replay_log(mode, &mut stats)?;
+        self.recount();
+        Ok(stats)
+    }
+
+    // rolls back to an older generation. the log only holds mutations made
+    // after the latest generation, so it is discarded along with the newer
+    // history, and the manifest is pointed at the generation restored
+    pub fn restore_from(
+        &mut self,
+        mode: RestoreMode,
+        id: u64,
+    ) -> Result<RestoreStats, HorcruxError> {
+        let exists = generation::generations(self.store.as_ref(), &self.base)
+            .map_err(list_error)?
+            .iter()
+            .any(|(existing, _)| *existing == id);
+        if !exists {
+            return Err(HorcruxError::RestoreDB(format!(
+                "Snapshot generation {} does not exist",
+                id
+            )));
+        }
+        let mut stats = RestoreStats {
+            generation: Some(id),
+            ..Default::default()
         };
-        f.write_all(&dumped).unwrap();
-        f.sync_all()?;
-        rename(tmp_path.as_str(), self.snapshot_path.as_str())?;
+        let name = generation::generation_name(&self.base, id);
+        self.restore_generation(&name, mode, &mut stats)?;
+        self.recount();
 
+        let rollback = || -> Result<(), std::io::Error> {
+            let manifest = Manifest { latest: Some(id) };
+            generation::write_manifest(self.store.as_ref(), &self.base, &manifest)?;
+            aof::remove_segments_before(&self.snapshot_path, u64::MAX)
+        };
+        rollback().map_err(|err| {
+            HorcruxError::RestoreDB(format!("Failed to roll back to generation {}: {}", id, err))
+        })?;
+        println!("{:?}: Rolled back to generation {}", now(), id);
+        Ok(stats)
+    }
+
+    // the generation named by the manifest or the newest one without a
+    // manifest, none when there is only the unversioned snapshot written by
+    // older versions
+    fn latest_generation(&self, mode: RestoreMode) -> Result<Option<(u64, String)>, HorcruxError> {
+        let store = self.store.as_ref();
+        let manifest = generation::read_manifest(store, &self.base).map_err(|err| {
+            HorcruxError::RestoreDB(format!("Failed to read snapshot manifest: {}", err))
+        })?;
+        let mut generations = generation::generations(store, &self.base).map_err(list_error)?;
+        if let Some(id) = manifest.and_then(|manifest| manifest.latest) {
+            if let Some(found) = generations.iter().find(|(existing, _)| *existing == id) {
+                return Ok(Some(found.clone()));
+            }
+            // salvage may have moved it aside on a previous start
+            if mode != RestoreMode::Salvage {
+                return Err(HorcruxError::RestoreDB(format!(
+                    "Snapshot generation {} named by the manifest is missing",
+                    id
+                )));
+            }
+            println!(
+                "Snapshot generation {} is missing, using the newest one",
+                id
+            );
+        }
+        Ok(generations.pop())
+    }
+
+    // restores a full snapshot and the deltas written on top of it
+    fn restore_generation(
+        &mut self,
+        name: &str,
+        mode: RestoreMode,
+        stats: &mut RestoreStats,
+    ) -> Result<(), HorcruxError> {
+        let path = self.store.download(name).map_err(|err| {
+            HorcruxError::RestoreDB(format!("Failed to download {}: {}", name, err))
+        })?;
+        let damaged = self.restore_snapshot(&path, mode, stats);
+        self.store.release(&path);
+        if damaged? {
+            // keep the damaged file for inspection, retention would delete
+            // it otherwise
+            let aside = format!("{}.corrupt-{}", name, now());
+            println!("Moving {} to {}", name, aside);
+            if let Err(err) = self.store.rename(name, &aside) {
+                println!("Failed to move snapshot file: {}", err);
+            }
+        }
+        self.apply_deltas(name, mode, stats)
+    }
+
+    fn restore_unversioned(
+        &mut self,
+        mode: RestoreMode,
+        stats: &mut RestoreStats,
+    ) -> Result<(), HorcruxError> {
+        let path = self.snapshot_path.clone();
+        if !Path::new(&path).exists() {
+            println!("No snapshot file, starting with an empty DB");
+            return Ok(());
+        }
+        if self.restore_snapshot(&path, mode, stats)? {
+            let aside = format!("{}.corrupt-{}", path, now());
+            println!("Moving {} to {}", path, aside);
+            if let Err(err) = rename(&path, &aside) {
+                println!("Failed to move snapshot file: {}", err);
+            }
+        }
         Ok(())
     }
 
-    pub fn restore(&mut self) {
-        println!("{:?}: Restoring DB from snapshot", now());
-        let data = match std::fs::read(&self.snapshot_path) {
-            Ok(data) => data,
-            Err(err) => {
-                println!("Failed to read snapshot file: {}", err);
+    // loads a full snapshot and returns whether it was damaged, which only
+    // happens when salvaging
+    fn restore_snapshot(
+        &mut self,
+        path: &str,
+        mode: RestoreMode,
+        stats: &mut RestoreStats,
+    ) -> Result<bool, HorcruxError> {
+        println!("{:?}: Restoring DB from {}", now(), path);
+        let file = File::open(path).map_err(|err| {
+            HorcruxError::RestoreDB(format!("Failed to read snapshot file: {}", err))
+        })?;
+        let restored_at = unix_now();
+        let reader = BufReader::with_capacity(IO_CHUNK_SIZE, file);
+        let strict = mode != RestoreMode::Salvage;
+        let summary = snapshot::read(reader, strict, |key, value| {
+            if value.is_expired(restored_at) {
+                stats.expired += 1;
                 return;
             }
-        };
-        let mut mem = Bytes::from(data);
-        while !mem.is_empty() {
-            let (key, value) = match get_key_value_from_bytes(&mut mem) {
-                Ok((key, value)) => (key, value),
-                Err(err) => {
-                    println!("{}", err);
+            // keep the restored cas so that tokens held by clients stay valid
+            self.last_cas = self.last_cas.max(value.cas);
+            self.db.insert(key, value);
+            stats.loaded += 1;
+        })?;
+        stats.skipped += summary.skipped;
+        println!(
+            "{:?}: DB restored from snapshot, {} records loaded, {} skipped",
+            now(),
+            stats.loaded,
+            stats.skipped
+        );
+        match summary.error {
+            Some(err) => {
+                println!("{}", err);
+                self.needs_full = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // applies the deltas written on top of a full snapshot, in order
+    fn apply_deltas(
+        &mut self,
+        base: &str,
+        mode: RestoreMode,
+        stats: &mut RestoreStats,
+    ) -> Result<(), HorcruxError> {
+        let deltas = generation::deltas(self.store.as_ref(), base).map_err(list_error)?;
+        for (_, name) in deltas {
+            let read_error =
+                |err| HorcruxError::RestoreDB(format!("Failed to read {}: {}", name, err));
+            let path = self.store.download(&name).map_err(read_error)?;
+            let file = File::open(&path).map_err(|err| {
+                self.store.release(&path);
+                read_error(err)
+            })?;
+            let applied_at = unix_now();
+            let reader = BufReader::with_capacity(IO_CHUNK_SIZE, file);
+            let strict = mode != RestoreMode::Salvage;
+            let summary = snapshot::read(reader, strict, |key, value| {
+                // deleted keys are recorded as expired values
+                if value.is_expired(applied_at) {
+                    self.db.remove(&key);
                     return;
                 }
+                self.last_cas = self.last_cas.max(value.cas);
+                self.db.insert(key, value);
+            });
+            self.store.release(&path);
+            let summary = summary?;
+            stats.skipped += summary.skipped;
+            stats.deltas += 1;
+            if let Some(err) = summary.error {
+                // the files no longer describe what was restored
+                self.needs_full = true;
+                println!("{} in {}", err, name);
+            }
+        }
+        if stats.deltas > 0 {
+            println!("{:?}: Applied {} snapshot deltas", now(), stats.deltas);
+        }
+        Ok(())
+    }
+
+    // applies the mutations logged after the last snapshot
+    fn replay_log(
+        &mut self,
+        mode: RestoreMode,
+        stats: &mut RestoreStats,
+    ) -> Result<(), HorcruxError> {
+        let segments = aof::segments(&self.snapshot_path).map_err(|err| {
+            HorcruxError::RestoreDB(format!("Failed to list append-only log: {}", err))
+        })?;
+        let replayed_at = unix_now();
+        let strict = mode != RestoreMode::Salvage;
+        let newest = segments.last().map(|(seq, _)| *seq);
+        for (seq, path) in segments {
+            println!("{:?}: Replaying {}", now(), path);
+            let summary = aof::read_segment(&path, strict, Some(seq) == newest, |entry| {
+                // the next snapshot must cover the replayed keys, the log
+                // is deleted once it does
+                match entry {
+                    Entry::Set(key, value) if value.is_expired(replayed_at) => {
+                        self.mark_changed(&key);
+                        self.db.remove(&key);
+                    }
+                    Entry::Set(key, value) => {
+                        self.mark_changed(&key);
+                        self.last_cas = self.last_cas.max(value.cas);
+                        self.db.insert(key, value);
+                    }
+                    Entry::Delete(key) => {
+                        self.mark_changed(&key);
+                        self.db.remove(&key);
+                    }
+                }
+            });
+            let summary = match summary {
+                Ok(summary) => summary,
+                // only a segment that cannot be read at all gets here
+                Err(err) if !strict => {
+                    println!("{}", err);
+                    self.needs_full = true;
+                    continue;
+                }
+                Err(err) => return Err(err),
             };
-            self.insert(key, value);
+            stats.replayed += summary.loaded;
+            stats.skipped += summary.skipped;
+            if let Some(err) = summary.error {
+                println!("{}", err);
+                self.needs_full = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+// the DB at one point in time. a copy of it can be written to the store from
+// another thread while the DB keeps serving
+pub struct FrozenDB<'a> {
+    db: Cow<'a, Map<Value>>,
+    changed: Cow<'a, Map<u64>>,
+    snapshot_path: String,
+    base: String,
+    store: Arc<dyn SnapshotStore>,
+    retention: Retention,
+    max_deltas: usize,
+    needs_full: bool,
+    // the log segment that was current when the DB was frozen
+    log_seq: u64,
+}
+
+impl FrozenDB<'_> {
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.db.iter()
+    }
+
+    // writes the keys changed since the last snapshot as a delta of the
+    // latest generation, or a new full generation when a delta is not
+    // possible. a full snapshot is also how the deltas get compacted: it
+    // folds them into a new base and the old generation is left to retention
+    pub fn snapshot(&self) -> Result<(), std::io::Error> {
+        match self.delta_base()? {
+            Some(base) => self.snapshot_delta(&base)?,
+            None => self.snapshot_full()?,
         }
-        println!("{:?}: DB restored from snapshot", now());
+
+        // the snapshot now holds everything logged before the current segment
+        aof::remove_segments_before(&self.snapshot_path, self.log_seq)
+    }
+
+    fn snapshot_full(&self) -> Result<(), std::io::Error> {
+        let store = self.store.as_ref();
+        let id = generation::next_id(store, &self.base)?;
+        let name = generation::generation_name(&self.base, id);
+        write_snapshot_file(store, &name, self.db.iter())?;
+        generation::write_manifest(store, &self.base, &Manifest { latest: Some(id) })?;
+        generation::prune(store, &self.base, &self.retention, id)?;
+        Ok(())
     }
+
+    fn snapshot_delta(&self, base: &str) -> Result<(), std::io::Error> {
+        let store = self.store.as_ref();
+        let n = generation::deltas(store, base)?
+            .last()
+            .map_or(1, |(n, _)| n + 1);
+        let tombstone = tombstone();
+        let entries = self
+            .changed
+            .keys()
+            .map(|key| (key, self.db.get(key).unwrap_or(&tombstone)));
+        write_snapshot_file(store, &generation::delta_name(base, n), entries)
+    }
+
+    // the generation the next delta goes on top of, none when a full
+    // snapshot is due: deltas are disabled, the restored state did not match
+    // the files, there is no base yet, the base already has enough deltas, or
+    // so much changed that a delta would not be much smaller
+    fn delta_base(&self) -> Result<Option<String>, std::io::Error> {
+        if self.max_deltas == 0 || self.needs_full || self.changed.len() > self.db.len() / 2 {
+            return Ok(None);
+        }
+        let store = self.store.as_ref();
+        let id = match generation::read_manifest(store, &self.base)?
+            .and_then(|manifest| manifest.latest)
+        {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let exists = generation::generations(store, &self.base)?
+            .iter()
+            .any(|(existing, _)| *existing == id);
+        let base = generation::generation_name(&self.base, id);
+        if !exists || generation::deltas(store, &base)?.len() >= self.max_deltas {
+            return Ok(None);
+        }
+        Ok(Some(base))
+    }
+}
+
+// writes a snapshot file to a temporary path and stores it once complete
+fn write_snapshot_file<'a, I>(
+    store: &dyn SnapshotStore,
+    name: &str,
+    entries: I,
+) -> Result<(), std::io::Error>
+where
+    I: IntoIterator<Item = (&'a String, &'a Value)>,
+{
+    let tmp_path = store.temp_path(name);
+    let f = match File::create(tmp_path.as_str()) {
+        Ok(f) => f,
+        Err(err) => {
+            println("Failed to create snapshot file");
+            return Err(err);
+        }
+    };
+    let mut writer = BufWriter::with_capacity(IO_CHUNK_SIZE, f);
+    snapshot::write(&mut writer, entries)?;
+    let f = writer.into_inner().map_err(|err| err.into_error())?;
+    f.sync_all()?;
+    store.upload(&tmp_path, name)
 }
 
-// format: <key_len: u8><key><flags: u32><data_len: u32><data>...
-fn dump(db: &DB) -> Bytes {
-    let mut dumped = BytesMut::with_capacity(db.db.len() * 100);
-    for (key, value) in db.db.iter() {
-        dumped.put_u8(key.len() as u8);
-        dumped.put(key.as_bytes());
-        dumped.put_u32(value.flags);
-        dumped.put_u32(value.data.len() as u32);
-        dumped.put(value.data.as_bytes());
+fn list_error(err: std::io::Error) -> HorcruxError {
+    HorcruxError::RestoreDB(format!("Failed to list snapshot generations: {}", err))
+}
+
+// a delta records a deleted key as a value that expired at the epoch, so
+// applying it removes the key like any expired record
+fn tombstone() -> Value {
+    Value {
+        flags: 0,
+        exptime: 1,
+        cas: 0,
+        data: Bytes::new(),
+        stale: false,
+        token_sent: false,
     }
-    return dumped.freeze();
 }
 
 // println! is not safe in child process
@@ -99,17 +1175,6 @@ fn println(msg: &str) {
     let _ = output.write(b"\n");
 }
 
-fn get_key_value_from_bytes(mem: &mut Bytes) -> Result<(String, Value), HorcruxError> {
-    let key_len = mem.get_u8() as usize;
-    let key = String::from_utf8(mem.split_to(key_len).to_vec())
-        .map_err(|_| HorcruxError::RestoreDB("Failed to parse key from snapshot".to_string()))?;
-    let flags = mem.get_u32();
-    let data_len = mem.get_u32() as usize;
-    let data = String::from_utf8(mem.split_to(data_len).to_vec())
-        .map_err(|_| HorcruxError::RestoreDB("Failed to parse data from snapshot".to_string()))?;
-    Ok((key, Value { flags, data }))
-}
-
 fn now() -> String {
     Utc::now().format("%+").to_string()
 }
@@ -117,37 +1182,841 @@ fn now() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::eviction::Eviction;
 
     #[test]
     fn test_restore() {
-        let path = "/tmp/test_restore";
-        let mut db = DB::new(path.to_string());
+        let dir = "/tmp/test_restore";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let mut db = DB::new(path.clone());
         db.insert(
             "key1".to_string(),
             Value {
                 flags: 0,
-                data: "data1".to_string(),
+                exptime: 0,
+                cas: 0,
+                data: Bytes::from("data1"),
+                stale: false,
+                token_sent: false,
             },
         );
         db.insert(
             "key2".to_string(),
             Value {
                 flags: 0,
-                data: "data2".to_string(),
+                exptime: 0,
+                cas: 0,
+                data: Bytes::from("data2"),
+                stale: false,
+                token_sent: false,
             },
         );
 
         db.snapshot().unwrap();
 
-        let mut new_db = DB::new(path.to_string());
-        new_db.restore();
+        let mut new_db = DB::new(path.clone());
+        new_db.restore(RestoreMode::Strict).unwrap();
 
         let actual_1 = new_db.get("key1").unwrap();
         assert_eq!(actual_1.flags, 0);
-        assert_eq!(actual_1.data, "data1");
+        assert_eq!(actual_1.data, "data1".as_bytes());
 
         let actual_2 = new_db.get("key2").unwrap();
         assert_eq!(actual_2.flags, 0);
-        assert_eq!(actual_2.data, "data2");
+        assert_eq!(actual_2.data, "data2".as_bytes());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_binary_data() {
+        let dir = "/tmp/test_restore_binary_data";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let mut db = DB::new(path.clone());
+        let data = Bytes::from_static(&[0x00, 0xff, 0xfe, b'\r', b'\n', 0x80]);
+        db.insert(
+            "key".to_string(),
+            Value {
+                flags: 0,
+                exptime: 0,
+                cas: 0,
+                data: data.clone(),
+                stale: false,
+                token_sent: false,
+            },
+        );
+
+        db.snapshot().unwrap();
+
+        let mut new_db = DB::new(path.clone());
+        new_db.restore(RestoreMode::Strict).unwrap();
+        assert_eq!(new_db.get("key").unwrap().data, data);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiration() {
+        let mut db = DB::new("/tmp/test_expiration".to_string());
+        db.insert(
+            "expired".to_string(),
+            Value {
+                flags: 0,
+                exptime: unix_now() - 1,
+                cas: 0,
+                data: Bytes::from("data1"),
+                stale: false,
+                token_sent: false,
+            },
+        );
+        db.insert(
+            "alive".to_string(),
+            Value {
+                flags: 0,
+                exptime: deadline(60),
+                cas: 0,
+                data: Bytes::from("data2"),
+                stale: false,
+                token_sent: false,
+            },
+        );
+
+        assert!(db.get("expired").is_none());
+        assert_eq!(db.remove_expired(), 0);
+        assert!(db.get("alive").is_some());
+
+        // the sweep removes the keys nobody reads
+        for i in 0..1000 {
+            db.insert(
+                format!("expired{}", i),
+                Value {
+                    flags: 0,
+                    exptime: unix_now() - 1,
+                    cas: 0,
+                    data: Bytes::from("data"),
+                    stale: false,
+                    token_sent: false,
+                },
+            );
+        }
+        let mut removed = 0;
+        for _ in 0..100 {
+            removed += db.remove_expired();
+        }
+        assert_eq!(removed, 1000);
+        assert_eq!(db.memory_stats().items, 1);
+        assert!(db.get("alive").is_some());
+
+        // when few keys expire, a sweep only checks one batch
+        for i in 0..1000 {
+            db.insert(
+                format!("alive{}", i),
+                Value {
+                    flags: 0,
+                    exptime: 0,
+                    cas: 0,
+                    data: Bytes::from("data"),
+                    stale: false,
+                    token_sent: false,
+                },
+            );
+        }
+        db.sweep = None;
+        assert_eq!(db.remove_expired(), 0);
+        assert_eq!(db.sweep.as_ref().unwrap().len(), 1001 - SWEEP_BATCH);
+    }
+
+    #[test]
+    fn test_storage_commands() {
+        let mut db = DB::new("/tmp/test_storage_commands".to_string());
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::copy_from_slice(data.as_bytes()),
+            stale: false,
+            token_sent: false,
+        };
+
+        assert_eq!(
+            db.replace("key".to_string(), value("a")),
+            StoreResult::NotStored
+        );
+        assert_eq!(db.append("key", b"a"), StoreResult::NotStored);
+        assert_eq!(db.add("key".to_string(), value("b")), StoreResult::Stored);
+        assert_eq!(
+            db.add("key".to_string(), value("c")),
+            StoreResult::NotStored
+        );
+        assert_eq!(db.append("key", b"c"), StoreResult::Stored);
+        assert_eq!(db.prepend("key", b"a"), StoreResult::Stored);
+        assert_eq!(db.get("key").unwrap().data, "abc".as_bytes());
+
+        let cas = db.get("key").unwrap().cas;
+        assert_eq!(
+            db.replace("key".to_string(), value("d")),
+            StoreResult::Stored
+        );
+        assert_eq!(
+            db.cas("key".to_string(), value("e"), cas),
+            StoreResult::Exists
+        );
+        let cas = db.get("key").unwrap().cas;
+        assert_eq!(
+            db.cas("key".to_string(), value("e"), cas),
+            StoreResult::Stored
+        );
+        assert_eq!(db.get("key").unwrap().data, "e".as_bytes());
+        assert_eq!(
+            db.cas("missing".to_string(), value("e"), cas),
+            StoreResult::NotFound
+        );
+
+        // only the commands that stored something are counted as changes
+        assert_eq!(db.dirty(), 5);
+        db.mark_saved(3);
+        assert_eq!(db.dirty(), 2);
+    }
+
+    #[test]
+    fn test_counter_commands() {
+        let mut db = DB::new("/tmp/test_counter_commands".to_string());
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::copy_from_slice(data.as_bytes()),
+            stale: false,
+            token_sent: false,
+        };
+        db.insert("counter".to_string(), value(&(u64::MAX - 1).to_string()));
+        db.insert("text".to_string(), value("text"));
+
+        assert_eq!(db.incr("counter", 3), CounterResult::Value(1));
+        assert_eq!(db.decr("counter", 5), CounterResult::Value(0));
+        assert_eq!(db.incr("text", 1), CounterResult::NonNumeric);
+        assert_eq!(db.incr("missing", 1), CounterResult::NotFound);
+
+        assert!(db.touch("counter", unix_now() - 1).unwrap());
+        assert!(!db.touch("counter", 0).unwrap());
+        assert!(db.delete("text").unwrap());
+        assert!(!db.delete("text").unwrap());
+    }
+
+    #[test]
+    fn test_get_with_lease() {
+        let mut db = DB::new("/tmp/test_get_with_lease".to_string());
+        let vivify = LeaseOptions {
+            vivify: Some(deadline(30)),
+            ..Default::default()
+        };
+
+        // only the first client to miss is asked to fill the value
+        assert!(db
+            .get_with_lease("key", LeaseOptions::default())
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            db.get_with_lease("key", vivify).unwrap().unwrap().1,
+            Lease::Win
+        );
+        assert_eq!(
+            db.get_with_lease("key", vivify).unwrap().unwrap().1,
+            Lease::Lost
+        );
+
+        db.insert(
+            "key".to_string(),
+            Value {
+                flags: 0,
+                exptime: 0,
+                cas: 0,
+                data: Bytes::from("data"),
+                stale: false,
+                token_sent: false,
+            },
+        );
+        assert_eq!(
+            db.get_with_lease("key", vivify).unwrap().unwrap().1,
+            Lease::None
+        );
+
+        // a stale value is still served while one client recaches it
+        assert!(db.invalidate("key", None).unwrap());
+        let (value, lease) = db
+            .get_with_lease("key", LeaseOptions::default())
+            .unwrap()
+            .unwrap();
+        assert!(value.stale);
+        assert_eq!(value.data, "data".as_bytes());
+        assert_eq!(lease, Lease::Win);
+        let (_, lease) = db
+            .get_with_lease("key", LeaseOptions::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(lease, Lease::Lost);
+    }
+
+    #[test]
+    fn test_restore_keeps_exptime() {
+        let dir = "/tmp/test_restore_keeps_exptime";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let mut db = DB::new(path.clone());
+        let exptime = deadline(60);
+        db.insert(
+            "key1".to_string(),
+            Value {
+                flags: 0,
+                exptime,
+                cas: 0,
+                data: Bytes::from("data1"),
+                stale: false,
+                token_sent: false,
+            },
+        );
+        db.insert(
+            "key2".to_string(),
+            Value {
+                flags: 0,
+                exptime: unix_now() - 1,
+                cas: 0,
+                data: Bytes::from("data2"),
+                stale: false,
+                token_sent: false,
+            },
+        );
+
+        db.snapshot().unwrap();
+
+        let mut new_db = DB::new(path.clone());
+        new_db.restore(RestoreMode::Strict).unwrap();
+
+        assert_eq!(new_db.get("key1").unwrap().exptime, exptime);
+        assert_eq!(new_db.get("key1").unwrap().cas, db.get("key1").unwrap().cas);
+        assert!(new_db.get("key2").is_none());
+
+        // new tokens must not collide with the restored ones
+        new_db.insert(
+            "key3".to_string(),
+            Value {
+                flags: 0,
+                exptime: 0,
+                cas: 0,
+                data: Bytes::from("data3"),
+                stale: false,
+                token_sent: false,
+            },
+        );
+        assert!(new_db.get("key3").unwrap().cas > new_db.get("key1").unwrap().cas);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_log() {
+        let dir = "/tmp/test_replay_log";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(data.to_string()),
+            stale: false,
+            token_sent: false,
+        };
+
+        let mut db = DB::new(path.clone());
+        db.open_log(FsyncPolicy::Always).unwrap();
+        db.insert("key1".to_string(), value("1"));
+        db.insert("key2".to_string(), value("data2"));
+        db.rotate_log().unwrap();
+        db.snapshot().unwrap();
+
+        // only the segment written after the snapshot is left
+        assert_eq!(aof::segments(&path).unwrap().len(), 1);
+
+        db.incr("key1", 41);
+        db.delete("key2").unwrap();
+        db.insert("key3".to_string(), value("data3"));
+        db.append("key3", b"!");
+
+        // a write torn by a crash must not stop the replay
+        let (_, segment) = aof::segments(&path).unwrap().pop().unwrap();
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&segment)
+            .unwrap();
+        f.write_all(&[1, 4, b'k']).unwrap();
+
+        let mut new_db = DB::new(path.clone());
+        new_db.restore(RestoreMode::Strict).unwrap();
+
+        assert_eq!(new_db.get("key1").unwrap().data, "42".as_bytes());
+        assert!(new_db.get("key2").is_none());
+        assert_eq!(new_db.get("key3").unwrap().data, "data3!".as_bytes());
+        assert_eq!(new_db.get("key3").unwrap().cas, db.get("key3").unwrap().cas);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_failed() {
+        let path = "/tmp/test_log_failed";
+        aof::remove_segments_before(path, u64::MAX).unwrap();
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(data.to_string()),
+            stale: false,
+            token_sent: false,
+        };
+
+        let mut db = DB::new(path.to_string());
+        db.open_log(FsyncPolicy::No).unwrap();
+        assert_eq!(
+            db.insert("key1".to_string(), value("1")),
+            StoreResult::Stored
+        );
+        let stored = db.get("key1").unwrap().clone();
+
+        // the next segments cannot be written to, a failed write moves the
+        // log on to the next one
+        let full = (2..7)
+            .map(|seq| format!("{}.aof.{}", path, seq))
+            .collect::<Vec<_>>();
+        for segment in full.iter() {
+            let _ = std::fs::remove_file(segment);
+            std::os::unix::fs::symlink("/dev/full", segment).unwrap();
+        }
+        db.rotate_log().unwrap();
+
+        // the mutations that cannot be logged are undone
+        assert_eq!(
+            db.insert("key2".to_string(), value("2")),
+            StoreResult::LogFailed
+        );
+        assert_eq!(db.append("key1", b"0"), StoreResult::LogFailed);
+        assert_eq!(db.incr("key1", 1), CounterResult::LogFailed);
+        assert!(db.touch("key1", 1).is_err());
+        assert!(db.delete("key1").is_err());
+        assert!(db.get("key2").is_none());
+        let current = db.get("key1").unwrap();
+        assert_eq!(current.data, stored.data);
+        assert_eq!(current.exptime, stored.exptime);
+        assert_eq!(current.cas, stored.cas);
+        assert_eq!(db.dirty(), 1);
+        assert_eq!(db.memory_stats().items, 1);
+
+        assert_eq!(
+            db.insert("key3".to_string(), value("3")),
+            StoreResult::Stored
+        );
+        for segment in full.iter() {
+            std::fs::remove_file(segment).unwrap();
+        }
+        let mut new_db = DB::new(path.to_string());
+        new_db.restore(RestoreMode::Strict).unwrap();
+        assert_eq!(new_db.get("key1").unwrap().data, "1".as_bytes());
+        assert!(new_db.get("key2").is_none());
+        assert_eq!(new_db.get("key3").unwrap().data, "3".as_bytes());
+        aof::remove_segments_before(path, u64::MAX).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_log() {
+        let dir = "/tmp/test_damaged_log";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let value = || Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from("data"),
+            stale: false,
+            token_sent: false,
+        };
+
+        let mut db = DB::new(path.clone());
+        db.open_log(FsyncPolicy::Always).unwrap();
+        for i in 0..3 {
+            db.insert(format!("key{}", i), value());
+        }
+        db.rotate_log().unwrap();
+        db.insert("key3".to_string(), value());
+
+        let (_, older) = aof::segments(&path).unwrap().remove(0);
+        let intact = std::fs::read(&older).unwrap();
+        let restore = |mode| {
+            let mut new_db = DB::new(path.clone());
+            new_db.restore(mode).map(|stats| {
+                let mut keys = new_db
+                    .entries()
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+                keys.sort();
+                (keys, stats.skipped)
+            })
+        };
+
+        // a flipped byte in the second entry of an older segment
+        let mut data = intact.clone();
+        let entry_len = data.len() / 3;
+        data[entry_len + entry_len / 2] ^= 1;
+        std::fs::write(&older, &data).unwrap();
+        assert!(restore(RestoreMode::Strict).is_err());
+        let (keys, skipped) = restore(RestoreMode::Salvage).unwrap();
+        assert_eq!(keys, vec!["key0", "key2", "key3"]);
+        assert_eq!(skipped, 1);
+
+        // only the newest segment may end with a torn entry
+        std::fs::write(&older, &intact[..intact.len() - 3]).unwrap();
+        assert!(restore(RestoreMode::Strict).is_err());
+        let (keys, skipped) = restore(RestoreMode::Salvage).unwrap();
+        assert_eq!(keys, vec!["key0", "key1", "key3"]);
+        assert_eq!(skipped, 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_modes() {
+        let dir = "/tmp/test_restore_modes";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let mut db = DB::new(path.clone());
+        for i in 0..3 {
+            db.insert(
+                format!("key{}", i),
+                Value {
+                    flags: 0,
+                    exptime: 0,
+                    cas: 0,
+                    data: Bytes::from(vec![b'x'; 40_000]),
+                    stale: false,
+                    token_sent: false,
+                },
+            );
+        }
+        db.snapshot().unwrap();
+
+        // damage the last block, the first one holds two intact records
+        let (_, latest) = db.generations().unwrap().pop().unwrap();
+        let latest = format!("{}/{}", dir, latest);
+        let mut data = std::fs::read(&latest).unwrap();
+        let len = data.len();
+        data[len - 100] ^= 1;
+        std::fs::write(&latest, &data).unwrap();
+
+        let mut new_db = DB::new(path.clone());
+        match new_db.restore(RestoreMode::Strict).err().unwrap() {
+            HorcruxError::RestoreDB(_) => {} // expected
+            _ => panic!("Expected RestoreDB error"),
+        }
+
+        let mut new_db = DB::new(path.clone());
+        let stats = new_db.restore(RestoreMode::Empty).unwrap();
+        assert_eq!(stats.loaded, 0);
+
+        let mut new_db = DB::new(path.clone());
+        let stats = new_db.restore(RestoreMode::Salvage).unwrap();
+        assert_eq!(stats.loaded, 2);
+        assert_eq!(stats.skipped, 1);
+        assert!(!std::path::Path::new(&latest).exists());
+        let moved = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.starts_with("snapshot.gen.") && name.contains(".corrupt-")
+            })
+            .count();
+        assert!(moved > 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_from() {
+        let dir = "/tmp/test_restore_from";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(data.to_string()),
+            stale: false,
+            token_sent: false,
+        };
+
+        let mut db = DB::new(path.clone());
+        db.open_log(FsyncPolicy::Always).unwrap();
+        db.insert("key".to_string(), value("good"));
+        db.rotate_log().unwrap();
+        db.snapshot().unwrap();
+        db.insert("key".to_string(), value("garbage"));
+        db.rotate_log().unwrap();
+        db.snapshot().unwrap();
+        db.insert("key2".to_string(), value("garbage"));
+
+        let generations = db.generations().unwrap();
+        assert_eq!(generations.len(), 2);
+        let (good, _) = generations[0];
+        let (bad, _) = generations[1];
+
+        let mut new_db = DB::new(path.clone());
+        let stats = new_db.restore(RestoreMode::Strict).unwrap();
+        assert_eq!(stats.generation, Some(bad));
+        assert_eq!(new_db.get("key").unwrap().data, "garbage".as_bytes());
+        assert!(new_db.get("key2").is_some());
+
+        let mut new_db = DB::new(path.clone());
+        let stats = new_db.restore_from(RestoreMode::Strict, good).unwrap();
+        assert_eq!(stats.generation, Some(good));
+        assert_eq!(new_db.get("key").unwrap().data, "good".as_bytes());
+        assert!(new_db.get("key2").is_none());
+
+        // the rollback sticks across restarts
+        let mut new_db = DB::new(path.clone());
+        let stats = new_db.restore(RestoreMode::Strict).unwrap();
+        assert_eq!(stats.generation, Some(good));
+        assert!(new_db.get("key2").is_none());
+
+        let mut new_db = DB::new(path.clone());
+        assert!(new_db.restore_from(RestoreMode::Strict, 1).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_delta_snapshots() {
+        let dir = "/tmp/test_delta_snapshots";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(data.to_string()),
+            stale: false,
+            token_sent: false,
+        };
+        let restore = || {
+            let mut db = DB::new(path.clone());
+            let stats = db.restore(RestoreMode::Strict).unwrap();
+            (db, stats)
+        };
+
+        let mut db = DB::new(path.clone());
+        db.set_max_deltas(2);
+        for i in 0..10 {
+            db.insert(format!("key{}", i), value("base"));
+        }
+        // no base yet, so the first snapshot is a full one
+        db.snapshot().unwrap();
+        db.mark_saved(db.mutations());
+        let (_, base) = db.generations().unwrap().pop().unwrap();
+
+        db.insert("key1".to_string(), value("delta1"));
+        db.delete("key2").unwrap();
+        db.snapshot().unwrap();
+        db.mark_saved(db.mutations());
+        assert_eq!(db.deltas(&base).unwrap().len(), 1);
+
+        let (mut new_db, stats) = restore();
+        assert_eq!(stats.deltas, 1);
+        assert_eq!(new_db.get("key1").unwrap().data, "delta1".as_bytes());
+        assert!(new_db.get("key2").is_none());
+        assert_eq!(new_db.get("key3").unwrap().data, "base".as_bytes());
+
+        // only the keys changed since the last snapshot are written
+        db.insert("key3".to_string(), value("delta2"));
+        db.snapshot().unwrap();
+        db.mark_saved(db.mutations());
+        let (mut new_db, stats) = restore();
+        assert_eq!(stats.deltas, 2);
+        assert_eq!(new_db.get("key1").unwrap().data, "delta1".as_bytes());
+        assert_eq!(new_db.get("key3").unwrap().data, "delta2".as_bytes());
+
+        // the base has enough deltas, they are folded into a new one
+        db.insert("key4".to_string(), value("full"));
+        db.snapshot().unwrap();
+        let generations = db.generations().unwrap();
+        assert_eq!(generations.len(), 2);
+        assert!(db.deltas(&generations[1].1).unwrap().is_empty());
+        let (mut new_db, stats) = restore();
+        assert_eq!(stats.deltas, 0);
+        assert!(new_db.get("key2").is_none());
+        assert_eq!(new_db.get("key3").unwrap().data, "delta2".as_bytes());
+        assert_eq!(new_db.get("key4").unwrap().data, "full".as_bytes());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_frozen_snapshot() {
+        let dir = "/tmp/test_frozen_snapshot";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(data.to_string()),
+            stale: false,
+            token_sent: false,
+        };
+
+        // as the thread engine keeps it
+        let mut db = DB::new(path.clone());
+        db.set_persistent(true);
+        db.insert("key1".to_string(), value("frozen"));
+        db.insert("key2".to_string(), value("frozen"));
+        let frozen = db.freeze();
+
+        // changes made after freezing are not part of the snapshot
+        db.insert("key1".to_string(), value("changed"));
+        db.delete("key2").unwrap();
+        db.insert("key3".to_string(), value("changed"));
+        std::thread::spawn(move || frozen.snapshot())
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.get("key1").unwrap().data, "changed".as_bytes());
+
+        let mut new_db = DB::new(path);
+        let stats = new_db.restore(RestoreMode::Strict).unwrap();
+        assert_eq!(stats.loaded, 2);
+        assert_eq!(new_db.get("key1").unwrap().data, "frozen".as_bytes());
+        assert_eq!(new_db.get("key2").unwrap().data, "frozen".as_bytes());
+        assert!(new_db.get("key3").is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let dir = "/tmp/test_memory_limit";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let path = format!("{}/snapshot", dir);
+        let value = |data: &str| Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(data.to_string()),
+            stale: false,
+            token_sent: false,
+        };
+        let mut db = DB::new(path.clone());
+        let size = db.memory.item_size("key0", &value("0123456789"));
+        // every key written since the last snapshot takes this much more
+        let changed = db.memory.changed_size("key0");
+        db.set_max_deltas(1);
+        db.set_memory_limit(Some(3 * size + 3 * changed), false);
+        for i in 0..3 {
+            db.insert(format!("key{}", i), value("0123456789"));
+        }
+        db.snapshot().unwrap();
+        db.mark_saved(db.mutations());
+
+        // key1 is the least recently used once key0 is read
+        db.get("key0");
+        assert_eq!(
+            db.insert("key3".to_string(), value("0123456789")),
+            StoreResult::Stored
+        );
+        assert!(db.get("key1").is_none());
+        // growing a value evicts too
+        assert_eq!(db.append("key0", b"x"), StoreResult::Stored);
+        assert!(db.get("key2").is_none());
+        let stats = db.memory_stats();
+        assert_eq!(stats.items, 2);
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.used, 2 * size + 1 + 4 * changed);
+
+        // the evicted keys are left out of the next snapshot
+        db.snapshot().unwrap();
+        db.mark_saved(db.mutations());
+        assert_eq!(db.memory_stats().used, 2 * size + 1);
+        let mut restored = DB::new(path.clone());
+        restored.restore(RestoreMode::Strict).unwrap();
+        assert!(restored.get("key1").is_none());
+        assert!(restored.get("key2").is_none());
+        assert_eq!(restored.memory_stats().used, 2 * size + 1);
+
+        // without eviction, writes that do not fit fail
+        db.set_memory_limit(Some(3 * size + 1 + changed), true);
+        assert_eq!(
+            db.insert("key4".to_string(), value("0123456789")),
+            StoreResult::Stored
+        );
+        assert_eq!(
+            db.add("key5".to_string(), value("0123456789")),
+            StoreResult::OutOfMemory
+        );
+        assert_eq!(db.append("key4", b"0123456789"), StoreResult::OutOfMemory);
+        // replacing with a smaller value still fits
+        assert_eq!(
+            db.insert("key4".to_string(), value("0")),
+            StoreResult::Stored
+        );
+        assert_eq!(db.memory_stats().evictions, 2);
+
+        // an item larger than the limit fails without evicting anything
+        db.set_memory_limit(Some(3 * size + 1), false);
+        let large = "x".repeat(3 * size);
+        assert_eq!(
+            db.insert("key6".to_string(), value(&large)),
+            StoreResult::OutOfMemory
+        );
+        assert_eq!(
+            db.append("key4", large.as_bytes()),
+            StoreResult::OutOfMemory
+        );
+        assert_eq!(db.memory_stats().items, 3);
+        assert_eq!(db.memory_stats().evictions, 2);
+
+        // a restore evicts what does not fit in a smaller limit
+        let mut restored = DB::new(path.clone());
+        restored.set_memory_limit(Some(size + 1 + 2 * changed), false);
+        restored.restore(RestoreMode::Strict).unwrap();
+        assert_eq!(restored.memory_stats().items, 1);
+
+        // volatile-ttl only evicts keys that expire
+        let mut db = DB::new(path.clone());
+        db.set_eviction_policy(Eviction::VolatileTtl.policy());
+        db.set_memory_limit(Some(2 * size + 3 * changed), false);
+        let expiring = Value {
+            exptime: unix_now() + 100,
+            ..value("0123456789")
+        };
+        db.insert("key0".to_string(), value("0123456789"));
+        db.insert("key1".to_string(), expiring);
+        assert_eq!(
+            db.insert("key2".to_string(), value("0123456789")),
+            StoreResult::Stored
+        );
+        assert!(db.get("key1").is_none());
+        assert_eq!(
+            db.insert("key3".to_string(), value("0123456789")),
+            StoreResult::OutOfMemory
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
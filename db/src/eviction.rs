@@ -0,0 +1,686 @@
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::db::Value;
+
+// picks the keys to evict when a DB reaches its memory limit. it is told
+// about every key written, read and removed, and only keeps the keys and
+// what it needs to order them
+pub trait EvictionPolicy: Send {
+    // the key was stored or modified
+    fn on_write(&mut self, key: &str, value: &Value);
+
+    fn on_read(&mut self, key: &str);
+
+    fn on_remove(&mut self, key: &str);
+
+    // the next key to evict other than keep, which is being written. none
+    // if no key may be evicted
+    fn victim(&mut self, keep: &str) -> Option<String>;
+
+    // an estimate of the memory the policy keeps for the key, with the
+    // copies of the key it holds
+    fn overhead(&self, key: &str, value: &Value) -> usize;
+
+    fn clear(&mut self);
+}
+
+// the eviction policies a DB can be configured with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Eviction {
+    // the least recently used key
+    #[default]
+    Lru,
+    // the least recently used of a few keys picked at random, like redis,
+    // without keeping the keys in order
+    ApproxLru,
+    // memcached's hot, warm and cold queues, a key read once after it was
+    // written is not evicted before the keys that were never read
+    SegmentedLru,
+    // the least frequently used of a few keys picked at random, counts decay
+    // over time so that keys that used to be hot are evicted eventually
+    Lfu,
+    // the key expiring soonest, keys without an exptime are never evicted
+    VolatileTtl,
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Eviction::Lru),
+            "approx-lru" => Ok(Eviction::ApproxLru),
+            "slru" => Ok(Eviction::SegmentedLru),
+            "lfu" => Ok(Eviction::Lfu),
+            "volatile-ttl" => Ok(Eviction::VolatileTtl),
+            _ => Err(format!("Invalid eviction policy: {}", s)),
+        }
+    }
+}
+
+impl Eviction {
+    pub fn policy(self) -> Box<dyn EvictionPolicy> {
+        match self {
+            Eviction::Lru => Box::<Lru>::default(),
+            Eviction::ApproxLru => Box::<ApproxLru>::default(),
+            Eviction::SegmentedLru => Box::<SegmentedLru>::default(),
+            Eviction::Lfu => Box::<Lfu>::default(),
+            Eviction::VolatileTtl => Box::<VolatileTtl>::default(),
+        }
+    }
+}
+
+// keys in the order they were last used in
+#[derive(Default)]
+struct Queue {
+    clock: u64,
+    // last use of every key
+    used_at: HashMap<String, u64>,
+    keys: BTreeMap<u64, String>,
+}
+
+impl Queue {
+    // both maps hold a copy of the key
+    fn overhead(key: &str) -> usize {
+        2 * key.len() + size_of::<(String, u64)>() + size_of::<(u64, String)>()
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    // moves the key to the back, adding it if needed
+    fn push(&mut self, key: &str) {
+        self.remove(key);
+        self.clock += 1;
+        self.used_at.insert(key.to_string(), self.clock);
+        self.keys.insert(self.clock, key.to_string());
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.used_at.remove(key) {
+            Some(used_at) => {
+                self.keys.remove(&used_at);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.used_at.contains_key(key)
+    }
+
+    fn front(&self, keep: &str) -> Option<&String> {
+        self.keys.values().find(|key| key.as_str() != keep)
+    }
+
+    fn pop(&mut self) -> Option<String> {
+        let (_, key) = self.keys.pop_first()?;
+        self.used_at.remove(&key);
+        Some(key)
+    }
+
+    fn clear(&mut self) {
+        self.used_at.clear();
+        self.keys.clear();
+    }
+}
+
+#[derive(Default)]
+pub struct Lru {
+    queue: Queue,
+}
+
+impl EvictionPolicy for Lru {
+    fn on_write(&mut self, key: &str, _value: &Value) {
+        self.queue.push(key);
+    }
+
+    fn on_read(&mut self, key: &str) {
+        if self.queue.contains(key) {
+            self.queue.push(key);
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.queue.remove(key);
+    }
+
+    fn victim(&mut self, keep: &str) -> Option<String> {
+        self.queue.front(keep).cloned()
+    }
+
+    fn overhead(&self, key: &str, _value: &Value) -> usize {
+        Queue::overhead(key)
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+    }
+}
+
+// how many keys the sampling policies compare, redis' default
+const SAMPLES: usize = 5;
+
+// keys with some data, any of them can be picked at random in constant time
+struct Sampled<T> {
+    keys: Vec<String>,
+    // position in keys and data of every key
+    entries: HashMap<String, (usize, T)>,
+}
+
+impl<T> Default for Sampled<T> {
+    fn default() -> Self {
+        Sampled {
+            keys: Vec::new(),
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Sampled<T> {
+    // the list and the map both hold a copy of the key
+    fn overhead(key: &str) -> usize {
+        2 * key.len() + size_of::<String>() + size_of::<(String, (usize, T))>()
+    }
+
+    fn insert(&mut self, key: &str, data: T) {
+        match self.entries.get_mut(key) {
+            Some(entry) => entry.1 = data,
+            None => {
+                self.entries
+                    .insert(key.to_string(), (self.keys.len(), data));
+                self.keys.push(key.to_string());
+            }
+        }
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        self.entries.get_mut(key).map(|(_, data)| data)
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((pos, _)) = self.entries.remove(key) {
+            self.keys.swap_remove(pos);
+            if let Some(moved) = self.keys.get(pos) {
+                self.entries.get_mut(moved).unwrap().0 = pos;
+            }
+        }
+    }
+
+    // the key other than keep with the lowest score among a few picked at
+    // random, or among all of them when there are only a few
+    fn pick<F, S>(&mut self, keep: &str, mut score: F) -> Option<String>
+    where
+        F: FnMut(&mut T) -> S,
+        S: PartialOrd,
+    {
+        let candidates = if self.keys.len() <= SAMPLES {
+            (0..self.keys.len()).collect::<Vec<_>>()
+        } else {
+            let mut rng = rand::thread_rng();
+            (0..SAMPLES)
+                .map(|_| rng.gen_range(0..self.keys.len()))
+                .collect()
+        };
+        let mut best: Option<(S, usize)> = None;
+        for pos in candidates {
+            let key = &self.keys[pos];
+            if key == keep {
+                continue;
+            }
+            let score = score(&mut self.entries.get_mut(key).unwrap().1);
+            if best.as_ref().is_none_or(|(best, _)| score < *best) {
+                best = Some((score, pos));
+            }
+        }
+        match best {
+            Some((_, pos)) => Some(self.keys[pos].clone()),
+            // every key picked was keep
+            None => self.keys.iter().find(|key| key.as_str() != keep).cloned(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.entries.clear();
+    }
+}
+
+#[derive(Default)]
+pub struct ApproxLru {
+    clock: u64,
+    // last use of every key
+    sampled: Sampled<u64>,
+}
+
+impl EvictionPolicy for ApproxLru {
+    fn on_write(&mut self, key: &str, _value: &Value) {
+        self.clock += 1;
+        self.sampled.insert(key, self.clock);
+    }
+
+    fn on_read(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(used_at) = self.sampled.get_mut(key) {
+            *used_at = self.clock;
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.sampled.remove(key);
+    }
+
+    fn victim(&mut self, keep: &str) -> Option<String> {
+        self.sampled.pick(keep, |used_at| *used_at)
+    }
+
+    fn overhead(&self, key: &str, _value: &Value) -> usize {
+        Sampled::<u64>::overhead(key)
+    }
+
+    fn clear(&mut self) {
+        self.sampled.clear();
+    }
+}
+
+// shares of the keys the hot and warm queues hold, like memcached
+const HOT_PERCENT: usize = 20;
+const WARM_PERCENT: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Hot,
+    Warm,
+    Cold,
+}
+
+// new keys enter the hot queue. keys leaving the hot or warm queue move to
+// the cold one unless they were read meanwhile, which keeps them warm, and a
+// cold key that is read is moved back to the warm queue. keys are evicted
+// from the cold queue first
+#[derive(Default)]
+pub struct SegmentedLru {
+    hot: Queue,
+    warm: Queue,
+    cold: Queue,
+    // the segment of every key and whether it was read since it entered it
+    entries: HashMap<String, (Segment, bool)>,
+}
+
+impl SegmentedLru {
+    fn queue(&mut self, segment: Segment) -> &mut Queue {
+        match segment {
+            Segment::Hot => &mut self.hot,
+            Segment::Warm => &mut self.warm,
+            Segment::Cold => &mut self.cold,
+        }
+    }
+
+    fn move_to(&mut self, key: String, segment: Segment) {
+        self.queue(segment).push(&key);
+        self.entries.insert(key, (segment, false));
+    }
+
+    // moves the keys over the share of the hot and warm queues down, each
+    // holds at least one key so that a few keys are not all cold
+    fn balance(&mut self) {
+        let total = self.entries.len();
+        let hot = (total * HOT_PERCENT / 100).max(1);
+        while self.hot.len() > hot {
+            let key = self.hot.pop().unwrap();
+            let active = self.entries[&key].1;
+            let segment = if active { Segment::Warm } else { Segment::Cold };
+            self.move_to(key, segment);
+        }
+        let warm = (total * WARM_PERCENT / 100).max(1);
+        while self.warm.len() > warm {
+            let key = self.warm.pop().unwrap();
+            // read again while warm, it gets another round
+            let segment = if self.entries[&key].1 {
+                Segment::Warm
+            } else {
+                Segment::Cold
+            };
+            self.move_to(key, segment);
+        }
+    }
+}
+
+impl EvictionPolicy for SegmentedLru {
+    fn on_write(&mut self, key: &str, _value: &Value) {
+        match self.entries.get_mut(key) {
+            // a rewrite counts as a use
+            Some((_, active)) => *active = true,
+            None => {
+                self.move_to(key.to_string(), Segment::Hot);
+                self.balance();
+            }
+        }
+    }
+
+    fn on_read(&mut self, key: &str) {
+        match self.entries.get_mut(key) {
+            Some((Segment::Cold, _)) => {
+                self.cold.remove(key);
+                self.move_to(key.to_string(), Segment::Warm);
+                self.balance();
+            }
+            Some((_, active)) => *active = true,
+            None => {}
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        if let Some((segment, _)) = self.entries.remove(key) {
+            self.queue(segment).remove(key);
+        }
+    }
+
+    fn victim(&mut self, keep: &str) -> Option<String> {
+        self.cold
+            .front(keep)
+            .or_else(|| self.warm.front(keep))
+            .or_else(|| self.hot.front(keep))
+            .cloned()
+    }
+
+    // the key is in one of the queues and in the map of segments
+    fn overhead(&self, key: &str, _value: &Value) -> usize {
+        Queue::overhead(key) + key.len() + size_of::<(String, (Segment, bool))>()
+    }
+
+    fn clear(&mut self) {
+        self.hot.clear();
+        self.warm.clear();
+        self.cold.clear();
+        self.entries.clear();
+    }
+}
+
+// counts start here so that new keys are not evicted before they are read
+const LFU_INIT: u8 = 5;
+// the higher, the more reads it takes to raise a high count, like redis
+const LFU_LOG_FACTOR: f64 = 10.0;
+// counts drop by one every this many seconds without a read
+const LFU_DECAY_SECS: u64 = 60;
+
+// an 8-bit count of reads that grows logarithmically, and the decay period
+// it was last updated in
+#[derive(Debug, Clone, Copy)]
+struct Frequency {
+    count: u8,
+    period: u64,
+}
+
+impl Frequency {
+    fn decay(&mut self, period: u64) {
+        let elapsed = period.saturating_sub(self.period);
+        self.count = self.count.saturating_sub(elapsed.min(u8::MAX as u64) as u8);
+        self.period = period;
+    }
+
+    fn increment(&mut self) {
+        if self.count == u8::MAX {
+            return;
+        }
+        let base = self.count.saturating_sub(LFU_INIT) as f64;
+        if rand::thread_rng().gen::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+            self.count += 1;
+        }
+    }
+}
+
+pub struct Lfu {
+    started: Instant,
+    sampled: Sampled<Frequency>,
+}
+
+impl Default for Lfu {
+    fn default() -> Self {
+        Lfu {
+            started: Instant::now(),
+            sampled: Sampled::default(),
+        }
+    }
+}
+
+impl Lfu {
+    fn period(&self) -> u64 {
+        self.started.elapsed().as_secs() / LFU_DECAY_SECS
+    }
+}
+
+impl EvictionPolicy for Lfu {
+    fn on_write(&mut self, key: &str, _value: &Value) {
+        let period = self.period();
+        match self.sampled.get_mut(key) {
+            Some(frequency) => {
+                frequency.decay(period);
+                frequency.increment();
+            }
+            None => self.sampled.insert(
+                key,
+                Frequency {
+                    count: LFU_INIT,
+                    period,
+                },
+            ),
+        }
+    }
+
+    fn on_read(&mut self, key: &str) {
+        let period = self.period();
+        if let Some(frequency) = self.sampled.get_mut(key) {
+            frequency.decay(period);
+            frequency.increment();
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.sampled.remove(key);
+    }
+
+    fn victim(&mut self, keep: &str) -> Option<String> {
+        let period = self.period();
+        self.sampled.pick(keep, |frequency| {
+            frequency.decay(period);
+            frequency.count
+        })
+    }
+
+    fn overhead(&self, key: &str, _value: &Value) -> usize {
+        Sampled::<Frequency>::overhead(key)
+    }
+
+    fn clear(&mut self) {
+        self.sampled.clear();
+    }
+}
+
+#[derive(Default)]
+pub struct VolatileTtl {
+    clock: u64,
+    // exptime of every key that has one
+    exptimes: HashMap<String, (u32, u64)>,
+    // keys by exptime, the ties in the order they were written
+    keys: BTreeMap<(u32, u64), String>,
+}
+
+impl EvictionPolicy for VolatileTtl {
+    fn on_write(&mut self, key: &str, value: &Value) {
+        self.on_remove(key);
+        if value.exptime == 0 {
+            return;
+        }
+        self.clock += 1;
+        let at = (value.exptime, self.clock);
+        self.exptimes.insert(key.to_string(), at);
+        self.keys.insert(at, key.to_string());
+    }
+
+    fn on_read(&mut self, _key: &str) {}
+
+    fn on_remove(&mut self, key: &str) {
+        if let Some(at) = self.exptimes.remove(key) {
+            self.keys.remove(&at);
+        }
+    }
+
+    fn victim(&mut self, keep: &str) -> Option<String> {
+        self.keys.values().find(|key| key.as_str() != keep).cloned()
+    }
+
+    // only the keys with an exptime are kept
+    fn overhead(&self, key: &str, value: &Value) -> usize {
+        if value.exptime == 0 {
+            return 0;
+        }
+        2 * key.len() + size_of::<(String, (u32, u64))>() + size_of::<((u32, u64), String)>()
+    }
+
+    fn clear(&mut self) {
+        self.exptimes.clear();
+        self.keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn value(exptime: u32) -> Value {
+        Value {
+            flags: 0,
+            exptime,
+            cas: 0,
+            data: Bytes::new(),
+            stale: false,
+            token_sent: false,
+        }
+    }
+
+    // evicts every key but keep, returning them in eviction order
+    fn drain(policy: &mut dyn EvictionPolicy, keep: &str) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while let Some(key) = policy.victim(keep) {
+            policy.on_remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    #[test]
+    fn test_lru() {
+        for eviction in [Eviction::Lru, Eviction::ApproxLru] {
+            let mut policy = eviction.policy();
+            for key in ["a", "b", "c", "d"] {
+                policy.on_write(key, &value(0));
+            }
+            policy.on_read("a");
+            policy.on_write("b", &value(0));
+            policy.on_remove("c");
+            // few keys are all sampled, so the approximation is exact
+            assert_eq!(drain(policy.as_mut(), "a"), vec!["d", "b"]);
+        }
+
+        // with more keys than samples an old key is picked: the oldest of 5
+        // keys picked at random is older than the median 97% of the time
+        let mut policy = Eviction::ApproxLru.policy();
+        for i in 0..1000 {
+            policy.on_write(&i.to_string(), &value(0));
+        }
+        // the keys below 500 become the newest ones
+        for i in 0..500 {
+            policy.on_read(&i.to_string());
+        }
+        let old = (0..200)
+            .filter(|_| policy.victim("").unwrap().parse::<usize>().unwrap() >= 500)
+            .count();
+        assert!(
+            old > 150,
+            "{} of 200 victims were older than the median",
+            old
+        );
+        policy.on_remove("0");
+        assert_eq!(drain(policy.as_mut(), "").len(), 999);
+    }
+
+    #[test]
+    fn test_segmented_lru() {
+        let mut policy = Eviction::SegmentedLru.policy();
+        for i in 0..10 {
+            policy.on_write(&format!("key{}", i), &value(0));
+            // read while hot, it is kept warm
+            if i == 0 {
+                policy.on_read("key0");
+            }
+        }
+        // a scan of new keys does not push out a key that was read
+        for i in 0..10 {
+            policy.on_write(&format!("scan{}", i), &value(0));
+        }
+        let evicted = drain(policy.as_mut(), "");
+        assert_eq!(evicted.len(), 20);
+        let position = |key: &str| evicted.iter().position(|evicted| evicted == key);
+        assert!(position("key0") > position("scan0"));
+        assert!(position("key1") < position("key2"));
+
+        // a cold key that is read moves back to the warm queue
+        let mut policy = Eviction::SegmentedLru.policy();
+        for i in 0..10 {
+            policy.on_write(&format!("key{}", i), &value(0));
+        }
+        policy.on_read("key0");
+        assert_eq!(policy.victim(""), Some("key1".to_string()));
+    }
+
+    #[test]
+    fn test_lfu() {
+        let mut policy = Eviction::Lfu.policy();
+        for i in 0..5 {
+            policy.on_write(&format!("key{}", i), &value(0));
+        }
+        // the first read always counts
+        policy.on_read("key2");
+        let evicted = drain(policy.as_mut(), "");
+        assert_eq!(evicted.last().unwrap(), "key2");
+
+        let mut frequency = Frequency {
+            count: LFU_INIT + 2,
+            period: 3,
+        };
+        frequency.decay(5);
+        assert_eq!(frequency.count, LFU_INIT);
+        frequency.decay(1000);
+        assert_eq!(frequency.count, 0);
+        frequency.increment();
+        assert_eq!(frequency.count, 1);
+    }
+
+    #[test]
+    fn test_volatile_ttl() {
+        let mut policy = Eviction::VolatileTtl.policy();
+        policy.on_write("never", &value(0));
+        policy.on_write("late", &value(300));
+        policy.on_write("soon", &value(100));
+        policy.on_write("later", &value(200));
+        // no longer expires
+        policy.on_write("later", &value(0));
+        policy.on_read("soon");
+        assert_eq!(drain(policy.as_mut(), "late"), vec!["soon"]);
+        assert_eq!(policy.victim(""), Some("late".to_string()));
+
+        assert_eq!(
+            "volatile-ttl".parse::<Eviction>(),
+            Ok(Eviction::VolatileTtl)
+        );
+        assert!("random".parse::<Eviction>().is_err());
+    }
+}
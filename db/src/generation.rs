@@ -0,0 +1,235 @@
+use chrono::Utc;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::store::SnapshotStore;
+
+// snapshots are kept as generations named <base>.gen.<id>, where base is the
+// file name of the snapshot path and the id is the unix time in milliseconds
+// the generation was started at. the manifest, <base>.manifest, names the
+// generation to restore, it is only updated once that generation is
+// completely stored. a generation is a full snapshot followed by the deltas
+// written on top of it, named <generation>.delta.<n>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub latest: Option<u64>,
+}
+
+// which generations are kept after a snapshot, the one named by the manifest
+// is never deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    // how many generations to keep
+    pub keep: usize,
+    // generations older than this are deleted even if there are fewer
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            keep: 5,
+            max_age: None,
+        }
+    }
+}
+
+// the file name snapshots of path are stored under
+pub fn base_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |name| name.to_string_lossy().to_string())
+}
+
+pub fn generation_name(base: &str, id: u64) -> String {
+    format!("{}.gen.{}", base, id)
+}
+
+pub fn delta_name(generation: &str, n: u64) -> String {
+    format!("{}.delta.{}", generation, n)
+}
+
+fn manifest_name(base: &str) -> String {
+    format!("{}.manifest", base)
+}
+
+// stored files named <prefix><n>, sorted by n
+fn numbered(store: &dyn SnapshotStore, prefix: &str) -> std::io::Result<Vec<(u64, String)>> {
+    let mut files = store
+        .list(prefix)?
+        .into_iter()
+        .filter_map(|name| {
+            let n = name.strip_prefix(prefix)?.parse::<u64>().ok()?;
+            Some((n, name))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+// existing generations, oldest first
+pub fn generations(store: &dyn SnapshotStore, base: &str) -> std::io::Result<Vec<(u64, String)>> {
+    numbered(store, &format!("{}.gen.", base))
+}
+
+// deltas of a generation, in the order they apply
+pub fn deltas(store: &dyn SnapshotStore, generation: &str) -> std::io::Result<Vec<(u64, String)>> {
+    numbered(store, &format!("{}.delta.", generation))
+}
+
+// id for a new generation, later than every existing one even if the clock
+// went backwards
+pub fn next_id(store: &dyn SnapshotStore, base: &str) -> std::io::Result<u64> {
+    let now = Utc::now().timestamp_millis() as u64;
+    let last = generations(store, base)?.last().map_or(0, |(id, _)| *id);
+    Ok(now.max(last + 1))
+}
+
+// format: one "<name> <value>" line per field, unknown fields are ignored
+pub fn read_manifest(store: &dyn SnapshotStore, base: &str) -> std::io::Result<Option<Manifest>> {
+    let content = match store.download(&manifest_name(base)) {
+        Ok(path) => {
+            let content = fs::read_to_string(&path);
+            store.release(&path);
+            content?
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut manifest = Manifest::default();
+    for line in content.lines() {
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        if name == "latest" {
+            let id = value.parse::<u64>().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid manifest line: {}", line),
+                )
+            })?;
+            manifest.latest = Some(id);
+        }
+    }
+    Ok(Some(manifest))
+}
+
+// replaces the manifest, readers see either the old or the new one
+pub fn write_manifest(
+    store: &dyn SnapshotStore,
+    base: &str,
+    manifest: &Manifest,
+) -> std::io::Result<()> {
+    let name = manifest_name(base);
+    let tmp_path = store.temp_path(&name);
+    let mut f = File::create(&tmp_path)?;
+    if let Some(id) = manifest.latest {
+        writeln!(f, "latest {}", id)?;
+    }
+    f.sync_all()?;
+    store.upload(&tmp_path, &name)
+}
+
+// deletes the generations the retention policy does not keep and returns
+// their names
+pub fn prune(
+    store: &dyn SnapshotStore,
+    base: &str,
+    retention: &Retention,
+    latest: u64,
+) -> std::io::Result<Vec<String>> {
+    let now = Utc::now().timestamp_millis() as u64;
+    let max_age = retention.max_age.map(|age| age.as_millis() as u64);
+    let mut removed = Vec::new();
+    for (i, (id, name)) in generations(store, base)?.into_iter().rev().enumerate() {
+        if id == latest {
+            continue;
+        }
+        let too_many = i >= retention.keep;
+        let too_old = max_age.is_some_and(|age| now.saturating_sub(id) > age);
+        if too_many || too_old {
+            for (_, delta) in deltas(store, &name)? {
+                store.remove(&delta)?;
+            }
+            store.remove(&name)?;
+            removed.push(name);
+        }
+    }
+    Ok(removed)
+}
+
+// deletes every generation with its deltas and the manifest
+pub fn remove_all(store: &dyn SnapshotStore, base: &str) -> std::io::Result<()> {
+    for (_, name) in generations(store, base)? {
+        for (_, delta) in deltas(store, &name)? {
+            store.remove(&delta)?;
+        }
+        store.remove(&name)?;
+    }
+    match store.remove(&manifest_name(base)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::LocalStore;
+
+    #[test]
+    fn test_manifest_and_prune() {
+        let dir = "/tmp/test_generations";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+        let store = LocalStore::new(dir);
+        let base = "snapshot";
+
+        assert_eq!(read_manifest(&store, base).unwrap(), None);
+        let manifest = Manifest { latest: Some(3) };
+        write_manifest(&store, base, &manifest).unwrap();
+        assert_eq!(read_manifest(&store, base).unwrap(), Some(manifest));
+
+        let now = Utc::now().timestamp_millis() as u64;
+        let ids = [1, 2, 3, now - 1000, now];
+        let create = |name: &str| File::create(format!("{}/{}", dir, name)).unwrap();
+        for id in ids {
+            create(&generation_name(base, id));
+        }
+        for n in [2, 1] {
+            create(&delta_name(&generation_name(base, 1), n));
+        }
+        let listed = deltas(&store, &generation_name(base, 1)).unwrap();
+        assert_eq!(
+            listed.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(generations(&store, base).unwrap().len(), 5);
+        assert!(next_id(&store, base).unwrap() > now);
+
+        // the two newest are kept by count, 3 because the manifest names it
+        let retention = Retention {
+            keep: 2,
+            max_age: None,
+        };
+        assert_eq!(prune(&store, base, &retention, 3).unwrap().len(), 2);
+        assert!(deltas(&store, &generation_name(base, 1))
+            .unwrap()
+            .is_empty());
+        let left = generations(&store, base).unwrap();
+        assert_eq!(
+            left.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![3, now - 1000, now]
+        );
+
+        // by age, only the latest is left
+        let retention = Retention {
+            keep: 5,
+            max_age: Some(Duration::from_millis(500)),
+        };
+        prune(&store, base, &retention, now).unwrap();
+        assert_eq!(generations(&store, base).unwrap().len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1 +1,9 @@
+pub mod aof;
 pub mod db;
+pub mod eviction;
+pub mod generation;
+pub mod map;
+pub mod memory;
+pub mod shard;
+pub mod snapshot;
+pub mod store;
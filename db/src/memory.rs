@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use crate::db::Value;
+use crate::eviction::{Eviction, EvictionPolicy};
+
+// the memory a map entry of the given size takes on average, measured with
+// 1k to 1M keys. a hash map is between 7/16 and 7/8 full, a persistent map
+// keeps its entries in nodes of 32 slots that are mostly empty
+fn entry_size(entry: usize, persistent: bool) -> usize {
+    if persistent {
+        (entry + 16) * 9
+    } else {
+        entry * 7 / 4
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub items: usize,
+    pub used: usize,
+    // none when the DB may grow without bound
+    pub limit: Option<usize>,
+    pub evictions: u64,
+}
+
+// the memory used by the values of a DB. which key is evicted first is up
+// to the eviction policy, the least recently used one by default
+pub struct Memory {
+    limit: Option<usize>,
+    // writes fail instead of evicting when the limit is reached
+    no_evict: bool,
+    used: usize,
+    // taken by the keys changed since the last snapshot, which stay until
+    // it is written
+    changed: usize,
+    // whether the DB keeps its keys in persistent maps
+    persistent: bool,
+    evictions: u64,
+    // size of every key
+    sizes: HashMap<String, usize>,
+    policy: Box<dyn EvictionPolicy>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory {
+            limit: None,
+            no_evict: false,
+            used: 0,
+            changed: 0,
+            persistent: false,
+            evictions: 0,
+            sizes: HashMap::new(),
+            policy: Eviction::default().policy(),
+        }
+    }
+}
+
+impl Memory {
+    pub fn set_limit(&mut self, limit: Option<usize>, no_evict: bool) {
+        self.limit = limit;
+        self.no_evict = no_evict;
+    }
+
+    // whether the DB maps are persistent, the keys are registered again by
+    // the caller
+    pub fn set_persistent(&mut self, persistent: bool) {
+        self.persistent = persistent;
+    }
+
+    // replaces the policy, the keys are registered again by the caller
+    pub fn set_policy(&mut self, policy: Box<dyn EvictionPolicy>) {
+        self.policy = policy;
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            items: self.sizes.len(),
+            used: self.used + self.changed,
+            limit: self.limit,
+            evictions: self.evictions,
+        }
+    }
+
+    // whether the memory used would fit in the limit if key took size bytes
+    pub fn fits(&self, key: &str, size: usize) -> bool {
+        match self.limit {
+            Some(limit) => self.used + self.changed - self.size(key) + size <= limit,
+            None => true,
+        }
+    }
+
+    // whether an item of size bytes fits in the limit once everything else
+    // is evicted
+    pub fn can_hold(&self, size: usize) -> bool {
+        self.limit.is_none_or(|limit| size <= limit)
+    }
+
+    pub fn can_evict(&self) -> bool {
+        !self.no_evict
+    }
+
+    // an estimate of the memory a key and its value take. the DB and memory
+    // maps hold a copy of the key, the policy may hold more
+    pub fn item_size(&self, key: &str, value: &Value) -> usize {
+        2 * key.len()
+            + value.data.len()
+            + entry_size(size_of::<(String, Value)>(), self.persistent)
+            + entry_size(size_of::<(String, usize)>(), false)
+            + self.policy.overhead(key, value)
+    }
+
+    // the memory a key takes in the DB's map of changed keys
+    pub fn changed_size(&self, key: &str) -> usize {
+        key.len() + entry_size(size_of::<(String, u64)>(), self.persistent)
+    }
+
+    // counts a key that was not changed since the last snapshot
+    pub fn add_changed(&mut self, key: &str) {
+        self.changed += self.changed_size(key);
+    }
+
+    // forgets a changed key once a snapshot covers it
+    pub fn remove_changed(&mut self, key: &str) {
+        self.changed -= self.changed_size(key);
+    }
+
+    pub fn size(&self, key: &str) -> usize {
+        self.sizes.get(key).copied().unwrap_or(0)
+    }
+
+    // records the size of a key that was just written
+    pub fn set(&mut self, key: &str, value: &Value) {
+        let size = self.item_size(key, value);
+        if let Some(previous) = self.sizes.insert(key.to_string(), size) {
+            self.used -= previous;
+        }
+        self.used += size;
+        self.policy.on_write(key, value);
+    }
+
+    // tells the policy a key was just read
+    pub fn touch(&mut self, key: &str) {
+        if self.sizes.contains_key(key) {
+            self.policy.on_read(key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(size) = self.sizes.remove(key) {
+            self.used -= size;
+            self.policy.on_remove(key);
+        }
+    }
+
+    // the next key to evict other than keep, which is being written
+    pub fn victim(&mut self, keep: &str) -> Option<String> {
+        self.policy.victim(keep)
+    }
+
+    pub fn record_eviction(&mut self) {
+        self.evictions += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+        self.changed = 0;
+        self.sizes.clear();
+        self.policy.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn value(len: usize) -> Value {
+        Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(vec![0; len]),
+            stale: false,
+            token_sent: false,
+        }
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut memory = Memory::default();
+        let size = memory.item_size("a", &value(100));
+        // the lru queue keeps the key in two more maps
+        let queue = size_of::<(String, u64)>() + size_of::<(u64, String)>();
+        let maps = entry_size(size_of::<(String, Value)>(), false)
+            + entry_size(size_of::<(String, usize)>(), false);
+        assert_eq!(size, 104 + maps + queue);
+        memory.set_limit(Some(3 * size), false);
+
+        for key in ["a", "b", "c"] {
+            memory.set(key, &value(100));
+        }
+        assert_eq!(memory.stats().used, 3 * size);
+        assert!(memory.fits("a", size));
+        assert!(!memory.fits("d", size));
+
+        // a read moves a key to the back
+        memory.touch("a");
+        assert_eq!(memory.victim("d"), Some("b".to_string()));
+        assert_eq!(memory.victim("b"), Some("c".to_string()));
+
+        // a rewrite replaces the size
+        memory.set("b", &value(100 + size));
+        assert_eq!(memory.stats().used, 4 * size);
+        assert_eq!(memory.victim("d"), Some("c".to_string()));
+
+        memory.remove("c");
+        memory.remove("missing");
+        assert_eq!(memory.stats().used, 3 * size);
+        assert_eq!(memory.stats().items, 2);
+        assert_eq!(memory.victim("d"), Some("a".to_string()));
+
+        // changed keys count until a snapshot covers them
+        memory.add_changed("a");
+        assert_eq!(memory.stats().used, 3 * size + memory.changed_size("a"));
+        assert!(!memory.fits("d", size));
+        memory.remove_changed("a");
+        assert_eq!(memory.stats().used, 3 * size);
+
+        // a persistent map takes more for every key
+        memory.set_persistent(true);
+        assert!(memory.item_size("a", &value(100)) > size + 512);
+    }
+
+    #[test]
+    fn test_item_size() {
+        let size = |eviction: Eviction, value: &Value| {
+            let mut memory = Memory::default();
+            memory.set_policy(eviction.policy());
+            memory.item_size("key", value)
+        };
+        let plain = value(100);
+        let mut expiring = value(100);
+        expiring.exptime = 1;
+
+        // every policy but volatile-ttl keeps two more copies of every key
+        let base = 106
+            + entry_size(size_of::<(String, Value)>(), false)
+            + entry_size(size_of::<(String, usize)>(), false);
+        for eviction in [Eviction::Lru, Eviction::ApproxLru, Eviction::Lfu] {
+            assert!(size(eviction, &plain) > base + 6);
+        }
+        // slru also keeps the segment of every key
+        assert!(size(Eviction::SegmentedLru, &plain) > size(Eviction::Lru, &plain) + 3);
+        // volatile-ttl only keeps the keys with an exptime
+        assert_eq!(size(Eviction::VolatileTtl, &plain), base);
+        assert!(size(Eviction::VolatileTtl, &expiring) > base + 6);
+    }
+}
@@ -0,0 +1,216 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use types::types::HorcruxError;
+
+use crate::store::SnapshotStore;
+
+// how keys were spread over the shards whose snapshots are stored, kept as
+// <base>.shards where base is the file name of the snapshot path. restoring
+// with another layout would look for keys in the wrong shard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardLayout {
+    pub shards: usize,
+    // name of the function hashing keys to shards
+    pub hash: String,
+}
+
+// recorded with the snapshots of the shards, change it whenever shard_for
+// sends keys elsewhere
+pub const SHARD_HASH: &str = "fnv1a64-jump";
+
+// the shard owning key. keys are hashed with 64-bit FNV-1a, which is fixed by
+// its specification unlike the hasher of the standard library, and spread
+// with jump consistent hashing (Lamping and Veach), so that going from n to
+// n + 1 shards only moves the keys of the new shard
+pub fn shard_for(key: &str, shards: usize) -> usize {
+    jump(fnv1a(key.as_bytes()), shards)
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn jump(mut key: u64, buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+// the snapshot path of one shard. a single shard uses the path itself so
+// that it keeps restoring the snapshots of an unsharded server
+pub fn shard_path(path: &str, shard: usize, shards: usize) -> String {
+    if shards == 1 {
+        return path.to_string();
+    }
+    format!("{}.shard.{}", path, shard)
+}
+
+fn layout_name(base: &str) -> String {
+    format!("{}.shards", base)
+}
+
+// format: one "<name> <value>" line per field, unknown fields are ignored
+pub fn read_layout(store: &dyn SnapshotStore, base: &str) -> std::io::Result<Option<ShardLayout>> {
+    let content = match store.download(&layout_name(base)) {
+        Ok(path) => {
+            let content = fs::read_to_string(&path);
+            store.release(&path);
+            content?
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let invalid = |line: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid shard layout line: {}", line),
+        )
+    };
+    let mut shards = None;
+    let mut hash = None;
+    for line in content.lines() {
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        match name {
+            "shards" => shards = Some(value.parse::<usize>().map_err(|_| invalid(line))?),
+            "hash" => hash = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    match (shards, hash) {
+        (Some(shards), Some(hash)) => Ok(Some(ShardLayout { shards, hash })),
+        _ => Err(invalid(&content)),
+    }
+}
+
+// replaces the layout, readers see either the old or the new one
+pub fn write_layout(
+    store: &dyn SnapshotStore,
+    base: &str,
+    layout: &ShardLayout,
+) -> std::io::Result<()> {
+    let name = layout_name(base);
+    let tmp_path = store.temp_path(&name);
+    let mut f = File::create(&tmp_path)?;
+    writeln!(f, "shards {}", layout.shards)?;
+    writeln!(f, "hash {}", layout.hash)?;
+    f.sync_all()?;
+    store.upload(&tmp_path, &name)
+}
+
+// fails if the stored snapshots were written with another layout, then
+// records this one. snapshots without a layout were written by a single
+// shard, before layouts were recorded
+pub fn check_layout(
+    store: &dyn SnapshotStore,
+    base: &str,
+    layout: &ShardLayout,
+) -> Result<(), HorcruxError> {
+    let stored = read_layout(store, base)
+        .map_err(|err| HorcruxError::RestoreDB(format!("Failed to read shard layout: {}", err)))?;
+    match stored {
+        // a single shard holds every key whatever the hash
+        Some(stored) if stored.shards == 1 && layout.shards == 1 => {}
+        Some(stored) if stored == *layout => return Ok(()),
+        Some(stored) => {
+            return Err(HorcruxError::RestoreDB(format!(
+                "Snapshots were written by {} shards hashing with {}, not {} shards hashing with {}",
+                stored.shards, stored.hash, layout.shards, layout.hash
+            )));
+        }
+        None if layout.shards > 1 => {
+            let generations = format!("{}.gen.", base);
+            let unsharded = store
+                .list(base)
+                .map_err(|err| {
+                    HorcruxError::RestoreDB(format!("Failed to list snapshots: {}", err))
+                })?
+                .iter()
+                .any(|name| name == base || name.starts_with(&generations));
+            if unsharded {
+                return Err(HorcruxError::RestoreDB(format!(
+                    "Snapshots were written by 1 shard, not {}",
+                    layout.shards
+                )));
+            }
+        }
+        None => {}
+    }
+    write_layout(store, base, layout)
+        .map_err(|err| HorcruxError::RestoreDB(format!("Failed to write shard layout: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::LocalStore;
+
+    #[test]
+    fn test_shard_for() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+
+        let keys = (0..10000).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        assert!(keys.iter().all(|key| shard_for(key, 1) == 0));
+
+        // keys are spread evenly and a new shard only takes keys
+        let mut counts = [0; 4];
+        for key in keys.iter() {
+            let shard = shard_for(key, 4);
+            counts[shard] += 1;
+            let grown = shard_for(key, 5);
+            assert!(grown == shard || grown == 4);
+        }
+        assert!(counts.iter().all(|count| (2000..3000).contains(count)));
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let dir = "/tmp/test_check_layout";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+        let store = LocalStore::new(dir);
+        let base = "snapshot";
+        let layout = |shards| ShardLayout {
+            shards,
+            hash: "default".to_string(),
+        };
+
+        assert_eq!(shard_path("/tmp/snapshot", 0, 1), "/tmp/snapshot");
+        assert_eq!(shard_path("/tmp/snapshot", 2, 4), "/tmp/snapshot.shard.2");
+
+        // the first start records the layout, later ones must match it
+        check_layout(&store, base, &layout(4)).unwrap();
+        assert_eq!(read_layout(&store, base).unwrap(), Some(layout(4)));
+        check_layout(&store, base, &layout(4)).unwrap();
+        assert!(check_layout(&store, base, &layout(2)).is_err());
+        let other_hash = ShardLayout {
+            shards: 4,
+            hash: "other".to_string(),
+        };
+        assert!(check_layout(&store, base, &other_hash).is_err());
+
+        // snapshots of an unsharded server can only be restored by one shard
+        fs::remove_file(format!("{}/{}", dir, layout_name(base))).unwrap();
+        File::create(format!("{}/{}.gen.1", dir, base)).unwrap();
+        assert!(check_layout(&store, base, &layout(4)).is_err());
+        check_layout(&store, base, &layout(1)).unwrap();
+        let single = ShardLayout {
+            shards: 1,
+            hash: SHARD_HASH.to_string(),
+        };
+        check_layout(&store, base, &single).unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
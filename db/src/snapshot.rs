@@ -0,0 +1,443 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::{ErrorKind, Read, Write};
+use types::types::HorcruxError;
+
+use crate::db::Value;
+
+// format: <magic: "HCRX"><version: u16>
+//         (<'B'><entry_count: u32><len: u32><crc32: u32><entries>)*
+//         <'E'><total_entry_count: u64>
+// entry:  <key_len: u16><key><flags: u32><exptime: u32><cas: u64><data_len: u32><data>
+//
+// files without the magic number are read in the legacy format written by
+// the first releases, a bare sequence of entries without exptime or cas:
+// legacy: <key_len: u8><key><flags: u32><data_len: u32><data>
+const MAGIC: &[u8; 4] = b"HCRX";
+pub const VERSION: u16 = 1;
+
+const BLOCK: u8 = b'B';
+const TRAILER: u8 = b'E';
+
+// entries are grouped in blocks of about this size, each with its own crc
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// writes a snapshot one block at a time and returns the number of entries,
+// wrap the writer in a BufWriter to keep the number of writes low
+pub fn write<'a, W, I>(writer: &mut W, entries: I) -> Result<u64, std::io::Error>
+where
+    W: Write,
+    I: IntoIterator<Item = (&'a String, &'a Value)>,
+{
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+
+    let mut total = 0u64;
+    let mut block = BytesMut::with_capacity(BLOCK_SIZE);
+    let mut count = 0u32;
+    for (key, value) in entries {
+        put_key_value(&mut block, key, value);
+        count += 1;
+        if block.len() >= BLOCK_SIZE {
+            write_block(writer, count, &block)?;
+            total += count as u64;
+            block.clear();
+            count = 0;
+        }
+    }
+    if count > 0 {
+        write_block(writer, count, &block)?;
+        total += count as u64;
+    }
+
+    writer.write_all(&[TRAILER])?;
+    writer.write_all(&total.to_be_bytes())?;
+    Ok(total)
+}
+
+fn write_block<W: Write>(writer: &mut W, count: u32, block: &[u8]) -> Result<(), std::io::Error> {
+    let mut header = BytesMut::with_capacity(13);
+    header.put_u8(BLOCK);
+    header.put_u32(count);
+    header.put_u32(block.len() as u32);
+    header.put_u32(crc32fast::hash(block));
+    writer.write_all(&header)?;
+    writer.write_all(block)
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    // intact records handed to the caller
+    pub loaded: usize,
+    // records known to be lost
+    pub skipped: usize,
+    // the first problem found, None if the snapshot is intact
+    pub error: Option<HorcruxError>,
+}
+
+// reads a snapshot one block at a time, calling on_entry for every intact
+// record. A strict read fails on the first sign of corruption, otherwise
+// damaged blocks are skipped and the problem is reported in the summary
+pub fn read<R, F>(reader: R, strict: bool, mut on_entry: F) -> Result<Summary, HorcruxError>
+where
+    R: Read,
+    F: FnMut(String, Value),
+{
+    let mut summary = Summary::default();
+    match read_into(reader, strict, &mut summary, &mut on_entry) {
+        Ok(()) => Ok(summary),
+        Err(err) if strict => Err(err),
+        Err(err) => {
+            summary.error.get_or_insert(err);
+            Ok(summary)
+        }
+    }
+}
+
+// the format version of a snapshot, None for the legacy format
+pub fn version<R: Read>(mut reader: R) -> Result<Option<u16>, HorcruxError> {
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    read_up_to(&mut reader, MAGIC.len(), &mut magic)?;
+    if magic != MAGIC {
+        return Ok(None);
+    }
+    let mut version = [0; 2];
+    read_exact(&mut reader, &mut version, "header")?;
+    Ok(Some(u16::from_be_bytes(version)))
+}
+
+// an error returned here ends the read, when strict is false a damaged
+// block is recorded in the summary and skipped instead
+fn read_into<R, F>(
+    mut reader: R,
+    strict: bool,
+    summary: &mut Summary,
+    on_entry: &mut F,
+) -> Result<(), HorcruxError>
+where
+    R: Read,
+    F: FnMut(String, Value),
+{
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    read_up_to(&mut reader, MAGIC.len(), &mut magic)?;
+    if magic != MAGIC {
+        return read_legacy(Read::chain(&magic[..], reader), summary, on_entry);
+    }
+    let mut version = [0; 2];
+    read_exact(&mut reader, &mut version, "header")?;
+    let version = u16::from_be_bytes(version);
+    if version != VERSION {
+        return Err(corrupted(&format!("unsupported version {}", version)));
+    }
+
+    loop {
+        let mut tag = [0; 1];
+        read_exact(&mut reader, &mut tag, "file, missing end of file trailer")?;
+        match tag[0] {
+            BLOCK => {
+                let mut header = [0; 12];
+                read_exact(&mut reader, &mut header, "block header")?;
+                let mut header = &header[..];
+                let count = header.get_u32() as usize;
+                let len = header.get_u32() as usize;
+                let crc = header.get_u32();
+
+                let mut block = Vec::new();
+                read_up_to(&mut reader, len, &mut block)?;
+                if block.len() < len {
+                    summary.skipped += count;
+                    return Err(corrupted("truncated block"));
+                }
+                match read_block(Bytes::from(block), count, crc) {
+                    Ok(entries) => {
+                        summary.loaded += entries.len();
+                        for (key, value) in entries {
+                            on_entry(key, value);
+                        }
+                    }
+                    Err(err) if strict => return Err(err),
+                    Err(err) => {
+                        // the block length is still trusted, move on to the next one
+                        summary.skipped += count;
+                        summary.error.get_or_insert(err);
+                    }
+                }
+            }
+            TRAILER => {
+                let mut total = [0; 8];
+                read_exact(&mut reader, &mut total, "trailer")?;
+                if u64::from_be_bytes(total) != (summary.loaded + summary.skipped) as u64 {
+                    return Err(corrupted("entry count mismatch"));
+                }
+                let mut rest = Vec::new();
+                read_up_to(&mut reader, 1, &mut rest)?;
+                if !rest.is_empty() {
+                    return Err(corrupted("unexpected data after the trailer"));
+                }
+                return Ok(());
+            }
+            tag => return Err(corrupted(&format!("unknown block type {}", tag))),
+        }
+    }
+}
+
+// the legacy format has no exptime or cas, values never expire and are
+// numbered in the order they were written so that their tokens are unique
+fn read_legacy<R, F>(
+    mut reader: R,
+    summary: &mut Summary,
+    on_entry: &mut F,
+) -> Result<(), HorcruxError>
+where
+    R: Read,
+    F: FnMut(String, Value),
+{
+    loop {
+        let mut key_len = Vec::with_capacity(1);
+        read_up_to(&mut reader, 1, &mut key_len)?;
+        let key_len = match key_len.first() {
+            Some(&key_len) => key_len as usize,
+            None => return Ok(()),
+        };
+
+        // <key><flags: u32><data_len: u32>
+        let mut entry = vec![0; key_len + 8];
+        if let Err(err) = read_exact(&mut reader, &mut entry, "entry") {
+            summary.skipped += 1;
+            return Err(err);
+        }
+        let mut header = &entry[key_len..];
+        let flags = header.get_u32();
+        let data_len = header.get_u32() as usize;
+        let mut data = Vec::new();
+        read_up_to(&mut reader, data_len, &mut data)?;
+        if data.len() < data_len {
+            summary.skipped += 1;
+            return Err(corrupted("truncated entry"));
+        }
+        entry.truncate(key_len);
+        let key = match String::from_utf8(entry) {
+            Ok(key) => key,
+            Err(_) => {
+                summary.skipped += 1;
+                return Err(HorcruxError::RestoreDB(
+                    "Failed to parse key from snapshot".to_string(),
+                ));
+            }
+        };
+        summary.loaded += 1;
+        let value = Value {
+            flags,
+            exptime: 0,
+            cas: summary.loaded as u64,
+            data: Bytes::from(data),
+            stale: false,
+            token_sent: false,
+        };
+        on_entry(key, value);
+    }
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8], what: &str) -> Result<(), HorcruxError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => corrupted(&format!("truncated {}", what)),
+        _ => HorcruxError::RestoreDB(format!("Failed to read snapshot: {}", err)),
+    })
+}
+
+// appends at most len bytes to buf, less only at the end of the file. The
+// buffer grows with the data actually read, so a damaged length cannot
+// allocate more than the file holds
+fn read_up_to<R: Read>(reader: &mut R, len: usize, buf: &mut Vec<u8>) -> Result<(), HorcruxError> {
+    reader
+        .take(len as u64)
+        .read_to_end(buf)
+        .map(|_| ())
+        .map_err(|err| HorcruxError::RestoreDB(format!("Failed to read snapshot: {}", err)))
+}
+
+fn read_block(
+    mut block: Bytes,
+    count: usize,
+    crc: u32,
+) -> Result<Vec<(String, Value)>, HorcruxError> {
+    if crc32fast::hash(&block) != crc {
+        return Err(corrupted("block checksum mismatch"));
+    }
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        entries.push(get_key_value_from_bytes(&mut block)?);
+    }
+    if block.has_remaining() {
+        return Err(corrupted("unexpected data at the end of a block"));
+    }
+    Ok(entries)
+}
+
+fn corrupted(msg: &str) -> HorcruxError {
+    HorcruxError::RestoreDB(format!("Corrupted snapshot: {}", msg))
+}
+
+pub(crate) fn put_key_value(buf: &mut BytesMut, key: &str, value: &Value) {
+    buf.put_u16(key.len() as u16);
+    buf.put(key.as_bytes());
+    buf.put_u32(value.flags);
+    buf.put_u32(value.exptime);
+    buf.put_u64(value.cas);
+    buf.put_u32(value.data.len() as u32);
+    buf.put(&value.data[..]);
+}
+
+pub(crate) fn get_key_value_from_bytes(mem: &mut Bytes) -> Result<(String, Value), HorcruxError> {
+    if mem.remaining() < 2 {
+        return Err(corrupted("truncated entry"));
+    }
+    let key_len = mem.get_u16() as usize;
+    if mem.remaining() < key_len + 20 {
+        return Err(corrupted("truncated entry"));
+    }
+    let key = String::from_utf8(mem.split_to(key_len).to_vec())
+        .map_err(|_| HorcruxError::RestoreDB("Failed to parse key from snapshot".to_string()))?;
+    let flags = mem.get_u32();
+    let exptime = mem.get_u32();
+    let cas = mem.get_u64();
+    let data_len = mem.get_u32() as usize;
+    if mem.remaining() < data_len {
+        return Err(corrupted("truncated entry"));
+    }
+    let data = mem.split_to(data_len);
+    Ok((
+        key,
+        Value {
+            flags,
+            exptime,
+            cas,
+            data,
+            stale: false,
+            token_sent: false,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    fn encode(entries: &[(String, Value)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write(&mut buf, entries.iter().map(|(key, value)| (key, value))).unwrap();
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Result<Vec<(String, Value)>, HorcruxError> {
+        let mut entries = Vec::new();
+        read(data, true, |key, value| entries.push((key, value)))?;
+        Ok(entries)
+    }
+
+    fn entries() -> Vec<(String, Value)> {
+        (0..3)
+            .map(|i| {
+                (
+                    format!("key{}", i).repeat(100),
+                    Value {
+                        flags: i,
+                        exptime: 0,
+                        cas: i as u64 + 1,
+                        data: Bytes::from(vec![i as u8; 50_000]),
+                        stale: false,
+                        token_sent: false,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let entries = entries();
+        let encoded = encode(&entries);
+
+        // read through a buffer much smaller than a block
+        assert_eq!(version(&encoded[..]).unwrap(), Some(VERSION));
+        let mut decoded = Vec::new();
+        let reader = BufReader::with_capacity(100, &encoded[..]);
+        read(reader, true, |key, value| decoded.push((key, value))).unwrap();
+        assert_eq!(decoded.len(), 3);
+        for ((key, value), (expected_key, expected)) in decoded.iter().zip(entries.iter()) {
+            assert_eq!(key, expected_key);
+            assert_eq!(key.len(), 400);
+            assert_eq!(value.flags, expected.flags);
+            assert_eq!(value.cas, expected.cas);
+            assert_eq!(value.data, expected.data);
+        }
+    }
+
+    #[test]
+    fn test_read_rejects_corruption() {
+        let entries = entries();
+        let encoded = encode(&entries);
+
+        // truncated anywhere, including right before the trailer
+        for len in [
+            3,
+            6,
+            20,
+            encoded.len() / 2,
+            encoded.len() - 9,
+            encoded.len() - 1,
+        ] {
+            assert!(decode(&encoded[..len]).is_err());
+        }
+
+        // a flipped bit in the data
+        let mut flipped = encoded.clone();
+        flipped[1000] ^= 1;
+        match decode(&flipped).err().unwrap() {
+            HorcruxError::RestoreDB(msg) => assert!(msg.contains("checksum")),
+            _ => panic!("Expected RestoreDB error"),
+        }
+
+        // salvage keeps the intact blocks
+        let mut salvaged = Vec::new();
+        let summary = read(&flipped[..], false, |key, value| {
+            salvaged.push((key, value))
+        })
+        .unwrap();
+        assert!(summary.error.is_some());
+        assert_eq!(summary.skipped, 2);
+        assert_eq!(summary.loaded, 1);
+        assert_eq!(salvaged[0].1.cas, 3);
+
+        // an unknown version
+        let mut newer = encoded.clone();
+        newer[5] = 2;
+        assert_eq!(version(&newer[..]).unwrap(), Some(2));
+        assert!(decode(&newer).is_err());
+    }
+
+    #[test]
+    fn test_read_legacy() {
+        // written by the unversioned dump of the first releases
+        let legacy =
+            b"\x03key\x00\x00\x00\x01\x00\x00\x00\x05value\x04key2\x00\x00\x00\x00\x00\x00\x00\x00";
+
+        assert_eq!(version(&legacy[..]).unwrap(), None);
+        let decoded = decode(legacy).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].0, "key");
+        assert_eq!(decoded[0].1.flags, 1);
+        assert_eq!(decoded[0].1.exptime, 0);
+        assert_eq!(decoded[0].1.data, "value".as_bytes());
+        assert_eq!(decoded[1].0, "key2");
+        assert!(decoded[1].1.data.is_empty());
+        // every value gets its own cas
+        assert_eq!(decoded[0].1.cas, 1);
+        assert_eq!(decoded[1].1.cas, 2);
+
+        // a truncated legacy file is an error, not a panic
+        assert!(decode(&legacy[..10]).is_err());
+        assert!(decode(&legacy[..15]).is_err());
+        assert!(decode(&legacy[..legacy.len() - 1]).is_err());
+    }
+}
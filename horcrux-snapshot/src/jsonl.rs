@@ -0,0 +1,124 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use types::types::HorcruxError;
+
+use db::db::{check_key, Value};
+
+// one value per line. data that is not UTF-8 is written as hex in data_hex
+// instead, so that every value survives a dump and load
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    flags: u32,
+    // absolute Unix time in seconds, 0 means the value never expires
+    exptime: u32,
+    cas: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data_hex: Option<String>,
+}
+
+pub fn write<W: Write>(writer: &mut W, key: &str, value: &Value) -> std::io::Result<()> {
+    let (data, data_hex) = match std::str::from_utf8(&value.data) {
+        Ok(data) => (Some(data.to_string()), None),
+        Err(_) => (None, Some(hex::encode(&value.data))),
+    };
+    let record = Record {
+        key: key.to_string(),
+        flags: value.flags,
+        exptime: value.exptime,
+        cas: value.cas,
+        data,
+        data_hex,
+    };
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")
+}
+
+// calls on_entry for every line, empty lines are ignored. a key the server
+// would refuse fails the whole read
+pub fn read<R, F>(reader: R, mut on_entry: F) -> Result<usize, HorcruxError>
+where
+    R: BufRead,
+    F: FnMut(String, Value),
+{
+    let mut count = 0;
+    for (i, line) in reader.lines().enumerate() {
+        let invalid = |msg: String| HorcruxError::RestoreDB(format!("line {}: {}", i + 1, msg));
+        let line = line.map_err(|err| invalid(err.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line).map_err(|err| invalid(err.to_string()))?;
+        check_key(record.key.as_bytes()).map_err(|msg| invalid(msg.to_string()))?;
+        let data = match (record.data, record.data_hex) {
+            (Some(data), None) => Bytes::from(data),
+            (None, Some(data_hex)) => {
+                Bytes::from(hex::decode(data_hex).map_err(|err| invalid(err.to_string()))?)
+            }
+            _ => return Err(invalid("expected one of data and data_hex".to_string())),
+        };
+        let value = Value {
+            flags: record.flags,
+            exptime: record.exptime,
+            cas: record.cas,
+            data,
+            stale: false,
+            token_sent: false,
+        };
+        on_entry(record.key, value);
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(data: &[u8]) -> Value {
+        Value {
+            flags: 3,
+            exptime: 0,
+            cas: 9,
+            data: Bytes::copy_from_slice(data),
+            stale: false,
+            token_sent: false,
+        }
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let mut buf = Vec::new();
+        write(&mut buf, "text\"key\"", &value(b"hello\nworld")).unwrap();
+        write(&mut buf, "binary", &value(&[0xff, 0x00, 0x80])).unwrap();
+        let lines = String::from_utf8(buf.clone()).unwrap();
+        assert!(lines
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains("\"data_hex\":\"ff0080\""));
+
+        let mut entries = Vec::new();
+        let count = read(&buf[..], |key, value| entries.push((key, value))).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(entries[0].0, "text\"key\"");
+        assert_eq!(entries[0].1.data, "hello\nworld".as_bytes());
+        assert_eq!(entries[1].1.data, &[0xff, 0x00, 0x80][..]);
+        assert_eq!(entries[1].1.flags, 3);
+        assert_eq!(entries[1].1.cas, 9);
+
+        let both = r#"{"key":"k","flags":0,"exptime":0,"cas":1,"data":"a","data_hex":"61"}"#;
+        assert!(read(both.as_bytes(), |_, _| {}).is_err());
+        assert!(read(&b"\n{\"key\":"[..], |_, _| {}).is_err());
+
+        // keys the server would refuse
+        for key in ["", "a b", "tab\t", "del\x7f", &"k".repeat(251)] {
+            let mut buf = Vec::new();
+            write(&mut buf, key, &value(b"data")).unwrap();
+            assert!(read(&buf[..], |_, _| {}).is_err(), "{:?}", key);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+mod jsonl;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+use clap::{Parser, Subcommand};
+
+use db::db::{check_key, unix_now, RestoreMode, StoreResult, Value, DB};
+use db::snapshot;
+use db::store::{LocalStore, SnapshotStore};
+
+/// inspects and converts the snapshots of a stopped server. a snapshot is
+/// named by the snapshot path the server was started with, it is read with
+/// its latest generation, deltas and log like the server restores it
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// key count, byte totals and value size histogram
+    Info { snapshot_path: String },
+
+    /// reads every stored generation and delta, failing on any damage
+    Verify { snapshot_path: String },
+
+    /// writes every value to a file
+    Dump {
+        snapshot_path: String,
+        #[clap(long, default_value = "jsonl")]
+        format: Format,
+        #[clap(short, long)]
+        output: String,
+    },
+
+    /// writes a new snapshot from a dump
+    Load {
+        input: String,
+        #[clap(long, default_value = "jsonl")]
+        from: Format,
+        /// snapshot path to start the server with
+        #[clap(short, long)]
+        output: String,
+    },
+
+    /// lists the keys only in a, only in b, or with another value
+    Diff { a: String, b: String },
+
+    /// writes a new snapshot with the keys of both, b wins for keys in both
+    Merge {
+        a: String,
+        b: String,
+        #[clap(short, long)]
+        output: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Jsonl,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(format!("Invalid format: {}", s)),
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let result = match args.command {
+        Command::Info { snapshot_path } => info(&snapshot_path),
+        Command::Verify { snapshot_path } => verify(&snapshot_path),
+        Command::Dump {
+            snapshot_path,
+            format,
+            output,
+        } => dump(&snapshot_path, format, &output),
+        Command::Load {
+            input,
+            from,
+            output,
+        } => load(&input, from, &output),
+        Command::Diff { a, b } => diff(&a, &b),
+        Command::Merge { a, b, output } => merge(&a, &b, &output),
+    };
+    match result {
+        Ok(true) => {}
+        // verify found damage or diff found differences
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            println!("{}", err);
+            process::exit(2);
+        }
+    }
+}
+
+// the values of a snapshot sorted by key, expired ones are left out
+fn open(path: &str) -> Result<BTreeMap<String, Value>, Box<dyn Error>> {
+    let mut db = DB::new(path.to_string());
+    if db.generations()?.is_empty() && !Path::new(path).exists() {
+        return Err(format!("No snapshot at {}", path).into());
+    }
+    db.restore(RestoreMode::Strict)?;
+    Ok(db
+        .entries()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect())
+}
+
+// writes values as a new snapshot, refusing to touch existing ones and
+// keys the server would refuse
+fn create<I>(path: &str, entries: I) -> Result<usize, Box<dyn Error>>
+where
+    I: IntoIterator<Item = (String, Value)>,
+{
+    let mut db = DB::new(path.to_string());
+    if !db.generations()?.is_empty() || Path::new(path).exists() {
+        return Err(format!("Snapshots already exist at {}", path).into());
+    }
+    for (key, value) in entries {
+        if let Err(msg) = check_key(key.as_bytes()) {
+            return Err(format!("Invalid key {:?}: {}", key, msg).into());
+        }
+        let result = db.import(key.clone(), value);
+        if result != StoreResult::Stored {
+            return Err(format!("Failed to store {:?}: {:?}", key, result).into());
+        }
+    }
+    db.snapshot()?;
+    Ok(db.entries().count())
+}
+
+// the file the latest generation starts from, or the unversioned snapshot
+fn full_snapshot(path: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let db = DB::new(path.to_string());
+    match db.generations()?.pop() {
+        Some((_, name)) => Ok(Some(LocalStore::for_snapshot(path).download(&name)?)),
+        None if Path::new(path).exists() => Ok(Some(path.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn format_name(path: &str) -> Result<String, Box<dyn Error>> {
+    let version = snapshot::version(BufReader::new(File::open(path)?))?;
+    Ok(match version {
+        None => "legacy".to_string(),
+        Some(version) if version > snapshot::VERSION => format!(
+            "v{} (unsupported, this build reads up to v{})",
+            version,
+            snapshot::VERSION
+        ),
+        Some(version) => format!("v{}", version),
+    })
+}
+
+fn info(path: &str) -> Result<bool, Box<dyn Error>> {
+    let generations = DB::new(path.to_string()).generations()?;
+    if let Some(file) = full_snapshot(path)? {
+        println!("format: {}", format_name(&file)?);
+    }
+    println!("generations: {}", generations.len());
+    let entries = open(path)?;
+
+    let now = unix_now();
+    let mut key_bytes = 0;
+    let mut data_bytes = 0;
+    let mut expiring = 0;
+    // values counted by size, the bucket n holding sizes below 2^n
+    let mut histogram = BTreeMap::new();
+    for (key, value) in entries.iter() {
+        key_bytes += key.len();
+        data_bytes += value.data.len();
+        if value.exptime != 0 && value.exptime > now {
+            expiring += 1;
+        }
+        let bucket = usize::BITS - value.data.len().leading_zeros();
+        *histogram.entry(bucket).or_insert(0) += 1;
+    }
+    println!("keys: {}", entries.len());
+    println!("expiring keys: {}", expiring);
+    println!("key bytes: {}", key_bytes);
+    println!("data bytes: {}", data_bytes);
+    println!("value sizes:");
+    for (bucket, count) in histogram {
+        let upper = 1u64 << bucket;
+        println!("  {:>10} - {:<10} {}", upper / 2, upper - 1, count);
+    }
+    Ok(true)
+}
+
+// reads a stored file strictly and reports the records it holds
+fn verify_file(path: &str) -> bool {
+    let result = File::open(path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            snapshot::read(BufReader::new(file), true, |_, _| {}).map_err(|err| err.to_string())
+        });
+    match result {
+        Ok(summary) => {
+            println!("{}: ok, {} records", path, summary.loaded);
+            true
+        }
+        Err(err) => {
+            println!("{}: {}", path, err);
+            false
+        }
+    }
+}
+
+fn verify(path: &str) -> Result<bool, Box<dyn Error>> {
+    let db = DB::new(path.to_string());
+    let store = LocalStore::for_snapshot(path);
+    let mut ok = true;
+    let mut files = 0;
+    if Path::new(path).exists() {
+        ok &= verify_file(path);
+        files += 1;
+    }
+    for (_, name) in db.generations()? {
+        ok &= verify_file(&store.download(&name)?);
+        files += 1;
+        for (_, delta) in db.deltas(&name)? {
+            ok &= verify_file(&store.download(&delta)?);
+            files += 1;
+        }
+    }
+    if files == 0 {
+        return Err(format!("No snapshot at {}", path).into());
+    }
+    // the manifest and the log must also restore
+    match open(path) {
+        Ok(entries) => println!("restored {} keys", entries.len()),
+        Err(err) => {
+            println!("Failed to restore: {}", err);
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+fn dump(path: &str, format: Format, output: &str) -> Result<bool, Box<dyn Error>> {
+    let entries = open(path)?;
+    let mut writer = BufWriter::new(File::create(output)?);
+    for (key, value) in entries.iter() {
+        match format {
+            Format::Jsonl => jsonl::write(&mut writer, key, value)?,
+        }
+    }
+    writer.flush()?;
+    println!("Dumped {} keys to {}", entries.len(), output);
+    Ok(true)
+}
+
+fn load(input: &str, from: Format, output: &str) -> Result<bool, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(input)?);
+    let now = unix_now();
+    let mut entries = Vec::new();
+    let mut expired = 0;
+    match from {
+        Format::Jsonl => jsonl::read(reader, |key, value| {
+            if value.is_expired(now) {
+                expired += 1;
+            } else {
+                entries.push((key, value));
+            }
+        })?,
+    };
+    let loaded = create(output, entries)?;
+    println!(
+        "Loaded {} keys into {}, skipped {} expired",
+        loaded, output, expired
+    );
+    Ok(true)
+}
+
+fn same(a: &Value, b: &Value) -> bool {
+    a.flags == b.flags && a.exptime == b.exptime && a.data == b.data
+}
+
+fn diff(a: &str, b: &str) -> Result<bool, Box<dyn Error>> {
+    let a = open(a)?;
+    let b = open(b)?;
+    let mut only_a = 0;
+    let mut only_b = 0;
+    let mut changed = 0;
+    for (key, value) in a.iter() {
+        match b.get(key) {
+            None => {
+                println!("- {}", key);
+                only_a += 1;
+            }
+            Some(other) if !same(value, other) => {
+                println!("~ {}", key);
+                changed += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    for key in b.keys().filter(|key| !a.contains_key(*key)) {
+        println!("+ {}", key);
+        only_b += 1;
+    }
+    println!(
+        "{} only in a, {} only in b, {} changed",
+        only_a, only_b, changed
+    );
+    Ok(only_a + only_b + changed == 0)
+}
+
+fn merge(a: &str, b: &str, output: &str) -> Result<bool, Box<dyn Error>> {
+    let mut merged = open(a)?;
+    let b = open(b)?;
+    let both = b.keys().filter(|key| merged.contains_key(*key)).count();
+    merged.extend(b);
+    let merged = create(output, merged)?;
+    println!(
+        "Merged {} keys into {}, {} were in both",
+        merged, output, both
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn value(data: &str, flags: u32) -> Value {
+        Value {
+            flags,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from(data.to_string()),
+            stale: false,
+            token_sent: false,
+        }
+    }
+
+    fn snapshot(path: &str, entries: &[(&str, &str, u32)]) {
+        create(
+            path,
+            entries
+                .iter()
+                .map(|(key, data, flags)| (key.to_string(), value(data, *flags))),
+        )
+        .unwrap();
+    }
+
+    fn setup(dir: &str) {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn test_diff() {
+        let dir = "/tmp/test_snapshot_diff";
+        setup(dir);
+        let a = format!("{}/a", dir);
+        let b = format!("{}/b", dir);
+        let c = format!("{}/c", dir);
+        snapshot(
+            &a,
+            &[("same", "1", 0), ("flags", "1", 0), ("only_a", "1", 0)],
+        );
+        snapshot(
+            &b,
+            &[("same", "1", 0), ("flags", "1", 1), ("only_b", "1", 0)],
+        );
+        snapshot(
+            &c,
+            &[("same", "1", 0), ("flags", "1", 0), ("only_a", "1", 0)],
+        );
+
+        assert!(!diff(&a, &b).unwrap());
+        assert!(diff(&a, &c).unwrap());
+        assert!(diff(&a, &format!("{}/missing", dir)).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = "/tmp/test_snapshot_merge";
+        setup(dir);
+        let a = format!("{}/a", dir);
+        let b = format!("{}/b", dir);
+        let output = format!("{}/merged", dir);
+        snapshot(&a, &[("both", "a", 0), ("only_a", "a", 0)]);
+        snapshot(&b, &[("both", "b", 0), ("only_b", "b", 0)]);
+
+        assert!(merge(&a, &b, &output).unwrap());
+        let merged = open(&output).unwrap();
+        assert_eq!(
+            merged.keys().collect::<Vec<_>>(),
+            vec!["both", "only_a", "only_b"]
+        );
+        assert_eq!(merged["both"].data, "b".as_bytes());
+
+        // existing snapshots are never overwritten
+        assert!(merge(&a, &b, &output).is_err());
+        assert!(merge(&a, &b, &a).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_dump_and_load() {
+        let dir = "/tmp/test_snapshot_load";
+        setup(dir);
+        let source = format!("{}/source", dir);
+        let output = format!("{}/loaded", dir);
+        let dumped = format!("{}/dump.jsonl", dir);
+        snapshot(&source, &[("key1", "data1", 1), ("key2", "data2", 2)]);
+
+        assert!(dump(&source, Format::Jsonl, &dumped).unwrap());
+        assert!(load(&dumped, Format::Jsonl, &output).unwrap());
+        assert!(diff(&source, &output).unwrap());
+
+        // a bad key fails the load before anything is written
+        let bad = format!("{}/bad.jsonl", dir);
+        let mut file = File::create(&bad).unwrap();
+        for key in ["good", "bad key"] {
+            jsonl::write(&mut file, key, &value("data", 0)).unwrap();
+        }
+        let rejected = format!("{}/rejected", dir);
+        assert!(load(&bad, Format::Jsonl, &rejected).is_err());
+        assert!(DB::new(rejected.clone()).generations().unwrap().is_empty());
+
+        // so do keys the dump format can hold but a snapshot cannot
+        let long = format!("{}/long", dir);
+        let key = "k".repeat(70000);
+        assert!(create(&long, [(key, value("data", 0))]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
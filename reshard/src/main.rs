@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::process;
+
+use clap::Parser;
+
+use db::aof;
+use db::db::{RestoreMode, StoreResult, DB};
+use db::generation;
+use db::shard::{self, ShardLayout, SHARD_HASH};
+use db::store::LocalStore;
+
+/// rewrites the snapshots of a stopped server for another number of shards.
+/// the source snapshots are only read, so an interrupted run can be started
+/// again with a new output path
+#[derive(Debug, Parser)]
+struct Args {
+    /// snapshot path the server was started with
+    #[clap(long)]
+    snapshot_path: String,
+
+    /// snapshot path to start the resharded server with
+    #[clap(long)]
+    output_path: String,
+
+    /// number of shards to spread the keys over
+    #[clap(long)]
+    shards: usize,
+
+    /// what to do with a damaged source snapshot: strict or salvage
+    #[clap(long, default_value = "strict")]
+    restore_mode: RestoreMode,
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(err) = reshard(&args) {
+        println!("Failed to reshard: {}", err);
+        process::exit(1);
+    }
+}
+
+fn reshard(args: &Args) -> Result<(), Box<dyn Error>> {
+    if args.shards == 0 {
+        return Err("Number of shards cannot be 0".into());
+    }
+    if args.restore_mode == RestoreMode::Empty {
+        return Err("Cannot reshard with the empty restore mode".into());
+    }
+    if args.snapshot_path == args.output_path {
+        return Err("Output path must differ from the snapshot path".into());
+    }
+
+    let output_store = LocalStore::for_snapshot(&args.output_path);
+    let output_base = generation::base_name(&args.output_path);
+    if shard::read_layout(&output_store, &output_base)?.is_some() {
+        return Err(format!("Snapshots already exist at {}", args.output_path).into());
+    }
+
+    // snapshots without a layout were written by a single shard
+    let source_store = LocalStore::for_snapshot(&args.snapshot_path);
+    let source_base = generation::base_name(&args.snapshot_path);
+    let source_shards = match shard::read_layout(&source_store, &source_base)? {
+        Some(layout) if layout.shards > 1 && layout.hash != SHARD_HASH => {
+            return Err(format!("Unknown shard hash: {}", layout.hash).into());
+        }
+        Some(layout) => layout.shards,
+        None => 1,
+    };
+
+    let mut dbs = Vec::new();
+    for shard_id in 0..args.shards {
+        // left by an interrupted run or another server, they would be
+        // restored along with the new snapshots
+        let path = shard::shard_path(&args.output_path, shard_id, args.shards);
+        let db = DB::new(path.clone());
+        if !db.generations()?.is_empty() || !aof::segments(&path)?.is_empty() {
+            return Err(format!("Snapshots already exist at {}", path).into());
+        }
+        dbs.push(db);
+    }
+    let mut moved = 0;
+    for shard_id in 0..source_shards {
+        let path = shard::shard_path(&args.snapshot_path, shard_id, source_shards);
+        let mut source = DB::new(path.clone());
+        let stats = source.restore(args.restore_mode)?;
+        println!(
+            "Restored {} records, skipped {}, expired {} from {}",
+            stats.loaded, stats.skipped, stats.expired, path
+        );
+        for (key, value) in source.entries() {
+            let result = dbs[shard::shard_for(key, args.shards)].import(key.clone(), value.clone());
+            if result != StoreResult::Stored {
+                return Err(format!("Failed to store {:?}: {:?}", key, result).into());
+            }
+            moved += 1;
+        }
+    }
+
+    for (shard_id, db) in dbs.iter().enumerate() {
+        if let Err(err) = db.snapshot() {
+            return Err(format!("Failed to take snapshot of shard {}: {}", shard_id, err).into());
+        }
+    }
+    // recorded last, so that an interrupted run is not mistaken for a
+    // finished one
+    let layout = ShardLayout {
+        shards: args.shards,
+        hash: SHARD_HASH.to_string(),
+    };
+    shard::write_layout(&output_store, &output_base, &layout)?;
+    println!(
+        "Resharded {} records from {} to {} shards",
+        moved, source_shards, args.shards
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use db::db::Value;
+
+    fn args(snapshot_path: &str, output_path: &str, shards: usize) -> Args {
+        Args {
+            snapshot_path: snapshot_path.to_string(),
+            output_path: output_path.to_string(),
+            shards,
+            restore_mode: RestoreMode::Strict,
+        }
+    }
+
+    // every key of the shards at path, with the shard it was found in
+    fn restore_all(path: &str, shards: usize) -> Vec<(usize, String, Value)> {
+        let mut entries = Vec::new();
+        for shard_id in 0..shards {
+            let mut db = DB::new(shard::shard_path(path, shard_id, shards));
+            db.restore(RestoreMode::Strict).unwrap();
+            for (key, value) in db.entries() {
+                entries.push((shard_id, key.clone(), value.clone()));
+            }
+        }
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+        entries
+    }
+
+    #[test]
+    fn test_reshard() {
+        let dir = "/tmp/test_reshard";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let single = format!("{}/single", dir);
+        let three = format!("{}/three", dir);
+        let two = format!("{}/two", dir);
+
+        let mut db = DB::new(single.clone());
+        for i in 0..100 {
+            db.insert(
+                format!("key{}", i),
+                Value {
+                    flags: i,
+                    exptime: 0,
+                    cas: 0,
+                    data: Bytes::from(format!("data{}", i)),
+                    stale: false,
+                    token_sent: false,
+                },
+            );
+        }
+        db.snapshot().unwrap();
+        let source = restore_all(&single, 1);
+
+        // a single shard has no layout, the resharded ones have one
+        reshard(&args(&single, &three, 3)).unwrap();
+        reshard(&args(&three, &two, 2)).unwrap();
+        for (path, shards) in [(&three, 3), (&two, 2)] {
+            let store = LocalStore::for_snapshot(path);
+            let layout = shard::read_layout(&store, &generation::base_name(path))
+                .unwrap()
+                .unwrap();
+            assert_eq!(layout.shards, shards);
+
+            // every key is kept once, in the shard that owns it
+            let entries = restore_all(path, shards);
+            assert_eq!(entries.len(), source.len());
+            for ((shard_id, key, value), (_, source_key, source_value)) in
+                entries.iter().zip(source.iter())
+            {
+                assert_eq!(key, source_key);
+                assert_eq!(*shard_id, shard::shard_for(key, shards));
+                assert_eq!(value.flags, source_value.flags);
+                assert_eq!(value.data, source_value.data);
+                assert_eq!(value.cas, source_value.cas);
+            }
+        }
+
+        // the output of a finished run is never overwritten
+        assert!(reshard(&args(&single, &two, 2)).is_err());
+        assert!(reshard(&args(&single, &single, 2)).is_err());
+        assert!(reshard(&args(&single, &format!("{}/zero", dir), 0)).is_err());
+
+        // nor are the shards left without a layout by an interrupted run
+        let interrupted = format!("{}/interrupted", dir);
+        let mut db = DB::new(shard::shard_path(&interrupted, 1, 2));
+        db.insert(
+            "stale".to_string(),
+            Value {
+                flags: 0,
+                exptime: 0,
+                cas: 0,
+                data: Bytes::from("stale"),
+                stale: false,
+                token_sent: false,
+            },
+        );
+        db.snapshot().unwrap();
+        assert!(reshard(&args(&single, &interrupted, 2)).is_err());
+        let log = format!("{}/log", dir);
+        let mut db = DB::new(shard::shard_path(&log, 0, 2));
+        db.open_log(aof::FsyncPolicy::No).unwrap();
+        assert!(reshard(&args(&single, &log, 2)).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
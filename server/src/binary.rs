@@ -0,0 +1,670 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::handler::Handler;
+use super::memcache::parse_key;
+use super::worker::SetMode;
+use db::db::{CounterResult, StoreResult, Value};
+use types::types::HorcruxError;
+
+// first byte of every binary request, used to tell the protocols apart
+pub const MAGIC_REQUEST: u8 = 0x80;
+const MAGIC_RESPONSE: u8 = 0x81;
+const HEADER_LEN: usize = 24;
+
+// incr/decr with this expiration fail on a missing key instead of creating it
+const NO_AUTO_CREATE: u32 = 0xffffffff;
+
+// -----------------------------------------------------------------------------
+// Opcodes
+// -----------------------------------------------------------------------------
+
+const OP_GET: u8 = 0x00;
+const OP_SET: u8 = 0x01;
+const OP_ADD: u8 = 0x02;
+const OP_REPLACE: u8 = 0x03;
+const OP_DELETE: u8 = 0x04;
+const OP_INCREMENT: u8 = 0x05;
+const OP_DECREMENT: u8 = 0x06;
+const OP_QUIT: u8 = 0x07;
+const OP_GETQ: u8 = 0x09;
+const OP_NOOP: u8 = 0x0a;
+const OP_VERSION: u8 = 0x0b;
+const OP_GETK: u8 = 0x0c;
+const OP_GETKQ: u8 = 0x0d;
+const OP_APPEND: u8 = 0x0e;
+const OP_PREPEND: u8 = 0x0f;
+const OP_SETQ: u8 = 0x11;
+const OP_ADDQ: u8 = 0x12;
+const OP_REPLACEQ: u8 = 0x13;
+const OP_DELETEQ: u8 = 0x14;
+const OP_INCREMENTQ: u8 = 0x15;
+const OP_DECREMENTQ: u8 = 0x16;
+const OP_QUITQ: u8 = 0x17;
+const OP_APPENDQ: u8 = 0x19;
+const OP_PREPENDQ: u8 = 0x1a;
+const OP_TOUCH: u8 = 0x1c;
+const OP_GAT: u8 = 0x1d;
+const OP_GATQ: u8 = 0x1e;
+const OP_GATK: u8 = 0x23;
+const OP_GATKQ: u8 = 0x24;
+
+// -----------------------------------------------------------------------------
+// Status codes
+// -----------------------------------------------------------------------------
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_VALUE_TOO_LARGE: u16 = 0x0003;
+const STATUS_INVALID_ARGUMENTS: u16 = 0x0004;
+const STATUS_ITEM_NOT_STORED: u16 = 0x0005;
+const STATUS_NON_NUMERIC: u16 = 0x0006;
+const STATUS_UNKNOWN_COMMAND: u16 = 0x0081;
+const STATUS_OUT_OF_MEMORY: u16 = 0x0082;
+const STATUS_INTERNAL_ERROR: u16 = 0x0084;
+
+// -----------------------------------------------------------------------------
+// Request
+// -----------------------------------------------------------------------------
+
+pub struct Request {
+    pub opcode: u8,
+    pub opaque: u32,
+    pub cas: u64,
+    pub extras: Bytes,
+    pub key: Bytes,
+    pub value: Bytes,
+    // the value was over the max item size and has been discarded
+    pub too_large: bool,
+}
+
+impl Request {
+    pub fn is_quit(&self) -> bool {
+        self.opcode == OP_QUIT || self.opcode == OP_QUITQ
+    }
+
+    // the commands that address a key
+    fn takes_key(&self) -> bool {
+        matches!(
+            self.opcode,
+            OP_GET
+                | OP_GETQ
+                | OP_GETK
+                | OP_GETKQ
+                | OP_GAT
+                | OP_GATQ
+                | OP_GATK
+                | OP_GATKQ
+                | OP_SET
+                | OP_SETQ
+                | OP_ADD
+                | OP_ADDQ
+                | OP_REPLACE
+                | OP_REPLACEQ
+                | OP_APPEND
+                | OP_APPENDQ
+                | OP_PREPEND
+                | OP_PREPENDQ
+                | OP_DELETE
+                | OP_DELETEQ
+                | OP_INCREMENT
+                | OP_INCREMENTQ
+                | OP_DECREMENT
+                | OP_DECREMENTQ
+                | OP_TOUCH
+        )
+    }
+
+    // quiet commands only answer on failure, GETQ variants only on a hit
+    fn is_quiet(&self) -> bool {
+        matches!(
+            self.opcode,
+            OP_GETQ
+                | OP_GETKQ
+                | OP_SETQ
+                | OP_ADDQ
+                | OP_REPLACEQ
+                | OP_DELETEQ
+                | OP_INCREMENTQ
+                | OP_DECREMENTQ
+                | OP_QUITQ
+                | OP_APPENDQ
+                | OP_PREPENDQ
+                | OP_GATQ
+                | OP_GATKQ
+        )
+    }
+}
+
+// format: <magic: u8><opcode: u8><key_len: u16><extras_len: u8><data_type: u8>
+//         <vbucket: u16><body_len: u32><opaque: u32><cas: u64><extras><key><value>
+//
+// a value over max_item_size is read and discarded instead of buffered, the
+// request is then marked too large
+pub async fn read_request<R>(reader: &mut R, max_item_size: usize) -> Result<Request, HorcruxError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; HEADER_LEN];
+    if reader.read_exact(&mut header).await.is_err() {
+        return Err(HorcruxError::Connection(
+            "Failed to read from socket".to_string(),
+        ));
+    }
+    let mut header = &header[..];
+    if header.get_u8() != MAGIC_REQUEST {
+        return Err(HorcruxError::Connection("Invalid magic".to_string()));
+    }
+    let opcode = header.get_u8();
+    let key_len = header.get_u16() as usize;
+    let extras_len = header.get_u8() as usize;
+    let _data_type = header.get_u8();
+    let _vbucket = header.get_u16();
+    let body_len = header.get_u32() as usize;
+    let opaque = header.get_u32();
+    let cas = header.get_u64();
+
+    if extras_len + key_len > body_len {
+        return Err(HorcruxError::Connection("Invalid body length".to_string()));
+    }
+    if body_len - extras_len - key_len > max_item_size {
+        let mut body = (&mut *reader).take(body_len as u64);
+        let discarded = tokio::io::copy(&mut body, &mut tokio::io::sink()).await;
+        if discarded.ok() != Some(body_len as u64) {
+            return Err(HorcruxError::Connection("Failed to read body".to_string()));
+        }
+        return Ok(Request {
+            opcode,
+            opaque,
+            cas,
+            extras: Bytes::new(),
+            key: Bytes::new(),
+            value: Bytes::new(),
+            too_large: true,
+        });
+    }
+    let mut body = vec![0; body_len];
+    if reader.read_exact(&mut body).await.is_err() {
+        return Err(HorcruxError::Connection("Failed to read body".to_string()));
+    }
+    let mut body = Bytes::from(body);
+    let extras = body.split_to(extras_len);
+    let key = body.split_to(key_len);
+
+    Ok(Request {
+        opcode,
+        opaque,
+        cas,
+        extras,
+        key,
+        value: body,
+        too_large: false,
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Response
+// -----------------------------------------------------------------------------
+
+pub struct Response {
+    opcode: u8,
+    status: u16,
+    opaque: u32,
+    cas: u64,
+    extras: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Response {
+    fn new(req: &Request, status: u16) -> Self {
+        Response {
+            opcode: req.opcode,
+            status,
+            opaque: req.opaque,
+            cas: 0,
+            extras: Vec::new(),
+            key: Vec::new(),
+            value: Vec::new(),
+        }
+    }
+
+    // errors carry a human readable message in the value, as memcached does
+    fn error(req: &Request, status: u16) -> Self {
+        let mut res = Response::new(req, status);
+        let msg = match status {
+            STATUS_KEY_NOT_FOUND => "Not found",
+            STATUS_KEY_EXISTS => "Data exists for key.",
+            STATUS_VALUE_TOO_LARGE => "Too large.",
+            STATUS_INVALID_ARGUMENTS => "Invalid arguments",
+            STATUS_ITEM_NOT_STORED => "Not stored.",
+            STATUS_NON_NUMERIC => "Non-numeric server-side value for incr or decr",
+            STATUS_UNKNOWN_COMMAND => "Unknown command",
+            _ => "Internal error",
+        };
+        res.value = msg.as_bytes().to_vec();
+        res
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let body_len = self.extras.len() + self.key.len() + self.value.len();
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + body_len);
+        buf.put_u8(MAGIC_RESPONSE);
+        buf.put_u8(self.opcode);
+        buf.put_u16(self.key.len() as u16);
+        buf.put_u8(self.extras.len() as u8);
+        buf.put_u8(0);
+        buf.put_u16(self.status);
+        buf.put_u32(body_len as u32);
+        buf.put_u32(self.opaque);
+        buf.put_u64(self.cas);
+        buf.put(&self.extras[..]);
+        buf.put(&self.key[..]);
+        buf.put(&self.value[..]);
+        buf.to_vec()
+    }
+}
+
+pub async fn send_response<W>(writer: &mut W, response: Response) -> Result<(), HorcruxError>
+where
+    W: AsyncWrite + Unpin,
+{
+    if writer.write_all(&response.as_bytes()).await.is_err() {
+        println!("Failed to send response");
+        return Err(HorcruxError::Connection(
+            "Failed to send response".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// Dispatch
+// -----------------------------------------------------------------------------
+
+// runs a request against the handler, returns None when a quiet command has
+// nothing to report
+pub fn handle<T: Handler>(handler: &T, req: Request) -> Option<Response> {
+    if req.too_large {
+        return Some(Response::error(&req, STATUS_VALUE_TOO_LARGE));
+    }
+
+    let res = match req.opcode {
+        OP_NOOP | OP_QUIT | OP_QUITQ => Response::new(&req, STATUS_OK),
+        OP_VERSION => {
+            let mut res = Response::new(&req, STATUS_OK);
+            res.value = env!("CARGO_PKG_VERSION").as_bytes().to_vec();
+            res
+        }
+        _ if req.takes_key() => match parse_key(&req.key) {
+            Ok(key) => handle_key(handler, &req, key),
+            Err(_) => Response::error(&req, STATUS_INVALID_ARGUMENTS),
+        },
+        _ => Response::error(&req, STATUS_UNKNOWN_COMMAND),
+    };
+
+    // quiet gets stay silent on a miss, other quiet commands on success
+    let silent = match req.opcode {
+        OP_GETQ | OP_GETKQ | OP_GATQ | OP_GATKQ => res.status == STATUS_KEY_NOT_FOUND,
+        _ => res.status == STATUS_OK,
+    };
+    if req.is_quiet() && silent {
+        return None;
+    }
+    Some(res)
+}
+
+// runs a command that addresses a key
+fn handle_key<T: Handler>(handler: &T, req: &Request, key: String) -> Response {
+    match req.opcode {
+        OP_GET | OP_GETQ | OP_GETK | OP_GETKQ => {
+            let hit = handler.get_many(vec![key]).pop();
+            value_response(req, hit, matches!(req.opcode, OP_GETK | OP_GETKQ))
+        }
+        OP_GAT | OP_GATQ | OP_GATK | OP_GATKQ => match read_extras_u32(req) {
+            Some(exptime) => match handler.get_and_touch(vec![key], exptime) {
+                Ok(mut hits) => {
+                    value_response(req, hits.pop(), matches!(req.opcode, OP_GATK | OP_GATKQ))
+                }
+                Err(_) => Response::error(req, STATUS_INTERNAL_ERROR),
+            },
+            None => Response::error(req, STATUS_INVALID_ARGUMENTS),
+        },
+        OP_SET | OP_SETQ | OP_ADD | OP_ADDQ | OP_REPLACE | OP_REPLACEQ => store(handler, req, key),
+        OP_APPEND | OP_APPENDQ | OP_PREPEND | OP_PREPENDQ => {
+            let mode = if matches!(req.opcode, OP_APPEND | OP_APPENDQ) {
+                SetMode::Append
+            } else {
+                SetMode::Prepend
+            };
+            let result = handler.meta_set(key, mode, 0, 0, req.value.clone(), None);
+            store_response(req, result)
+        }
+        OP_DELETE | OP_DELETEQ => match handler.delete(key) {
+            Ok(true) => Response::new(req, STATUS_OK),
+            Ok(false) => Response::error(req, STATUS_KEY_NOT_FOUND),
+            Err(_) => Response::error(req, STATUS_INTERNAL_ERROR),
+        },
+        OP_INCREMENT | OP_INCREMENTQ | OP_DECREMENT | OP_DECREMENTQ => counter(handler, req, key),
+        OP_TOUCH => match read_extras_u32(req) {
+            Some(exptime) => match handler.touch(key, exptime) {
+                Ok(true) => Response::new(req, STATUS_OK),
+                Ok(false) => Response::error(req, STATUS_KEY_NOT_FOUND),
+                Err(_) => Response::error(req, STATUS_INTERNAL_ERROR),
+            },
+            None => Response::error(req, STATUS_INVALID_ARGUMENTS),
+        },
+        _ => Response::error(req, STATUS_UNKNOWN_COMMAND),
+    }
+}
+
+fn read_extras_u32(req: &Request) -> Option<u32> {
+    if req.extras.len() != 4 {
+        return None;
+    }
+    Some(req.extras.clone().get_u32())
+}
+
+fn value_response(req: &Request, hit: Option<(String, Value)>, with_key: bool) -> Response {
+    match hit {
+        Some((key, value)) => {
+            let mut res = Response::new(req, STATUS_OK);
+            res.cas = value.cas;
+            res.extras = value.flags.to_be_bytes().to_vec();
+            if with_key {
+                res.key = key.into_bytes();
+            }
+            res.value = value.data.to_vec();
+            res
+        }
+        None => {
+            let mut res = Response::error(req, STATUS_KEY_NOT_FOUND);
+            if with_key {
+                res.key = req.key.to_vec();
+            }
+            res
+        }
+    }
+}
+
+// extras: <flags: u32><exptime: u32>
+fn store<T: Handler>(handler: &T, req: &Request, key: String) -> Response {
+    if req.extras.len() != 8 {
+        return Response::error(req, STATUS_INVALID_ARGUMENTS);
+    }
+    let mut extras = req.extras.clone();
+    let flags = extras.get_u32();
+    let exptime = extras.get_u32();
+    let data = req.value.clone();
+
+    let (mode, cas) = match req.opcode {
+        // a non-zero cas turns set into a compare-and-swap
+        OP_SET | OP_SETQ if req.cas != 0 => (SetMode::Set, Some(req.cas)),
+        OP_SET | OP_SETQ => (SetMode::Set, None),
+        OP_ADD | OP_ADDQ => (SetMode::Add, None),
+        _ => (SetMode::Replace, None),
+    };
+    let result = handler.meta_set(key, mode, flags, exptime, data, cas);
+    store_response(req, result)
+}
+
+// a stored value is answered with its new cas token
+fn store_response(
+    req: &Request,
+    result: Result<(StoreResult, Option<Value>), HorcruxError>,
+) -> Response {
+    match result {
+        Ok((StoreResult::Stored, value)) => {
+            let mut res = Response::new(req, STATUS_OK);
+            res.cas = value.map_or(0, |value| value.cas);
+            res
+        }
+        Ok((StoreResult::NotStored, _)) => match req.opcode {
+            OP_ADD | OP_ADDQ => Response::error(req, STATUS_KEY_EXISTS),
+            OP_REPLACE | OP_REPLACEQ => Response::error(req, STATUS_KEY_NOT_FOUND),
+            _ => Response::error(req, STATUS_ITEM_NOT_STORED),
+        },
+        Ok((StoreResult::Exists, _)) => Response::error(req, STATUS_KEY_EXISTS),
+        Ok((StoreResult::NotFound, _)) => Response::error(req, STATUS_KEY_NOT_FOUND),
+        Ok((StoreResult::OutOfMemory, _)) | Err(HorcruxError::OutOfMemory) => {
+            Response::error(req, STATUS_OUT_OF_MEMORY)
+        }
+        Ok((StoreResult::LogFailed, _)) | Err(_) => Response::error(req, STATUS_INTERNAL_ERROR),
+    }
+}
+
+// extras: <delta: u64><initial: u64><exptime: u32>
+fn counter<T: Handler>(handler: &T, req: &Request, key: String) -> Response {
+    if req.extras.len() != 20 {
+        return Response::error(req, STATUS_INVALID_ARGUMENTS);
+    }
+    let mut extras = req.extras.clone();
+    let delta = extras.get_u64();
+    let initial = extras.get_u64();
+    let exptime = extras.get_u32();
+    let incr = matches!(req.opcode, OP_INCREMENT | OP_INCREMENTQ);
+    let update = |key: String| {
+        if incr {
+            handler.incr(key, delta)
+        } else {
+            handler.decr(key, delta)
+        }
+    };
+
+    let mut result = update(key.clone());
+    if let Ok(CounterResult::NotFound) = result {
+        if exptime != NO_AUTO_CREATE {
+            // create the counter with the initial value, unless another
+            // client got there first
+            result = match handler.add(key.clone(), 0, exptime, initial.to_string().into()) {
+                Ok(StoreResult::Stored) => Ok(CounterResult::Value(initial)),
+                Ok(_) => update(key),
+                Err(err) => Err(err),
+            };
+        }
+    }
+
+    match result {
+        Ok(CounterResult::Value(n)) => {
+            let mut res = Response::new(req, STATUS_OK);
+            res.value = n.to_be_bytes().to_vec();
+            res
+        }
+        Ok(CounterResult::NotFound) => Response::error(req, STATUS_KEY_NOT_FOUND),
+        Ok(CounterResult::NonNumeric) => Response::error(req, STATUS_NON_NUMERIC),
+        Ok(CounterResult::OutOfMemory) | Err(HorcruxError::OutOfMemory) => {
+            Response::error(req, STATUS_OUT_OF_MEMORY)
+        }
+        Ok(CounterResult::LogFailed) | Err(_) => Response::error(req, STATUS_INTERNAL_ERROR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::BaseHandler;
+    use crate::worker::{JobQueue, Worker};
+    use db::db::DB;
+    use std::io::Cursor;
+    use std::thread;
+
+    fn encode_request(opcode: u8, opaque: u32, extras: &[u8], key: &str, value: &str) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_u8(MAGIC_REQUEST);
+        buf.put_u8(opcode);
+        buf.put_u16(key.len() as u16);
+        buf.put_u8(extras.len() as u8);
+        buf.put_u8(0);
+        buf.put_u16(0);
+        buf.put_u32((extras.len() + key.len() + value.len()) as u32);
+        buf.put_u32(opaque);
+        buf.put_u64(0);
+        buf.put(extras);
+        buf.put(key.as_bytes());
+        buf.put(value.as_bytes());
+        buf.to_vec()
+    }
+
+    fn start_handler() -> BaseHandler {
+        let job_queue = JobQueue::new();
+        let mut worker = Worker::new(job_queue.clone(), DB::new("/tmp".to_string()), vec![]);
+        thread::spawn(move || {
+            worker.run();
+        });
+        BaseHandler::new(job_queue)
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let data = encode_request(OP_SET, 7, &[0, 0, 0, 1, 0, 0, 0, 0], "key", "value");
+        let mut socket = Cursor::new(data);
+
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        assert_eq!(req.opcode, OP_SET);
+        assert_eq!(req.opaque, 7);
+        assert_eq!(req.extras.len(), 8);
+        assert_eq!(&req.key[..], b"key");
+        assert_eq!(&req.value[..], b"value");
+    }
+
+    #[tokio::test]
+    async fn test_read_request_too_large() {
+        let handler = start_handler();
+        let mut data = encode_request(OP_SETQ, 1, &[0; 8], "key", &"x".repeat(1025));
+        data.extend(encode_request(OP_GET, 2, &[], "key", ""));
+        let mut socket = Cursor::new(data);
+
+        // the value is discarded and the connection stays usable
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        assert!(req.too_large);
+        let res = handle(&handler, req).unwrap();
+        assert_eq!(res.status, STATUS_VALUE_TOO_LARGE);
+        assert_eq!(res.opaque, 1);
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        assert_eq!(handle(&handler, req).unwrap().status, STATUS_KEY_NOT_FOUND);
+
+        // a huge body length is not allocated up front
+        let mut data = encode_request(OP_SET, 1, &[0; 8], "key", "value");
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        let mut socket = Cursor::new(data);
+        assert!(read_request(&mut socket, 1024).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quiet_pipeline() {
+        let handler = start_handler();
+        let mut data = Vec::new();
+        data.extend(encode_request(OP_SETQ, 1, &[0; 8], "key", "value"));
+        data.extend(encode_request(OP_GETQ, 2, &[], "missing", ""));
+        data.extend(encode_request(OP_GETKQ, 3, &[], "key", ""));
+        data.extend(encode_request(OP_NOOP, 4, &[], "", ""));
+        let mut socket = Cursor::new(data);
+
+        let mut responses = Vec::new();
+        for _ in 0..4 {
+            let req = read_request(&mut socket, 1024).await.unwrap();
+            if let Some(res) = handle(&handler, req) {
+                responses.push(res);
+            }
+        }
+
+        // only the hit and the noop are answered
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].opaque, 3);
+        assert_eq!(responses[0].key, b"key");
+        assert_eq!(responses[0].value, b"value");
+        assert_eq!(responses[1].opcode, OP_NOOP);
+    }
+
+    #[tokio::test]
+    async fn test_binary_value() {
+        let handler = start_handler();
+        let value = [0x00, 0xff, 0x80, b'\r', b'\n'];
+        let mut data = encode_request(OP_SET, 1, &[0; 8], "key", "");
+        data.extend(value);
+        data[11] += value.len() as u8;
+        data.extend(encode_request(OP_GET, 2, &[], "key", ""));
+        data.extend(encode_request(OP_GET, 3, &[], "bad key", ""));
+        let mut socket = Cursor::new(data);
+
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        assert_eq!(handle(&handler, req).unwrap().status, STATUS_OK);
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        assert_eq!(handle(&handler, req).unwrap().value, value);
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        let res = handle(&handler, req).unwrap();
+        assert_eq!(res.status, STATUS_INVALID_ARGUMENTS);
+    }
+
+    #[tokio::test]
+    async fn test_increment_creates_counter() {
+        let handler = start_handler();
+        let mut extras = BytesMut::new();
+        extras.put_u64(5);
+        extras.put_u64(10);
+        extras.put_u32(0);
+        let data = [
+            encode_request(OP_INCREMENT, 1, &extras, "counter", ""),
+            encode_request(OP_INCREMENT, 2, &extras, "counter", ""),
+        ]
+        .concat();
+        let mut socket = Cursor::new(data);
+
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        let res = handle(&handler, req).unwrap();
+        assert_eq!(res.value, 10u64.to_be_bytes());
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        let res = handle(&handler, req).unwrap();
+        assert_eq!(res.value, 15u64.to_be_bytes());
+    }
+    #[tokio::test]
+    async fn test_store_returns_cas() {
+        let handler = start_handler();
+        let data = [
+            encode_request(OP_SET, 1, &[0; 8], "key", "value"),
+            encode_request(OP_APPEND, 2, &[], "key", "!"),
+            encode_request(OP_GET, 3, &[], "key", ""),
+        ]
+        .concat();
+        let mut socket = Cursor::new(data);
+
+        let mut tokens = Vec::new();
+        for _ in 0..3 {
+            let req = read_request(&mut socket, 1024).await.unwrap();
+            let res = handle(&handler, req).unwrap();
+            assert_eq!(res.status, STATUS_OK);
+            tokens.push(res.cas);
+        }
+        assert_ne!(tokens[0], 0);
+        assert_ne!(tokens[1], tokens[0]);
+        assert_eq!(tokens[2], tokens[1]);
+
+        // the returned token is the one a cas set must use
+        let mut data = encode_request(OP_SET, 4, &[0; 8], "key", "new");
+        data[16..24].copy_from_slice(&tokens[0].to_be_bytes());
+        let mut stale = data.clone();
+        data[16..24].copy_from_slice(&tokens[1].to_be_bytes());
+        stale.extend(data);
+        let mut socket = Cursor::new(stale);
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        assert_eq!(handle(&handler, req).unwrap().status, STATUS_KEY_EXISTS);
+        let req = read_request(&mut socket, 1024).await.unwrap();
+        let res = handle(&handler, req).unwrap();
+        assert_eq!(res.status, STATUS_OK);
+        assert!(res.cas > tokens[1]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command() {
+        let handler = start_handler();
+        // flush, stat and verbosity do not take a key
+        for opcode in [0x08, 0x10, 0x1b] {
+            let data = encode_request(opcode, 1, &[], "", "");
+            let req = read_request(&mut Cursor::new(data), 1024).await.unwrap();
+            let res = handle(&handler, req).unwrap();
+            assert_eq!(res.status, STATUS_UNKNOWN_COMMAND);
+            assert_eq!(res.value, b"Unknown command");
+        }
+    }
+}
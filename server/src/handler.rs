@@ -1,25 +1,234 @@
-use super::worker::{JobQueue, Request, Response};
-use db::db::Value;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use super::supervisor::SnapshotStatus;
+use super::worker::{Arithmetic, JobQueue, Request, Response, SetMode};
+use bytes::Bytes;
+use crossbeam_channel::RecvError;
+use db::db::{deadline, CounterResult, Lease, LeaseOptions, StoreResult, Value};
+use db::memory::MemoryStats;
+use db::shard::shard_for;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use types::types::HorcruxError;
 
 // -----------------------------------------------------------------------------
 // Handler trait
 // -----------------------------------------------------------------------------
 
-pub trait Handler: Clone + SetHandler + GetHandler + SnapshotHandler {}
+pub trait Handler:
+    Clone
+    + SetHandler
+    + AddHandler
+    + ReplaceHandler
+    + AppendHandler
+    + PrependHandler
+    + CasHandler
+    + GetHandler
+    + DeleteHandler
+    + IncrHandler
+    + TouchHandler
+    + MetaHandler
+    + SnapshotHandler
+    + StatsHandler
+    + ReshardHandler
+{
+}
 
 pub trait SetHandler {
-    fn set(&self, key: String, flags: u32, exptime: u32, data: String) -> Result<(), HorcruxError>;
+    fn set(&self, key: String, flags: u32, exptime: u32, data: Bytes) -> Result<(), HorcruxError>;
+}
+
+pub trait AddHandler {
+    fn add(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+    ) -> Result<StoreResult, HorcruxError>;
+}
+
+pub trait ReplaceHandler {
+    fn replace(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+    ) -> Result<StoreResult, HorcruxError>;
+}
+
+pub trait AppendHandler {
+    fn append(&self, key: String, data: Bytes) -> Result<StoreResult, HorcruxError>;
+}
+
+pub trait PrependHandler {
+    fn prepend(&self, key: String, data: Bytes) -> Result<StoreResult, HorcruxError>;
+}
+
+pub trait CasHandler {
+    fn cas(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        cas: u64,
+    ) -> Result<StoreResult, HorcruxError>;
 }
 
 pub trait GetHandler {
     fn get(&self, key: &str) -> Option<Value>;
+
+    // returns the hits in the order of the requested keys
+    fn get_many(&self, keys: Vec<String>) -> Vec<(String, Value)>;
+
+    // reads a value and tells whether the caller should recache it, the
+    // exptimes in opts are relative like in requests
+    fn get_with_lease(
+        &self,
+        key: String,
+        opts: LeaseOptions,
+    ) -> Result<Option<(Value, Lease)>, HorcruxError>;
+}
+
+pub trait DeleteHandler {
+    // returns false if the key does not exist
+    fn delete(&self, key: String) -> Result<bool, HorcruxError>;
+
+    // marks the value as stale instead of removing it, returns false if the
+    // key does not exist
+    fn invalidate(&self, key: String, exptime: Option<u32>) -> Result<bool, HorcruxError>;
+}
+
+pub trait IncrHandler {
+    fn incr(&self, key: String, delta: u64) -> Result<CounterResult, HorcruxError>;
+
+    fn decr(&self, key: String, delta: u64) -> Result<CounterResult, HorcruxError>;
+}
+
+pub trait TouchHandler {
+    // returns false if the key does not exist
+    fn touch(&self, key: String, exptime: u32) -> Result<bool, HorcruxError>;
+
+    // returns the hits in the order of the requested keys
+    fn get_and_touch(
+        &self,
+        keys: Vec<String>,
+        exptime: u32,
+    ) -> Result<Vec<(String, Value)>, HorcruxError>;
+}
+
+// the writes of the meta protocol, which return the value they left so that
+// its cas and ttl can be reported without reading it again
+pub trait MetaHandler {
+    fn meta_set(
+        &self,
+        key: String,
+        mode: SetMode,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        cas: Option<u64>,
+    ) -> Result<(StoreResult, Option<Value>), HorcruxError>;
+
+    // the exptimes in op are relative like in requests
+    fn meta_arithmetic(
+        &self,
+        key: String,
+        op: Arithmetic,
+    ) -> Result<(CounterResult, Option<Value>), HorcruxError>;
 }
 
 pub trait SnapshotHandler {
     fn snapshot(&self, wait: bool) -> Result<(), HorcruxError>;
+    fn snapshot_status(&self) -> Result<SnapshotStatus, HorcruxError>;
+}
+
+pub trait StatsHandler {
+    fn memory_stats(&self) -> Result<MemoryStats, HorcruxError>;
+}
+
+pub trait ReshardHandler {
+    // spreads the keys over a new number of shards while serving reads
+    fn reshard(&self, shards: usize) -> Result<(), HorcruxError>;
+}
+
+fn new_value(flags: u32, exptime: u32, data: Bytes) -> Value {
+    Value {
+        flags,
+        exptime: deadline(exptime),
+        cas: 0,
+        data,
+        stale: false,
+        token_sent: false,
+    }
+}
+
+fn lease_options(opts: LeaseOptions) -> LeaseOptions {
+    LeaseOptions {
+        touch: opts.touch.map(deadline),
+        vivify: opts.vivify.map(deadline),
+        recache: opts.recache,
+    }
+}
+
+fn arithmetic(op: Arithmetic) -> Arithmetic {
+    Arithmetic {
+        vivify: op
+            .vivify
+            .map(|(initial, exptime)| (initial, deadline(exptime))),
+        touch: op.touch.map(deadline),
+        ..op
+    }
+}
+
+// splits the response of a meta write with to_result
+fn written<T>(
+    result: Result<Response, RecvError>,
+    to_result: fn(Result<Response, RecvError>) -> Result<T, HorcruxError>,
+) -> Result<(T, Option<Value>), HorcruxError> {
+    match result {
+        Ok(Response::Written(res, value)) => Ok((to_result(Ok(*res))?, value)),
+        _ => Err(HorcruxError::Internal),
+    }
+}
+
+fn store_result(result: Result<Response, RecvError>) -> Result<StoreResult, HorcruxError> {
+    match result {
+        Ok(Response::Stored) => Ok(StoreResult::Stored),
+        Ok(Response::NotStored) => Ok(StoreResult::NotStored),
+        Ok(Response::Exists) => Ok(StoreResult::Exists),
+        Ok(Response::NotFound) => Ok(StoreResult::NotFound),
+        Ok(Response::OutOfMemory) => Err(HorcruxError::OutOfMemory),
+        Ok(Response::LogFailed) => Err(HorcruxError::LogFailed),
+        _ => Err(HorcruxError::Internal),
+    }
+}
+
+fn counter_result(result: Result<Response, RecvError>) -> Result<CounterResult, HorcruxError> {
+    match result {
+        Ok(Response::Number(n)) => Ok(CounterResult::Value(n)),
+        Ok(Response::NotFound) => Ok(CounterResult::NotFound),
+        Ok(Response::NonNumeric) => Ok(CounterResult::NonNumeric),
+        Ok(Response::OutOfMemory) => Err(HorcruxError::OutOfMemory),
+        Ok(Response::LogFailed) => Err(HorcruxError::LogFailed),
+        _ => Err(HorcruxError::Internal),
+    }
+}
+
+// true for DELETED and TOUCHED, false for NOT_FOUND
+fn found_result(result: Result<Response, RecvError>) -> Result<bool, HorcruxError> {
+    match result {
+        Ok(Response::Deleted) | Ok(Response::Touched) => Ok(true),
+        Ok(Response::NotFound) => Ok(false),
+        Ok(Response::LogFailed) => Err(HorcruxError::LogFailed),
+        _ => Err(HorcruxError::Internal),
+    }
+}
+
+fn hits(keys: Vec<String>, values: Vec<Option<Value>>) -> Vec<(String, Value)> {
+    keys.into_iter()
+        .zip(values)
+        .filter_map(|(key, val)| val.map(|val| (key, val)))
+        .collect()
 }
 
 // -----------------------------------------------------------------------------
@@ -44,26 +253,94 @@ impl Clone for BaseHandler {
 }
 
 impl SetHandler for BaseHandler {
-    fn set(
-        &self,
-        key: String,
-        flags: u32,
-        _exptime: u32,
-        data: String,
-    ) -> Result<(), HorcruxError> {
-        let value = Value { flags, data };
+    fn set(&self, key: String, flags: u32, exptime: u32, data: Bytes) -> Result<(), HorcruxError> {
+        let value = new_value(flags, exptime, data);
         let result = self
             .job_queue
             .send_request(Request::Set { key, value })
             .recv();
         match result {
             Ok(Response::Stored) => {}
+            Ok(Response::OutOfMemory) => return Err(HorcruxError::OutOfMemory),
+            Ok(Response::LogFailed) => return Err(HorcruxError::LogFailed),
             _ => return Err(HorcruxError::Internal),
         }
         Ok(())
     }
 }
 
+impl AddHandler for BaseHandler {
+    fn add(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+    ) -> Result<StoreResult, HorcruxError> {
+        let value = new_value(flags, exptime, data);
+        store_result(
+            self.job_queue
+                .send_request(Request::Add { key, value })
+                .recv(),
+        )
+    }
+}
+
+impl ReplaceHandler for BaseHandler {
+    fn replace(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+    ) -> Result<StoreResult, HorcruxError> {
+        let value = new_value(flags, exptime, data);
+        store_result(
+            self.job_queue
+                .send_request(Request::Replace { key, value })
+                .recv(),
+        )
+    }
+}
+
+impl AppendHandler for BaseHandler {
+    fn append(&self, key: String, data: Bytes) -> Result<StoreResult, HorcruxError> {
+        store_result(
+            self.job_queue
+                .send_request(Request::Append { key, data })
+                .recv(),
+        )
+    }
+}
+
+impl PrependHandler for BaseHandler {
+    fn prepend(&self, key: String, data: Bytes) -> Result<StoreResult, HorcruxError> {
+        store_result(
+            self.job_queue
+                .send_request(Request::Prepend { key, data })
+                .recv(),
+        )
+    }
+}
+
+impl CasHandler for BaseHandler {
+    fn cas(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        cas: u64,
+    ) -> Result<StoreResult, HorcruxError> {
+        let value = new_value(flags, exptime, data);
+        store_result(
+            self.job_queue
+                .send_request(Request::Cas { key, value, cas })
+                .recv(),
+        )
+    }
+}
+
 impl GetHandler for BaseHandler {
     fn get(&self, key: &str) -> Option<Value> {
         let result = self
@@ -78,116 +355,1111 @@ impl GetHandler for BaseHandler {
             _ => None,
         }
     }
+
+    fn get_many(&self, keys: Vec<String>) -> Vec<(String, Value)> {
+        let result = self
+            .job_queue
+            .send_request(Request::GetMany { keys: keys.clone() })
+            .recv();
+
+        match result {
+            Ok(Response::Values(values)) => hits(keys, values),
+            _ => Vec::new(),
+        }
+    }
+
+    fn get_with_lease(
+        &self,
+        key: String,
+        opts: LeaseOptions,
+    ) -> Result<Option<(Value, Lease)>, HorcruxError> {
+        let opts = lease_options(opts);
+        match self
+            .job_queue
+            .send_request(Request::GetWithLease { key, opts })
+            .recv()
+        {
+            Ok(Response::Leased(res)) => Ok(res),
+            Ok(Response::LogFailed) => Err(HorcruxError::LogFailed),
+            _ => Err(HorcruxError::Internal),
+        }
+    }
+}
+
+impl DeleteHandler for BaseHandler {
+    fn delete(&self, key: String) -> Result<bool, HorcruxError> {
+        found_result(self.job_queue.send_request(Request::Delete { key }).recv())
+    }
+
+    fn invalidate(&self, key: String, exptime: Option<u32>) -> Result<bool, HorcruxError> {
+        let exptime = exptime.map(deadline);
+        found_result(
+            self.job_queue
+                .send_request(Request::Invalidate { key, exptime })
+                .recv(),
+        )
+    }
+}
+
+impl IncrHandler for BaseHandler {
+    fn incr(&self, key: String, delta: u64) -> Result<CounterResult, HorcruxError> {
+        counter_result(
+            self.job_queue
+                .send_request(Request::Incr { key, delta })
+                .recv(),
+        )
+    }
+
+    fn decr(&self, key: String, delta: u64) -> Result<CounterResult, HorcruxError> {
+        counter_result(
+            self.job_queue
+                .send_request(Request::Decr { key, delta })
+                .recv(),
+        )
+    }
+}
+
+impl TouchHandler for BaseHandler {
+    fn touch(&self, key: String, exptime: u32) -> Result<bool, HorcruxError> {
+        let exptime = deadline(exptime);
+        found_result(
+            self.job_queue
+                .send_request(Request::Touch { key, exptime })
+                .recv(),
+        )
+    }
+
+    fn get_and_touch(
+        &self,
+        keys: Vec<String>,
+        exptime: u32,
+    ) -> Result<Vec<(String, Value)>, HorcruxError> {
+        let result = self
+            .job_queue
+            .send_request(Request::GetAndTouch {
+                keys: keys.clone(),
+                exptime: deadline(exptime),
+            })
+            .recv();
+
+        match result {
+            Ok(Response::Values(values)) => Ok(hits(keys, values)),
+            Ok(Response::LogFailed) => Err(HorcruxError::LogFailed),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+impl MetaHandler for BaseHandler {
+    fn meta_set(
+        &self,
+        key: String,
+        mode: SetMode,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        cas: Option<u64>,
+    ) -> Result<(StoreResult, Option<Value>), HorcruxError> {
+        let value = new_value(flags, exptime, data);
+        written(
+            self.job_queue
+                .send_request(Request::MetaSet {
+                    key,
+                    value,
+                    mode,
+                    cas,
+                })
+                .recv(),
+            store_result,
+        )
+    }
+
+    fn meta_arithmetic(
+        &self,
+        key: String,
+        op: Arithmetic,
+    ) -> Result<(CounterResult, Option<Value>), HorcruxError> {
+        let op = arithmetic(op);
+        written(
+            self.job_queue
+                .send_request(Request::MetaArithmetic { key, op })
+                .recv(),
+            counter_result,
+        )
+    }
 }
 
 impl SnapshotHandler for BaseHandler {
     fn snapshot(&self, wait: bool) -> Result<(), HorcruxError> {
         match self
             .job_queue
-            .send_request(Request::Snapshot { wait: wait })
+            .send_request(Request::Snapshot { wait })
             .recv()
         {
             Ok(Response::SnapshotAccepted) => Ok(()),
             Ok(Response::SnapshotFinished) => Ok(()),
-            Ok(Response::SnapshotFailed) => Err(HorcruxError::Internal),
+            Ok(Response::SnapshotFailed) => Err(HorcruxError::Snapshot("failed".to_string())),
+            _ => Err(HorcruxError::Internal),
+        }
+    }
+
+    fn snapshot_status(&self) -> Result<SnapshotStatus, HorcruxError> {
+        match self.job_queue.send_request(Request::SnapshotStatus).recv() {
+            Ok(Response::SnapshotStatus(status)) => Ok(status),
             _ => Err(HorcruxError::Internal),
         }
     }
 }
 
+impl StatsHandler for BaseHandler {
+    fn memory_stats(&self) -> Result<MemoryStats, HorcruxError> {
+        match self.job_queue.send_request(Request::MemoryStats).recv() {
+            Ok(Response::MemoryStats(stats)) => Ok(stats),
+            _ => Err(HorcruxError::Internal),
+        }
+    }
+}
+
+impl ReshardHandler for BaseHandler {
+    fn reshard(&self, _shards: usize) -> Result<(), HorcruxError> {
+        Err(HorcruxError::Reshard(
+            "needs a server started with more than one shard".to_string(),
+        ))
+    }
+}
+
 impl Handler for BaseHandler {}
 
 // -----------------------------------------------------------------------------
 // ShardHandler
 // -----------------------------------------------------------------------------
 
-pub struct ShardHandler {
+// lets an online reshard start and retire shards, provided by the server
+pub trait ShardHost: Send + Sync {
+    // starts the worker of an empty shard, discarding stale snapshots left
+    // at its path
+    fn start_shard(&self, shard_id: usize, shards: usize) -> Result<JobQueue, HorcruxError>;
+
+    // records that the snapshots are now spread over this many shards
+    fn write_layout(&self, shards: usize) -> Result<(), HorcruxError>;
+
+    // deletes the snapshots of a stopped shard
+    fn remove_shard(&self, shard_id: usize, shards: usize) -> Result<(), HorcruxError>;
+}
+
+// which worker owns which key, replaced as a whole by a reshard
+struct Routing {
     job_queues: Vec<JobQueue>,
+    // keys are hashed over the first `shards` queues, the others belong to
+    // shards being added or retired by a reshard
+    shards: usize,
+    // writes are refused while keys are copied to their new shards
+    resharding: bool,
+}
+
+impl Routing {
+    fn shard_id(&self, key: &str) -> usize {
+        shard_for(key, self.shards)
+    }
+
+    fn shard(&self, key: &str) -> &JobQueue {
+        &self.job_queues[self.shard_id(key)]
+    }
+
+    // sends one batched request per shard and returns the hits in the order
+    // of the requested keys
+    fn fan_out<F>(
+        &self,
+        keys: Vec<String>,
+        make_request: F,
+    ) -> Result<Vec<(String, Value)>, HorcruxError>
+    where
+        F: Fn(Vec<String>) -> Request,
+    {
+        // group the keys by shard, remembering where each key was requested
+        let mut batches: Vec<(Vec<usize>, Vec<String>)> =
+            vec![(Vec::new(), Vec::new()); self.shards];
+        for (i, key) in keys.iter().enumerate() {
+            let (positions, shard_keys) = &mut batches[self.shard_id(key)];
+            positions.push(i);
+            shard_keys.push(key.clone());
+        }
+
+        // send one request per shard before waiting for any of them
+        let receivers = batches
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (positions, _))| !positions.is_empty())
+            .map(|(shard_id, (positions, shard_keys))| {
+                let receiver = self.job_queues[shard_id].send_request(make_request(shard_keys));
+                (shard_id, positions, receiver)
+            })
+            .collect::<Vec<_>>();
+
+        let mut values = vec![None; keys.len()];
+        let mut result = Ok(());
+        for (shard_id, positions, receiver) in receivers {
+            match receiver.recv() {
+                Ok(Response::Values(shard_values)) => {
+                    for (i, val) in positions.into_iter().zip(shard_values) {
+                        values[i] = val.map(|mut val| {
+                            val.cas = self.global_cas(shard_id, val.cas);
+                            val
+                        });
+                    }
+                }
+                // the other shards are still waited for
+                Ok(Response::LogFailed) => result = result.and(Err(HorcruxError::LogFailed)),
+                _ => result = result.and(Err(HorcruxError::Internal)),
+            }
+        }
+        result.map(|_| hits(keys, values))
+    }
+
+    // each shard numbers its cas tokens independently, so the shard id is
+    // folded into the token to keep it unique across shards. tokens handed
+    // out before a reshard are refused after it. a token too large to fold,
+    // as a restored one may be, is reported as 0 so that it is refused
+    // instead of matching a value of another shard
+    fn global_cas(&self, shard_id: usize, cas: u64) -> u64 {
+        cas.checked_mul(self.job_queues.len() as u64)
+            .and_then(|cas| cas.checked_add(shard_id as u64))
+            .unwrap_or(0)
+    }
+
+    // returns 0, which no shard ever issues, for a token of another shard
+    fn local_cas(&self, shard_id: usize, cas: u64) -> u64 {
+        let shards = self.job_queues.len() as u64;
+        if cas % shards != shard_id as u64 {
+            return 0;
+        }
+        cas / shards
+    }
+}
+
+pub struct ShardHandler {
+    routing: Arc<RwLock<Arc<Routing>>>,
+    host: Option<Arc<dyn ShardHost>>,
 }
 
 impl ShardHandler {
     pub fn new(job_queues: Vec<JobQueue>) -> Self {
-        ShardHandler { job_queues }
+        let routing = Routing {
+            shards: job_queues.len(),
+            job_queues,
+            resharding: false,
+        };
+        ShardHandler {
+            routing: Arc::new(RwLock::new(Arc::new(routing))),
+            host: None,
+        }
+    }
+
+    // enables online resharding
+    pub fn set_host(&mut self, host: Arc<dyn ShardHost>) {
+        self.host = Some(host);
+    }
+
+    fn routing(&self) -> Arc<Routing> {
+        self.routing.read().unwrap().clone()
+    }
+
+    // the routing for a mutation, refused while resharding. the mutation
+    // must be sent before the guard is dropped: a reshard takes the write
+    // lock, so it only exports the shards once every mutation routed with
+    // the old layout is queued ahead of the export
+    fn writable(&self) -> Result<RwLockReadGuard<'_, Arc<Routing>>, HorcruxError> {
+        let routing = self.routing.read().unwrap();
+        if routing.resharding {
+            return Err(HorcruxError::Resharding);
+        }
+        Ok(routing)
+    }
+
+    fn set_routing(&self, routing: Routing) {
+        *self.routing.write().unwrap() = Arc::new(routing);
+    }
+
+    // copies every key to its shard with the new count and snapshots them
+    // there before recording the new layout. until then the old shards keep
+    // all their keys and serve the reads
+    fn copy_keys(
+        &self,
+        host: &dyn ShardHost,
+        job_queues: &mut Vec<JobQueue>,
+        old_shards: usize,
+        shards: usize,
+    ) -> Result<(), HorcruxError> {
+        for shard_id in old_shards..shards {
+            job_queues.push(host.start_shard(shard_id, shards)?);
+        }
+        self.set_routing(Routing {
+            job_queues: job_queues.clone(),
+            shards: old_shards,
+            resharding: true,
+        });
+
+        for shard_id in 0..old_shards {
+            let exported = match job_queues[shard_id]
+                .send_request(Request::Export { shard_id, shards })
+                .recv()
+            {
+                Ok(Response::Entries(exported)) => exported,
+                _ => return Err(HorcruxError::Internal),
+            };
+            for entries in exported.iter() {
+                let mut batches = vec![Vec::new(); shards];
+                for (key, value) in entries {
+                    batches[shard_for(&key, shards)].push((key, value));
+                }
+                for (target, entries) in batches.into_iter().enumerate() {
+                    if entries.is_empty() {
+                        continue;
+                    }
+                    match job_queues[target]
+                        .send_request(Request::Import { entries })
+                        .recv()
+                    {
+                        Ok(Response::Moved(_)) => {}
+                        Ok(Response::OutOfMemory) => return Err(HorcruxError::OutOfMemory),
+                        Ok(Response::LogFailed) => return Err(HorcruxError::LogFailed),
+                        _ => return Err(HorcruxError::Internal),
+                    }
+                }
+            }
+        }
+
+        snapshot_shards(&job_queues[..shards], true)?;
+        host.write_layout(shards)
+    }
+}
+
+// takes a snapshot of every shard at once and reports the shards that failed
+fn snapshot_shards(job_queues: &[JobQueue], wait: bool) -> Result<(), HorcruxError> {
+    // take snapshot for each shard parallelly
+    let receivers = job_queues
+        .iter()
+        .map(|job_queue| job_queue.send_request(Request::Snapshot { wait }))
+        .collect::<Vec<_>>();
+
+    // wait for all snapshots to finish
+    let mut failed = Vec::new();
+    for (shard_id, receiver) in receivers.iter().enumerate() {
+        match receiver.recv() {
+            Ok(Response::SnapshotFinished) | Ok(Response::SnapshotAccepted) => {}
+            _ => {
+                println!("Failed to take snapshot of shard {}", shard_id);
+                failed.push(shard_id.to_string());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(HorcruxError::Snapshot(format!(
+            "failed on shards {}",
+            failed.join(",")
+        )));
+    }
+    Ok(())
+}
+
+// stops the worker of a shard and deletes its snapshots
+fn retire_shard(host: &dyn ShardHost, job_queue: &JobQueue, shard_id: usize, shards: usize) {
+    match job_queue.send_request(Request::Stop).recv() {
+        Ok(Response::Stopped) => {}
+        _ => println!("Failed to stop shard {}", shard_id),
+    }
+    if let Err(err) = host.remove_shard(shard_id, shards) {
+        println!("Failed to remove snapshots of shard {}: {}", shard_id, err);
     }
 }
 
 impl Clone for ShardHandler {
     fn clone(&self) -> Self {
         ShardHandler {
-            job_queues: self.job_queues.clone(),
+            routing: self.routing.clone(),
+            host: self.host.clone(),
         }
     }
 }
 
 impl SetHandler for ShardHandler {
-    fn set(
-        &self,
-        key: String,
-        flags: u32,
-        _exptime: u32,
-        data: String,
-    ) -> Result<(), HorcruxError> {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        let shard_id = (hash as usize) % self.job_queues.len();
-        let value = Value { flags, data };
-        let result = self.job_queues[shard_id]
+    fn set(&self, key: String, flags: u32, exptime: u32, data: Bytes) -> Result<(), HorcruxError> {
+        let value = new_value(flags, exptime, data);
+        let result = self
+            .writable()?
+            .shard(&key)
             .send_request(Request::Set { key, value })
             .recv();
         match result {
             Ok(Response::Stored) => {}
+            Ok(Response::OutOfMemory) => return Err(HorcruxError::OutOfMemory),
+            Ok(Response::LogFailed) => return Err(HorcruxError::LogFailed),
             _ => return Err(HorcruxError::Internal),
         }
         Ok(())
     }
 }
 
+impl AddHandler for ShardHandler {
+    fn add(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+    ) -> Result<StoreResult, HorcruxError> {
+        let value = new_value(flags, exptime, data);
+        store_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Add { key, value })
+                .recv(),
+        )
+    }
+}
+
+impl ReplaceHandler for ShardHandler {
+    fn replace(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+    ) -> Result<StoreResult, HorcruxError> {
+        let value = new_value(flags, exptime, data);
+        store_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Replace { key, value })
+                .recv(),
+        )
+    }
+}
+
+impl AppendHandler for ShardHandler {
+    fn append(&self, key: String, data: Bytes) -> Result<StoreResult, HorcruxError> {
+        store_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Append { key, data })
+                .recv(),
+        )
+    }
+}
+
+impl PrependHandler for ShardHandler {
+    fn prepend(&self, key: String, data: Bytes) -> Result<StoreResult, HorcruxError> {
+        store_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Prepend { key, data })
+                .recv(),
+        )
+    }
+}
+
+impl CasHandler for ShardHandler {
+    fn cas(
+        &self,
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        cas: u64,
+    ) -> Result<StoreResult, HorcruxError> {
+        let routing = self.writable()?;
+        let shard_id = routing.shard_id(&key);
+        let value = new_value(flags, exptime, data);
+        let cas = routing.local_cas(shard_id, cas);
+        store_result(
+            routing.job_queues[shard_id]
+                .send_request(Request::Cas { key, value, cas })
+                .recv(),
+        )
+    }
+}
+
 impl GetHandler for ShardHandler {
     fn get(&self, key: &str) -> Option<Value> {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        let shard_id = (hash as usize) % self.job_queues.len();
-        let result = self.job_queues[shard_id]
+        let routing = self.routing();
+        let shard_id = routing.shard_id(key);
+        let result = routing.job_queues[shard_id]
             .send_request(Request::Get {
                 key: key.to_string(),
             })
             .recv();
 
         match result {
-            Ok(Response::Value(val)) => val,
+            Ok(Response::Value(Some(mut val))) => {
+                val.cas = routing.global_cas(shard_id, val.cas);
+                Some(val)
+            }
             _ => None,
         }
     }
+
+    fn get_many(&self, keys: Vec<String>) -> Vec<(String, Value)> {
+        self.routing()
+            .fan_out(keys, |keys| Request::GetMany { keys })
+            .unwrap_or_default()
+    }
+
+    // while resharding, a lease taken on a key already copied to its new
+    // shard is lost when the reshard completes
+    fn get_with_lease(
+        &self,
+        key: String,
+        opts: LeaseOptions,
+    ) -> Result<Option<(Value, Lease)>, HorcruxError> {
+        let routing = self.routing();
+        let shard_id = routing.shard_id(&key);
+        let opts = lease_options(opts);
+        match routing.job_queues[shard_id]
+            .send_request(Request::GetWithLease { key, opts })
+            .recv()
+        {
+            Ok(Response::Leased(Some((mut val, lease)))) => {
+                val.cas = routing.global_cas(shard_id, val.cas);
+                Ok(Some((val, lease)))
+            }
+            Ok(Response::Leased(None)) => Ok(None),
+            Ok(Response::LogFailed) => Err(HorcruxError::LogFailed),
+            _ => Err(HorcruxError::Internal),
+        }
+    }
+}
+
+impl DeleteHandler for ShardHandler {
+    fn delete(&self, key: String) -> Result<bool, HorcruxError> {
+        found_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Delete { key })
+                .recv(),
+        )
+    }
+
+    fn invalidate(&self, key: String, exptime: Option<u32>) -> Result<bool, HorcruxError> {
+        let exptime = exptime.map(deadline);
+        found_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Invalidate { key, exptime })
+                .recv(),
+        )
+    }
+}
+
+impl IncrHandler for ShardHandler {
+    fn incr(&self, key: String, delta: u64) -> Result<CounterResult, HorcruxError> {
+        counter_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Incr { key, delta })
+                .recv(),
+        )
+    }
+
+    fn decr(&self, key: String, delta: u64) -> Result<CounterResult, HorcruxError> {
+        counter_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Decr { key, delta })
+                .recv(),
+        )
+    }
+}
+
+impl TouchHandler for ShardHandler {
+    fn touch(&self, key: String, exptime: u32) -> Result<bool, HorcruxError> {
+        let exptime = deadline(exptime);
+        found_result(
+            self.writable()?
+                .shard(&key)
+                .send_request(Request::Touch { key, exptime })
+                .recv(),
+        )
+    }
+
+    fn get_and_touch(
+        &self,
+        keys: Vec<String>,
+        exptime: u32,
+    ) -> Result<Vec<(String, Value)>, HorcruxError> {
+        let exptime = deadline(exptime);
+        // held like writable() so that the touches are not lost by a reshard
+        let routing = self.routing.read().unwrap();
+        // the values are still served while resharding, without the touch
+        if routing.resharding {
+            return routing.fan_out(keys, |keys| Request::GetMany { keys });
+        }
+        routing.fan_out(keys, |keys| Request::GetAndTouch { keys, exptime })
+    }
+}
+
+impl MetaHandler for ShardHandler {
+    fn meta_set(
+        &self,
+        key: String,
+        mode: SetMode,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        cas: Option<u64>,
+    ) -> Result<(StoreResult, Option<Value>), HorcruxError> {
+        let routing = self.writable()?;
+        let shard_id = routing.shard_id(&key);
+        let value = new_value(flags, exptime, data);
+        let cas = cas.map(|cas| routing.local_cas(shard_id, cas));
+        let (res, value) = written(
+            routing.job_queues[shard_id]
+                .send_request(Request::MetaSet {
+                    key,
+                    value,
+                    mode,
+                    cas,
+                })
+                .recv(),
+            store_result,
+        )?;
+        let value = value.map(|mut val| {
+            val.cas = routing.global_cas(shard_id, val.cas);
+            val
+        });
+        Ok((res, value))
+    }
+
+    fn meta_arithmetic(
+        &self,
+        key: String,
+        op: Arithmetic,
+    ) -> Result<(CounterResult, Option<Value>), HorcruxError> {
+        let routing = self.writable()?;
+        let shard_id = routing.shard_id(&key);
+        let op = arithmetic(op);
+        let (res, value) = written(
+            routing.job_queues[shard_id]
+                .send_request(Request::MetaArithmetic { key, op })
+                .recv(),
+            counter_result,
+        )?;
+        let value = value.map(|mut val| {
+            val.cas = routing.global_cas(shard_id, val.cas);
+            val
+        });
+        Ok((res, value))
+    }
 }
 
 impl SnapshotHandler for ShardHandler {
     fn snapshot(&self, wait: bool) -> Result<(), HorcruxError> {
-        // take snapshot for each shard parallelly
-        let receivers = &self
+        snapshot_shards(&self.routing().job_queues, wait)
+    }
+
+    // the status of the least advanced shard
+    fn snapshot_status(&self) -> Result<SnapshotStatus, HorcruxError> {
+        let receivers = self
+            .routing()
             .job_queues
             .iter()
-            .map(|job_queue| job_queue.send_request(Request::Snapshot { wait: wait }))
+            .map(|job_queue| job_queue.send_request(Request::SnapshotStatus))
             .collect::<Vec<_>>();
 
-        // wait for all snapshots to finish
+        let mut merged: Option<SnapshotStatus> = None;
         for receiver in receivers {
-            let result = receiver.recv();
-            match result {
-                Ok(Response::SnapshotFinished) => {
-                    println!("Snapshot taken successfully");
-                }
-                Ok(Response::SnapshotAccepted) => {
-                    println!("Snapshot request accepted");
-                }
-                _ => {
-                    println!("Failed to take snapshot");
-                }
+            let status = match receiver.recv() {
+                Ok(Response::SnapshotStatus(status)) => status,
+                _ => return Err(HorcruxError::Internal),
+            };
+            merged = Some(match merged {
+                None => status,
+                Some(merged) => SnapshotStatus {
+                    pid: merged.pid.or(status.pid),
+                    started_at: merged.started_at.or(status.started_at),
+                    last_success: merged
+                        .last_success
+                        .zip(status.last_success)
+                        .map(|(a, b)| a.min(b)),
+                    last_duration: merged.last_duration.max(status.last_duration),
+                    last_status: match merged.last_status {
+                        Some(s) if s != "ok" => Some(s),
+                        _ => status.last_status,
+                    },
+                    changes: merged.changes + status.changes,
+                },
+            });
+        }
+        Ok(merged.unwrap_or_default())
+    }
+}
+
+impl StatsHandler for ShardHandler {
+    // the totals of every shard
+    fn memory_stats(&self) -> Result<MemoryStats, HorcruxError> {
+        let receivers = self
+            .routing()
+            .job_queues
+            .iter()
+            .map(|job_queue| job_queue.send_request(Request::MemoryStats))
+            .collect::<Vec<_>>();
+
+        let mut total = MemoryStats {
+            limit: Some(0),
+            ..Default::default()
+        };
+        for receiver in receivers {
+            let stats = match receiver.recv() {
+                Ok(Response::MemoryStats(stats)) => stats,
+                _ => return Err(HorcruxError::Internal),
+            };
+            total.items += stats.items;
+            total.used += stats.used;
+            total.limit = total.limit.zip(stats.limit).map(|(a, b)| a + b);
+            total.evictions += stats.evictions;
+        }
+        Ok(total)
+    }
+}
+
+impl ReshardHandler for ShardHandler {
+    // a crash leaves snapshots restorable with the old layout until the new
+    // one is recorded, and keys are only dropped from the shards they left
+    // after that. keys a shard no longer owns are also dropped at startup
+    fn reshard(&self, shards: usize) -> Result<(), HorcruxError> {
+        let host = match self.host.as_ref() {
+            Some(host) => host.as_ref(),
+            None => return Err(HorcruxError::Reshard("is not enabled".to_string())),
+        };
+        // a single shard keeps its snapshots under another path
+        if shards < 2 {
+            return Err(HorcruxError::Reshard("needs at least 2 shards".to_string()));
+        }
+        let old = {
+            let mut routing = self.routing.write().unwrap();
+            if routing.resharding {
+                return Err(HorcruxError::Reshard("is already running".to_string()));
+            }
+            let old = routing.clone();
+            *routing = Arc::new(Routing {
+                job_queues: old.job_queues.clone(),
+                shards: old.shards,
+                resharding: true,
+            });
+            old
+        };
+        let old_shards = old.shards;
+        println!("Resharding from {} to {} shards", old_shards, shards);
+
+        let mut job_queues = old.job_queues.clone();
+        if let Err(err) = self.copy_keys(host, &mut job_queues, old_shards, shards) {
+            // back to the old layout, without the copies made so far
+            self.set_routing(Routing {
+                job_queues: old.job_queues.clone(),
+                shards: old_shards,
+                resharding: false,
+            });
+            for (shard_id, job_queue) in job_queues.iter().enumerate().skip(old_shards) {
+                retire_shard(host, job_queue, shard_id, shards);
             }
+            for (shard_id, job_queue) in old.job_queues.iter().enumerate() {
+                let _ = job_queue
+                    .send_request(Request::DropForeign {
+                        shard_id,
+                        shards: old_shards,
+                    })
+                    .recv();
+            }
+            println!("Failed to reshard: {}", err);
+            return Err(err);
+        }
+
+        self.set_routing(Routing {
+            job_queues: job_queues[..shards].to_vec(),
+            shards,
+            resharding: false,
+        });
+        for (shard_id, job_queue) in job_queues.iter().enumerate().take(old_shards.min(shards)) {
+            let _ = job_queue
+                .send_request(Request::DropForeign { shard_id, shards })
+                .recv();
         }
+        for (shard_id, job_queue) in job_queues.iter().enumerate().skip(shards) {
+            retire_shard(host, job_queue, shard_id, old_shards);
+        }
+        println!("Resharded from {} to {} shards", old_shards, shards);
         Ok(())
     }
 }
 
 impl Handler for ShardHandler {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::Worker;
+    use db::db::DB;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn test_shard_handler_cas_unique() {
+        let job_queues = vec![JobQueue::new(), JobQueue::new()];
+        for job_queue in job_queues.iter() {
+            let mut worker = Worker::new(job_queue.clone(), DB::new("/tmp".to_string()), vec![]);
+            thread::spawn(move || {
+                worker.run();
+            });
+        }
+        let handler = ShardHandler::new(job_queues);
+
+        let keys = (0..10).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        for key in keys.iter() {
+            handler
+                .set(key.clone(), 0, 0, Bytes::from("value"))
+                .unwrap();
+        }
+        let tokens = keys
+            .iter()
+            .map(|key| handler.get(key).unwrap().cas)
+            .collect::<HashSet<_>>();
+        assert_eq!(tokens.len(), keys.len());
+
+        let hits = handler.get_many(vec![
+            "key3".to_string(),
+            "missing".to_string(),
+            "key1".to_string(),
+            "key2".to_string(),
+        ]);
+        let hit_keys = hits.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        assert_eq!(hit_keys, vec!["key3", "key1", "key2"]);
+        assert!(hits
+            .iter()
+            .all(|(key, val)| handler.get(key).unwrap().cas == val.cas));
+
+        let cas = handler.get("key0").unwrap().cas;
+        let res = handler.cas("key0".to_string(), 0, 0, Bytes::from("new"), cas + 1);
+        assert_eq!(res.unwrap(), StoreResult::Exists);
+        let res = handler.cas("key0".to_string(), 0, 0, Bytes::from("new"), cas);
+        assert_eq!(res.unwrap(), StoreResult::Stored);
+
+        // a shard that does not answer with values fails the whole batch
+        let res = handler.routing().fan_out(keys.clone(), |keys| {
+            if keys.contains(&"key0".to_string()) {
+                Request::MemoryStats
+            } else {
+                Request::GetMany { keys }
+            }
+        });
+        assert!(matches!(res, Err(HorcruxError::Internal)));
+    }
+
+    #[test]
+    fn test_cas_overflow() {
+        let routing = Routing {
+            job_queues: vec![JobQueue::new(), JobQueue::new(), JobQueue::new()],
+            shards: 3,
+            resharding: false,
+        };
+        for shard_id in 0..3 {
+            for cas in [1, 1000, u64::MAX / 3 - 1] {
+                let token = routing.global_cas(shard_id, cas);
+                assert_eq!(routing.local_cas(shard_id, token), cas);
+                assert_eq!(routing.local_cas((shard_id + 1) % 3, token), 0);
+            }
+        }
+
+        // the largest token that still fits
+        assert_eq!(routing.global_cas(0, u64::MAX / 3), u64::MAX);
+        assert_eq!(routing.local_cas(0, u64::MAX), u64::MAX / 3);
+        // larger ones are reported as 0, which no shard issues
+        assert_eq!(routing.global_cas(1, u64::MAX / 3), 0);
+        assert_eq!(routing.global_cas(2, u64::MAX / 2), 0);
+        assert_eq!(routing.global_cas(0, u64::MAX), 0);
+        assert!((0..3).all(|shard_id| routing.local_cas(shard_id, 0) == 0));
+    }
+
+    #[test]
+    fn test_shard_handler_snapshot_failure() {
+        let dir = "/tmp/test_shard_handler_snapshot_failure";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        // the second shard cannot write to a missing directory
+        let paths = [
+            format!("{}/snapshot", dir),
+            format!("{}/missing/snapshot", dir),
+        ];
+        let job_queues = paths
+            .iter()
+            .map(|path| {
+                let job_queue = JobQueue::new();
+                let mut worker = Worker::new(job_queue.clone(), DB::new(path.clone()), vec![]);
+                thread::spawn(move || {
+                    worker.run();
+                });
+                job_queue
+            })
+            .collect::<Vec<_>>();
+        let handler = ShardHandler::new(job_queues);
+
+        match handler.snapshot(true) {
+            Err(HorcruxError::Snapshot(msg)) => assert_eq!(msg, "failed on shards 1"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // starts shards in a directory, like the server does next to its snapshots
+    struct TestHost {
+        path: String,
+        // memory limit of the shards a reshard starts
+        limit: Option<usize>,
+    }
+
+    impl TestHost {
+        fn start(&self, shard_id: usize, shards: usize, limit: Option<usize>) -> JobQueue {
+            let job_queue = JobQueue::new();
+            let path = db::shard::shard_path(&self.path, shard_id, shards);
+            let mut db = DB::new(path);
+            db.set_memory_limit(limit, true);
+            let mut worker = Worker::new(job_queue.clone(), db, vec![]);
+            thread::spawn(move || {
+                worker.run();
+            });
+            job_queue
+        }
+    }
+
+    impl ShardHost for TestHost {
+        fn start_shard(&self, shard_id: usize, shards: usize) -> Result<JobQueue, HorcruxError> {
+            Ok(self.start(shard_id, shards, self.limit))
+        }
+
+        fn write_layout(&self, shards: usize) -> Result<(), HorcruxError> {
+            let layout = db::shard::ShardLayout {
+                shards,
+                hash: db::shard::SHARD_HASH.to_string(),
+            };
+            let store = db::store::LocalStore::for_snapshot(&self.path);
+            db::shard::write_layout(&store, "snapshot", &layout).map_err(|_| HorcruxError::Internal)
+        }
+
+        fn remove_shard(&self, shard_id: usize, shards: usize) -> Result<(), HorcruxError> {
+            let path = db::shard::shard_path(&self.path, shard_id, shards);
+            DB::new(path).discard().map_err(|_| HorcruxError::Internal)
+        }
+    }
+
+    #[test]
+    fn test_shard_handler_reshard() {
+        let dir = "/tmp/test_shard_handler_reshard";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let host = TestHost {
+            path: format!("{}/snapshot", dir),
+            limit: None,
+        };
+        let job_queues = (0..2)
+            .map(|shard_id| host.start(shard_id, 2, None))
+            .collect();
+        let mut handler = ShardHandler::new(job_queues);
+        assert!(handler.reshard(3).is_err());
+        handler.set_host(Arc::new(host));
+        assert!(handler.reshard(1).is_err());
+
+        // enough keys for a shard to export them in several batches
+        let keys = (0..5000).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        for key in keys.iter() {
+            handler
+                .set(key.clone(), 0, 0, Bytes::from(key.clone()))
+                .unwrap();
+        }
+
+        // a write accepted while a reshard starts must not be lost
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let handler = handler.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut stored = Vec::new();
+                let mut i = 0;
+                while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                    let key = format!("concurrent{}", i);
+                    if handler.set(key.clone(), 0, 0, Bytes::from("v")).is_ok() {
+                        stored.push(key);
+                    }
+                    i += 1;
+                }
+                stored
+            })
+        };
+
+        let store = db::store::LocalStore::new(dir);
+        for shards in [3, 2] {
+            handler.reshard(shards).unwrap();
+            let layout = db::shard::read_layout(&store, "snapshot").unwrap().unwrap();
+            assert_eq!(layout.shards, shards);
+            let routing = handler.routing();
+            assert_eq!(routing.job_queues.len(), shards);
+            for key in keys.iter() {
+                assert_eq!(handler.get(key).unwrap().data, Bytes::from(key.clone()));
+            }
+            // the shards the keys left no longer keep them
+            for (shard_id, job_queue) in routing.job_queues.iter().enumerate() {
+                match job_queue
+                    .send_request(Request::Export { shard_id, shards })
+                    .recv()
+                {
+                    Ok(Response::Entries(exported)) => assert!(exported.recv().is_err()),
+                    _ => panic!("Unexpected response"),
+                }
+            }
+        }
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        for key in writer.join().unwrap() {
+            assert!(handler.get(&key).is_some(), "{} was lost", key);
+        }
+        handler
+            .set("new".to_string(), 0, 0, Bytes::from("value"))
+            .unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_shard_handler_reshard_out_of_memory() {
+        let dir = "/tmp/test_shard_handler_reshard_out_of_memory";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        // the new shard cannot hold a single key
+        let host = TestHost {
+            path: format!("{}/snapshot", dir),
+            limit: Some(1),
+        };
+        let job_queues = (0..2)
+            .map(|shard_id| host.start(shard_id, 2, None))
+            .collect();
+        let mut handler = ShardHandler::new(job_queues);
+        handler.set_host(Arc::new(host));
+        let keys = (0..100).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        for key in keys.iter() {
+            handler
+                .set(key.clone(), 0, 0, Bytes::from(key.clone()))
+                .unwrap();
+        }
+
+        // the reshard is rolled back instead of going over the limit
+        assert!(matches!(handler.reshard(3), Err(HorcruxError::OutOfMemory)));
+        let store = db::store::LocalStore::new(dir);
+        assert!(db::shard::read_layout(&store, "snapshot")
+            .unwrap()
+            .is_none());
+        assert_eq!(handler.routing().job_queues.len(), 2);
+        for key in keys.iter() {
+            assert_eq!(handler.get(key).unwrap().data, Bytes::from(key.clone()));
+        }
+        handler
+            .set("new".to_string(), 0, 0, Bytes::from("value"))
+            .unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
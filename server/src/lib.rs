@@ -1,4 +1,7 @@
+pub mod binary;
 pub mod handler;
 pub mod memcache;
+pub mod meta;
 pub mod server;
+pub mod supervisor;
 pub mod worker;
@@ -1,155 +1,840 @@
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::Decoder;
 
-use db::db::Value;
+use super::handler::Handler;
+use super::meta;
+use super::supervisor::SnapshotStatus;
+use db::db::{check_key, CounterResult, StoreResult, Value};
+use db::memory::MemoryStats;
 use types::types::HorcruxError;
 
 pub enum Request {
     Set {
         key: String,
         flags: u32,
-        _exptime: u32,
-        data: String,
+        exptime: u32,
+        data: Bytes,
+        noreply: bool,
+    },
+    Add {
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        noreply: bool,
+    },
+    Replace {
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        noreply: bool,
+    },
+    Append {
+        key: String,
+        data: Bytes,
+        noreply: bool,
+    },
+    Prepend {
+        key: String,
+        data: Bytes,
+        noreply: bool,
+    },
+    Cas {
+        key: String,
+        flags: u32,
+        exptime: u32,
+        data: Bytes,
+        cas: u64,
+        noreply: bool,
     },
     Get {
+        keys: Vec<String>,
+    },
+    Gets {
+        keys: Vec<String>,
+    },
+    Gat {
+        exptime: u32,
+        keys: Vec<String>,
+    },
+    Gats {
+        exptime: u32,
+        keys: Vec<String>,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Incr {
         key: String,
+        delta: u64,
+        noreply: bool,
     },
+    Decr {
+        key: String,
+        delta: u64,
+        noreply: bool,
+    },
+    Touch {
+        key: String,
+        exptime: u32,
+        noreply: bool,
+    },
+    Meta(meta::Request),
     Snapshot,
+    SnapshotStatus,
+    LastSave,
+    Stats,
+    Reshard {
+        shards: usize,
+    },
 }
 
-pub async fn read_request<R>(reader: &mut R) -> Result<Request, HorcruxError>
-where
-    R: AsyncRead + Unpin,
-{
-    // read request line
-    let mut buf = vec![0; 4096];
-    let request: String;
-    match reader.read(&mut buf).await {
-        Ok(n) if n == 0 => return Err(HorcruxError::ParseRequest("Empty request".to_string())),
-        Ok(n) => {
-            request = String::from_utf8_lossy(&buf[..n]).to_string();
+// longest request line accepted before the connection is dropped
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+enum State {
+    Line,
+    // waiting for a data block of len bytes and its trailing \r\n
+    Data { request: Request, len: usize },
+    // discarding a data block that is too large to store
+    Skip { remaining: usize },
+}
+
+// stateful decoder for the text protocol, a single read may carry many
+// pipelined requests or only part of one
+pub struct MemcacheCodec {
+    max_item_size: usize,
+    state: State,
+}
+
+impl MemcacheCodec {
+    pub fn new(max_item_size: usize) -> Self {
+        MemcacheCodec {
+            max_item_size,
+            state: State::Line,
         }
+    }
+}
+
+impl Decoder for MemcacheCodec {
+    // protocol errors are yielded as items so the connection stays usable
+    type Item = Result<Request, HorcruxError>;
+    type Error = HorcruxError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            match std::mem::replace(&mut self.state, State::Line) {
+                State::Line => {
+                    let pos = match src.iter().position(|&b| b == b'\n') {
+                        Some(pos) => pos,
+                        None if src.len() > MAX_LINE_LEN => {
+                            return Err(HorcruxError::Connection(
+                                "Request line too long".to_string(),
+                            ));
+                        }
+                        None => return Ok(None),
+                    };
+                    let line = src.split_to(pos + 1);
+                    match parse_request(&line) {
+                        Ok((request, None)) => return Ok(Some(Ok(request))),
+                        Ok((_, Some(len))) if len > self.max_item_size => {
+                            self.state = State::Skip {
+                                remaining: len.saturating_add(2),
+                            };
+                            return Ok(Some(Err(HorcruxError::TooLarge)));
+                        }
+                        Ok((request, Some(len))) => self.state = State::Data { request, len },
+                        Err(err) => {
+                            // the data block of an invalid storage command
+                            // is skipped rather than read as commands
+                            if let Some(len) = data_len(&line) {
+                                self.state = State::Skip {
+                                    remaining: len.saturating_add(2),
+                                };
+                            }
+                            return Ok(Some(Err(err)));
+                        }
+                    }
+                }
+                State::Data { request, len } => {
+                    if src.len() < len + 2 {
+                        src.reserve(len + 2 - src.len());
+                        self.state = State::Data { request, len };
+                        return Ok(None);
+                    }
+                    let data = src.split_to(len).freeze();
+                    if &src.split_to(2)[..] != b"\r\n" {
+                        return Ok(Some(Err(HorcruxError::BadDataChunk)));
+                    }
+                    return Ok(Some(Ok(request.with_data(data))));
+                }
+                State::Skip { remaining } => {
+                    let n = remaining.min(src.len());
+                    src.advance(n);
+                    if n < remaining {
+                        self.state = State::Skip {
+                            remaining: remaining - n,
+                        };
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Request {
+    // fills in the data block that followed a storage request line
+    fn with_data(self, data: Bytes) -> Request {
+        match self {
+            Request::Set {
+                key,
+                flags,
+                exptime,
+                noreply,
+                ..
+            } => Request::Set {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            },
+            Request::Add {
+                key,
+                flags,
+                exptime,
+                noreply,
+                ..
+            } => Request::Add {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            },
+            Request::Replace {
+                key,
+                flags,
+                exptime,
+                noreply,
+                ..
+            } => Request::Replace {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            },
+            Request::Append { key, noreply, .. } => Request::Append { key, data, noreply },
+            Request::Prepend { key, noreply, .. } => Request::Prepend { key, data, noreply },
+            Request::Cas {
+                key,
+                flags,
+                exptime,
+                cas,
+                noreply,
+                ..
+            } => Request::Cas {
+                key,
+                flags,
+                exptime,
+                data,
+                cas,
+                noreply,
+            },
+            Request::Meta(mut req) => {
+                req.data = data;
+                Request::Meta(req)
+            }
+            request => request,
+        }
+    }
+}
+
+// the length of the data block that follows a storage command line, read
+// on its own so that it is known even when the rest of the line is invalid
+fn data_len(line: &[u8]) -> Option<usize> {
+    let parts: Vec<&[u8]> = line
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect();
+    let len = match parts.first()?.to_ascii_lowercase().as_slice() {
+        b"set" | b"add" | b"replace" | b"append" | b"prepend" | b"cas" => parts.get(4)?,
+        b"ms" => parts.get(2)?,
+        _ => return None,
+    };
+    std::str::from_utf8(len).ok()?.parse().ok()
+}
+
+// parses a request line, returning the length of the data block that
+// follows it for storage commands
+fn parse_request(line: &[u8]) -> Result<(Request, Option<usize>), HorcruxError> {
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line,
         Err(_) => {
-            println!("Failed to read from socket");
-            return Err(HorcruxError::Connection(
-                "Failed to read from socket".to_string(),
+            return Err(HorcruxError::ParseRequest(
+                "invalid request line".to_string(),
             ));
         }
-    }
+    };
 
     // parse request
-    let parts: Vec<&str> = request.trim().split_whitespace().collect();
+    let parts: Vec<&str> = line.split_whitespace().collect();
     if parts.is_empty() {
         return Err(HorcruxError::Ignorable);
     }
     let command = parts[0].to_lowercase();
 
     match command.as_str() {
-        "set" => {
-            // validate request
-            if parts.len() != 5 && parts.len() != 6 {
-                return Err(HorcruxError::ParseRequest("Invalid request".to_string()));
-            }
+        "set" | "add" | "replace" | "append" | "prepend" => {
+            let noreply = parse_noreply(&parts, 5)?;
+            let (key, flags, exptime, len) = parse_storage_line(&parts)?;
+            let data = Bytes::new();
 
-            let flags = match parts[2].parse::<u32>() {
-                Ok(flags) => flags,
-                Err(_) => {
-                    return Err(HorcruxError::ParseRequest("Invalid flags".to_string()));
-                }
+            let request = match command.as_str() {
+                "set" => Request::Set {
+                    key,
+                    flags,
+                    exptime,
+                    data,
+                    noreply,
+                },
+                "add" => Request::Add {
+                    key,
+                    flags,
+                    exptime,
+                    data,
+                    noreply,
+                },
+                "replace" => Request::Replace {
+                    key,
+                    flags,
+                    exptime,
+                    data,
+                    noreply,
+                },
+                "append" => Request::Append { key, data, noreply },
+                _ => Request::Prepend { key, data, noreply },
             };
-
-            // validate exptime
-            let exptime = match parts[3].parse::<u32>() {
-                Ok(exptime) => exptime,
+            Ok((request, Some(len)))
+        }
+        "cas" => {
+            let noreply = parse_noreply(&parts, 6)?;
+            let (key, flags, exptime, len) = parse_storage_line(&parts)?;
+            let cas = match parts[5].parse::<u64>() {
+                Ok(cas) => cas,
                 Err(_) => {
-                    return Err(HorcruxError::ParseRequest("Invalid exptime".to_string()));
+                    return Err(HorcruxError::ParseRequest("invalid cas unique".to_string()));
                 }
             };
+            let request = Request::Cas {
+                key,
+                flags,
+                exptime,
+                data: Bytes::new(),
+                cas,
+                noreply,
+            };
+            Ok((request, Some(len)))
+        }
+        "get" | "gets" => {
+            // validate request
+            if parts.len() < 2 {
+                return Err(HorcruxError::ParseRequest(
+                    "bad command line format".to_string(),
+                ));
+            }
 
-            // read data
-            let len = match parts[4].parse::<usize>() {
-                Ok(len) => len,
+            let keys = parse_keys(&parts[1..])?;
+            if command == "get" {
+                Ok((Request::Get { keys }, None))
+            } else {
+                Ok((Request::Gets { keys }, None))
+            }
+        }
+        "gat" | "gats" => {
+            // validate request
+            if parts.len() < 3 {
+                return Err(HorcruxError::ParseRequest(
+                    "bad command line format".to_string(),
+                ));
+            }
+
+            let exptime = parse_exptime(parts[1])?;
+            let keys = parse_keys(&parts[2..])?;
+            if command == "gat" {
+                Ok((Request::Gat { exptime, keys }, None))
+            } else {
+                Ok((Request::Gats { exptime, keys }, None))
+            }
+        }
+        "delete" => {
+            let noreply = parse_noreply(&parts, 2)?;
+            let key = parse_key(parts[1].as_bytes())?;
+            Ok((Request::Delete { key, noreply }, None))
+        }
+        "incr" | "decr" => {
+            let noreply = parse_noreply(&parts, 3)?;
+            let key = parse_key(parts[1].as_bytes())?;
+            let delta = match parts[2].parse::<u64>() {
+                Ok(delta) => delta,
                 Err(_) => {
                     return Err(HorcruxError::ParseRequest(
-                        "Invalid data length".to_string(),
+                        "invalid numeric delta argument".to_string(),
                     ));
                 }
             };
-            let data: String;
-            if parts.len() == 6 {
-                data = parts[5].to_string();
+            if command == "incr" {
+                Ok((
+                    Request::Incr {
+                        key,
+                        delta,
+                        noreply,
+                    },
+                    None,
+                ))
             } else {
-                let mut buf = vec![0; len];
-                match reader.read(&mut buf).await {
-                    Ok(n) if n != len => {
-                        return Err(HorcruxError::Connection("Failed to read data".to_string()));
-                    }
-                    Ok(_) => {
-                        data = String::from_utf8_lossy(&buf).to_string();
-                    }
-                    Err(_) => {
-                        return Err(HorcruxError::Connection("Failed to read data".to_string()));
-                    }
-                }
+                Ok((
+                    Request::Decr {
+                        key,
+                        delta,
+                        noreply,
+                    },
+                    None,
+                ))
             }
-
-            Ok(Request::Set {
-                key: parts[1].to_string(),
-                flags,
-                _exptime: exptime,
-                data,
-            })
         }
-        "get" => {
-            // validate request
-            if parts.len() != 2 {
-                return Err(HorcruxError::ParseRequest("Invalid request".to_string()));
-            }
-
-            let key = parts[1].to_string();
-            Ok(Request::Get { key })
+        "touch" => {
+            let noreply = parse_noreply(&parts, 3)?;
+            let key = parse_key(parts[1].as_bytes())?;
+            let exptime = parse_exptime(parts[2])?;
+            Ok((
+                Request::Touch {
+                    key,
+                    exptime,
+                    noreply,
+                },
+                None,
+            ))
+        }
+        "mg" | "md" | "ma" | "mn" => Ok((Request::Meta(meta::parse_request(&parts)?), None)),
+        "ms" => {
+            let req = meta::parse_request(&parts)?;
+            let len = req.data_len;
+            Ok((Request::Meta(req), Some(len)))
         }
-        "snapshot" => Ok(Request::Snapshot),
-        "quit" => {
-            return Err(HorcruxError::Connection("Client quit".to_string()));
+        "snapshot" => match parts.get(1) {
+            None => Ok((Request::Snapshot, None)),
+            Some(&"status") if parts.len() == 2 => Ok((Request::SnapshotStatus, None)),
+            Some(_) => Err(HorcruxError::ParseRequest(
+                "bad command line format".to_string(),
+            )),
+        },
+        "lastsave" => Ok((Request::LastSave, None)),
+        "stats" if parts.len() == 1 => Ok((Request::Stats, None)),
+        "reshard" => match parts.as_slice() {
+            [_, shards] => match shards.parse::<usize>() {
+                Ok(shards) => Ok((Request::Reshard { shards }, None)),
+                Err(_) => Err(HorcruxError::ParseRequest(
+                    "invalid number of shards".to_string(),
+                )),
+            },
+            _ => Err(HorcruxError::ParseRequest(
+                "bad command line format".to_string(),
+            )),
+        },
+        "quit" => Err(HorcruxError::Connection("Client quit".to_string())),
+        _ => Err(HorcruxError::UnknownCommand),
+    }
+}
+
+// mutating commands take <len> arguments, optionally followed by noreply
+fn parse_noreply(parts: &[&str], len: usize) -> Result<bool, HorcruxError> {
+    if parts.len() == len {
+        return Ok(false);
+    }
+    if parts.len() == len + 1 && parts[len] == "noreply" {
+        return Ok(true);
+    }
+    Err(HorcruxError::ParseRequest(
+        "bad command line format".to_string(),
+    ))
+}
+
+// format: <command> <key> <flags> <exptime> <bytes> ...
+fn parse_storage_line(parts: &[&str]) -> Result<(String, u32, u32, usize), HorcruxError> {
+    let flags = match parts[2].parse::<u32>() {
+        Ok(flags) => flags,
+        Err(_) => {
+            return Err(HorcruxError::ParseRequest("invalid flags".to_string()));
         }
-        _ => {
-            return Err(HorcruxError::ParseRequest("Invalid command".to_string()));
+    };
+
+    let exptime = parse_exptime(parts[3])?;
+
+    let len = match parts[4].parse::<usize>() {
+        Ok(len) => len,
+        Err(_) => {
+            return Err(HorcruxError::ParseRequest(
+                "invalid data length".to_string(),
+            ));
         }
+    };
+
+    Ok((parse_key(parts[1].as_bytes())?, flags, exptime, len))
+}
+
+fn parse_exptime(part: &str) -> Result<u32, HorcruxError> {
+    match part.parse::<u32>() {
+        Ok(exptime) => Ok(exptime),
+        Err(_) => Err(HorcruxError::ParseRequest("invalid exptime".to_string())),
     }
 }
 
+pub fn parse_key(key: &[u8]) -> Result<String, HorcruxError> {
+    check_key(key).map_err(|msg| HorcruxError::ParseRequest(msg.to_string()))?;
+    match String::from_utf8(key.to_vec()) {
+        Ok(key) => Ok(key),
+        Err(_) => Err(HorcruxError::ParseRequest("invalid key".to_string())),
+    }
+}
+
+fn parse_keys(parts: &[&str]) -> Result<Vec<String>, HorcruxError> {
+    parts.iter().map(|key| parse_key(key.as_bytes())).collect()
+}
+
 pub enum Response {
     Stored,
-    Value(String, Option<Value>),
+    NotStored,
+    Exists,
+    NotFound,
+    Values(Vec<(String, Value)>),
+    ValuesWithCas(Vec<(String, Value)>),
+    Deleted,
+    Touched,
+    Number(u64),
+    NonNumeric,
+    Meta(meta::Response),
     Error,
+    ClientError(String),
+    ServerError(String),
     SnapshotFinished,
+    SnapshotStatus(SnapshotStatus),
+    Stats(MemoryStats),
+    Ok,
 }
 
 impl Response {
+    fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Response::Error
+                | Response::ClientError(_)
+                | Response::ServerError(_)
+                | Response::NonNumeric
+        )
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         match self {
             Response::Stored => "STORED\r\n".as_bytes().to_vec(),
-            Response::Value(key, response) => {
-                if let Some(value) = response {
-                    format!(
-                        "VALUE {} {} {}\r\n{}\r\nEND\r\n",
-                        key,
-                        value.flags,
-                        value.data.len(),
-                        value.data
-                    )
+            Response::NotStored => "NOT_STORED\r\n".as_bytes().to_vec(),
+            Response::Exists => "EXISTS\r\n".as_bytes().to_vec(),
+            Response::NotFound => "NOT_FOUND\r\n".as_bytes().to_vec(),
+            Response::Values(hits) => encode_values(hits, false),
+            Response::ValuesWithCas(hits) => encode_values(hits, true),
+            Response::Deleted => "DELETED\r\n".as_bytes().to_vec(),
+            Response::Touched => "TOUCHED\r\n".as_bytes().to_vec(),
+            Response::Number(n) => format!("{}\r\n", n).as_bytes().to_vec(),
+            Response::NonNumeric => {
+                "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
                     .as_bytes()
                     .to_vec()
-                } else {
-                    "END\r\n".as_bytes().to_vec()
-                }
             }
+            Response::Meta(response) => response.as_bytes(),
             Response::Error => "ERROR\r\n".as_bytes().to_vec(),
+            Response::ClientError(msg) => format!("CLIENT_ERROR {}\r\n", msg).as_bytes().to_vec(),
+            Response::ServerError(msg) => format!("SERVER_ERROR {}\r\n", msg).as_bytes().to_vec(),
             Response::SnapshotFinished => "SNAPSHOT FINISHED\r\n".as_bytes().to_vec(),
+            Response::SnapshotStatus(status) => encode_snapshot_status(status),
+            Response::Stats(stats) => encode_stats(stats),
+            Response::Ok => "OK\r\n".as_bytes().to_vec(),
+        }
+    }
+}
+
+impl From<HorcruxError> for Response {
+    fn from(err: HorcruxError) -> Self {
+        match err {
+            HorcruxError::ParseRequest(msg) => Response::ClientError(msg),
+            HorcruxError::BadDataChunk => Response::ClientError("bad data chunk".to_string()),
+            HorcruxError::UnknownCommand => Response::Error,
+            HorcruxError::TooLarge => {
+                Response::ServerError("object too large for cache".to_string())
+            }
+            HorcruxError::OutOfMemory => Response::ServerError("out of memory".to_string()),
+            HorcruxError::LogFailed => {
+                Response::ServerError("failed to write to append-only log".to_string())
+            }
+            HorcruxError::RestoreDB(msg) | HorcruxError::Connection(msg) => {
+                Response::ServerError(msg)
+            }
+            HorcruxError::Snapshot(msg) => Response::ServerError(format!("snapshot {}", msg)),
+            HorcruxError::Reshard(msg) => Response::ServerError(format!("reshard {}", msg)),
+            HorcruxError::Resharding => Response::ServerError("resharding in progress".to_string()),
+            HorcruxError::Ignorable | HorcruxError::Internal => {
+                Response::ServerError("internal error".to_string())
+            }
         }
     }
 }
 
+// format: STAT <name> <value>\r\n ... END\r\n, times are unix seconds and 0
+// means never
+fn encode_snapshot_status(status: &SnapshotStatus) -> Vec<u8> {
+    let stats = [
+        (
+            "in_progress",
+            (status.started_at.is_some() as u8).to_string(),
+        ),
+        ("pid", status.pid.unwrap_or(0).to_string()),
+        ("started_at", status.started_at.unwrap_or(0).to_string()),
+        ("last_success", status.last_success.unwrap_or(0).to_string()),
+        (
+            "last_duration_ms",
+            status
+                .last_duration
+                .map_or(0, |duration| duration.as_millis())
+                .to_string(),
+        ),
+        (
+            "last_status",
+            status.last_status.clone().unwrap_or("none".to_string()),
+        ),
+        ("changes_since_last_save", status.changes.to_string()),
+    ];
+    let mut buf = Vec::new();
+    for (name, value) in stats {
+        buf.extend_from_slice(format!("STAT {} {}\r\n", name, value).as_bytes());
+    }
+    buf.extend_from_slice(b"END\r\n");
+    buf
+}
+
+// the memory stats of memcached, limit_maxbytes is 0 without a limit
+fn encode_stats(stats: &MemoryStats) -> Vec<u8> {
+    let stats = [
+        ("curr_items", stats.items.to_string()),
+        ("bytes", stats.used.to_string()),
+        ("limit_maxbytes", stats.limit.unwrap_or(0).to_string()),
+        ("evictions", stats.evictions.to_string()),
+    ];
+    let mut buf = Vec::new();
+    for (name, value) in stats {
+        buf.extend_from_slice(format!("STAT {} {}\r\n", name, value).as_bytes());
+    }
+    buf.extend_from_slice(b"END\r\n");
+    buf
+}
+
+// format: VALUE <key> <flags> <bytes> [<cas>]\r\n<data>\r\n ... END\r\n
+fn encode_values(hits: &[(String, Value)], with_cas: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (key, value) in hits {
+        if with_cas {
+            buf.extend_from_slice(
+                format!(
+                    "VALUE {} {} {} {}\r\n",
+                    key,
+                    value.flags,
+                    value.data.len(),
+                    value.cas
+                )
+                .as_bytes(),
+            );
+        } else {
+            buf.extend_from_slice(
+                format!("VALUE {} {} {}\r\n", key, value.flags, value.data.len()).as_bytes(),
+            );
+        }
+        buf.extend_from_slice(&value.data);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf.extend_from_slice(b"END\r\n");
+    buf
+}
+
+// runs a request against the handler, returning None when no reply is due
+pub fn handle<T: Handler>(handler: &T, req: Request) -> Option<Response> {
+    let (res, noreply) = match req {
+        Request::Set {
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        } => {
+            let res = match handler.set(key, flags, exptime, data) {
+                Ok(_) => Response::Stored,
+                Err(err) => Response::from(err),
+            };
+            (res, noreply)
+        }
+        Request::Add {
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        } => (
+            store_response(handler.add(key, flags, exptime, data)),
+            noreply,
+        ),
+        Request::Replace {
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        } => (
+            store_response(handler.replace(key, flags, exptime, data)),
+            noreply,
+        ),
+        Request::Append { key, data, noreply } => {
+            (store_response(handler.append(key, data)), noreply)
+        }
+        Request::Prepend { key, data, noreply } => {
+            (store_response(handler.prepend(key, data)), noreply)
+        }
+        Request::Cas {
+            key,
+            flags,
+            exptime,
+            data,
+            cas,
+            noreply,
+        } => (
+            store_response(handler.cas(key, flags, exptime, data, cas)),
+            noreply,
+        ),
+        Request::Get { keys } => (Response::Values(handler.get_many(keys)), false),
+        Request::Gets { keys } => (Response::ValuesWithCas(handler.get_many(keys)), false),
+        Request::Gat { exptime, keys } => {
+            let res = match handler.get_and_touch(keys, exptime) {
+                Ok(hits) => Response::Values(hits),
+                Err(err) => Response::from(err),
+            };
+            (res, false)
+        }
+        Request::Gats { exptime, keys } => {
+            let res = match handler.get_and_touch(keys, exptime) {
+                Ok(hits) => Response::ValuesWithCas(hits),
+                Err(err) => Response::from(err),
+            };
+            (res, false)
+        }
+        Request::Delete { key, noreply } => {
+            let res = match handler.delete(key) {
+                Ok(true) => Response::Deleted,
+                Ok(false) => Response::NotFound,
+                Err(err) => Response::from(err),
+            };
+            (res, noreply)
+        }
+        Request::Incr {
+            key,
+            delta,
+            noreply,
+        } => (counter_response(handler.incr(key, delta)), noreply),
+        Request::Decr {
+            key,
+            delta,
+            noreply,
+        } => (counter_response(handler.decr(key, delta)), noreply),
+        Request::Touch {
+            key,
+            exptime,
+            noreply,
+        } => {
+            let res = match handler.touch(key, exptime) {
+                Ok(true) => Response::Touched,
+                Ok(false) => Response::NotFound,
+                Err(err) => Response::from(err),
+            };
+            (res, noreply)
+        }
+        Request::Meta(req) => return meta::handle(handler, req).map(Response::Meta),
+        Request::Snapshot => {
+            let res = match handler.snapshot(false) {
+                Ok(_) => Response::SnapshotFinished,
+                Err(err) => Response::from(err),
+            };
+            (res, false)
+        }
+        Request::SnapshotStatus => {
+            let res = match handler.snapshot_status() {
+                Ok(status) => Response::SnapshotStatus(status),
+                Err(err) => Response::from(err),
+            };
+            (res, false)
+        }
+        Request::LastSave => {
+            let res = match handler.snapshot_status() {
+                Ok(status) => Response::Number(status.last_success.unwrap_or(0) as u64),
+                Err(err) => Response::from(err),
+            };
+            (res, false)
+        }
+        Request::Stats => {
+            let res = match handler.memory_stats() {
+                Ok(stats) => Response::Stats(stats),
+                Err(err) => Response::from(err),
+            };
+            (res, false)
+        }
+        Request::Reshard { shards } => {
+            let res = match handler.reshard(shards) {
+                Ok(_) => Response::Ok,
+                Err(err) => Response::from(err),
+            };
+            (res, false)
+        }
+    };
+
+    // like memcached, errors are still reported to noreply requests
+    if noreply && !res.is_error() {
+        return None;
+    }
+    Some(res)
+}
+
+fn store_response(result: Result<StoreResult, HorcruxError>) -> Response {
+    match result {
+        Ok(StoreResult::Stored) => Response::Stored,
+        Ok(StoreResult::NotStored) => Response::NotStored,
+        Ok(StoreResult::Exists) => Response::Exists,
+        Ok(StoreResult::NotFound) => Response::NotFound,
+        Ok(StoreResult::OutOfMemory) => Response::from(HorcruxError::OutOfMemory),
+        Ok(StoreResult::LogFailed) => Response::from(HorcruxError::LogFailed),
+        Err(err) => Response::from(err),
+    }
+}
+
+fn counter_response(result: Result<CounterResult, HorcruxError>) -> Response {
+    match result {
+        Ok(CounterResult::Value(n)) => Response::Number(n),
+        Ok(CounterResult::NotFound) => Response::NotFound,
+        Ok(CounterResult::NonNumeric) => Response::NonNumeric,
+        Ok(CounterResult::OutOfMemory) => Response::from(HorcruxError::OutOfMemory),
+        Ok(CounterResult::LogFailed) => Response::from(HorcruxError::LogFailed),
+        Err(err) => Response::from(err),
+    }
+}
+
 pub async fn send_response<W>(writer: &mut W, response: Response) -> Result<(), HorcruxError>
 where
     W: AsyncWrite + Unpin,
@@ -165,45 +850,73 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
-    use tokio::io::BufReader;
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
     use types::types::HorcruxError;
 
-    use crate::memcache::{read_request, Request};
+    use crate::memcache::{MemcacheCodec, Request, Response};
 
-    async fn create_mock_socket(data: &str) -> BufReader<Cursor<Vec<u8>>> {
-        let cursor = Cursor::new(data.as_bytes().to_vec());
-        BufReader::new(cursor)
+    fn read_request(data: &[u8]) -> Result<Request, HorcruxError> {
+        let mut codec = MemcacheCodec::new(1024 * 1024);
+        let mut buf = BytesMut::from(data);
+        codec.decode(&mut buf).unwrap().unwrap()
     }
 
-    #[tokio::test]
-    async fn test_read_request_set() {
+    #[test]
+    fn test_read_request_set() {
         let data = "set key 0 0 5\r\nvalue\r\n";
-        let mut socket = create_mock_socket(data).await;
-
-        let request = read_request(&mut socket).await.unwrap();
+        let request = read_request(data.as_bytes()).unwrap();
         match request {
             Request::Set {
                 key,
                 flags,
-                _exptime,
+                exptime,
                 data,
+                noreply,
             } => {
                 assert_eq!(key, "key");
                 assert_eq!(flags, 0);
-                assert_eq!(_exptime, 0);
-                assert_eq!(data, "value");
+                assert_eq!(exptime, 0);
+                assert_eq!(data, "value".as_bytes());
+                assert!(!noreply);
             }
             _ => panic!("Expected Set request"),
         }
     }
 
-    #[tokio::test]
-    async fn test_read_request_set_error() {
-        let data = "set key 0 0\r\n";
-        let mut socket = create_mock_socket(data).await;
+    #[test]
+    fn test_read_request_set_binary_data() {
+        let data = b"set key 0 0 6\r\n\x00\xff\r\n\x80\x7f\r\n".to_vec();
+        let request = read_request(&data).unwrap();
+        match request {
+            Request::Set { data, .. } => {
+                assert_eq!(&data[..], b"\x00\xff\r\n\x80\x7f");
+            }
+            _ => panic!("Expected Set request"),
+        }
+    }
 
-        let result = read_request(&mut socket).await;
+    #[test]
+    fn test_read_request_invalid_key() {
+        let data = format!("get {}\r\n", "k".repeat(251));
+        let result = read_request(data.as_bytes());
+        match result.err().unwrap() {
+            HorcruxError::ParseRequest(_) => {} // expected
+            _ => panic!("Expected ParseRequest error"),
+        }
+
+        let data = "get key\x01\r\n";
+        let result = read_request(data.as_bytes());
+        match result.err().unwrap() {
+            HorcruxError::ParseRequest(_) => {} // expected
+            _ => panic!("Expected ParseRequest error"),
+        }
+    }
+
+    #[test]
+    fn test_read_request_set_error() {
+        let data = "set key 0 0\r\n";
+        let result = read_request(data.as_bytes());
         assert!(result.is_err());
         match result.err().unwrap() {
             HorcruxError::ParseRequest(_) => {} // expected
@@ -211,26 +924,96 @@ mod tests {
         }
     }
 
-    #[tokio::test]
-    async fn test_read_request_get() {
-        let data = "get key\r\n";
-        let mut socket = create_mock_socket(data).await;
+    #[test]
+    fn test_read_request_append() {
+        let data = "append key 0 0 11\r\nhello world\r\n";
+        let request = read_request(data.as_bytes()).unwrap();
+        match request {
+            Request::Append { key, data, .. } => {
+                assert_eq!(key, "key");
+                assert_eq!(data, "hello world".as_bytes());
+            }
+            _ => panic!("Expected Append request"),
+        }
+    }
 
-        let request = read_request(&mut socket).await.unwrap();
+    #[test]
+    fn test_read_request_cas() {
+        let data = "cas key 1 0 5 42\r\nvalue\r\n";
+        let request = read_request(data.as_bytes()).unwrap();
         match request {
-            Request::Get { key } => {
+            Request::Cas {
+                key,
+                flags,
+                exptime,
+                data,
+                cas,
+                ..
+            } => {
                 assert_eq!(key, "key");
+                assert_eq!(flags, 1);
+                assert_eq!(exptime, 0);
+                assert_eq!(data, "value".as_bytes());
+                assert_eq!(cas, 42);
+            }
+            _ => panic!("Expected Cas request"),
+        }
+    }
+
+    #[test]
+    fn test_read_request_get() {
+        let data = "get key\r\n";
+        let request = read_request(data.as_bytes()).unwrap();
+        match request {
+            Request::Get { keys } => {
+                assert_eq!(keys, vec!["key"]);
             }
             _ => panic!("Expected Get request"),
         }
     }
 
-    #[tokio::test]
-    async fn test_read_request_get_error() {
-        let data = "get\r\n";
-        let mut socket = create_mock_socket(data).await;
+    #[test]
+    fn test_read_request_gets() {
+        let data = "gets key1 key2 key3\r\n";
+        let request = read_request(data.as_bytes()).unwrap();
+        match request {
+            Request::Gets { keys } => {
+                assert_eq!(keys, vec!["key1", "key2", "key3"]);
+            }
+            _ => panic!("Expected Gets request"),
+        }
+    }
+
+    #[test]
+    fn test_read_request_gat() {
+        let data = "gat 60 key1 key2\r\n";
+        let request = read_request(data.as_bytes()).unwrap();
+        match request {
+            Request::Gat { exptime, keys } => {
+                assert_eq!(exptime, 60);
+                assert_eq!(keys, vec!["key1", "key2"]);
+            }
+            _ => panic!("Expected Gat request"),
+        }
+    }
 
-        let result = read_request(&mut socket).await;
+    #[test]
+    fn test_read_request_incr() {
+        let data = "incr key 18446744073709551615\r\n";
+        let request = read_request(data.as_bytes()).unwrap();
+        match request {
+            Request::Incr { key, delta, .. } => {
+                assert_eq!(key, "key");
+                assert_eq!(delta, u64::MAX);
+            }
+            _ => panic!("Expected Incr request"),
+        }
+    }
+
+    #[test]
+    fn test_read_request_get_error() {
+        let data = "get\r\n";
+        let result = read_request(data.as_bytes());
         assert!(result.is_err());
         match result.err().unwrap() {
             HorcruxError::ParseRequest(_) => {} // expected
@@ -238,28 +1021,190 @@ mod tests {
         }
     }
 
-    #[tokio::test]
-    async fn test_read_request_snapshot() {
+    #[test]
+    fn test_read_request_snapshot() {
         let data = "snapshot\r\n";
-        let mut socket = create_mock_socket(data).await;
-
-        let request = read_request(&mut socket).await.unwrap();
+        let request = read_request(data.as_bytes()).unwrap();
         match request {
             Request::Snapshot => {}
             _ => panic!("Expected Snapshot request"),
         }
+
+        let request = read_request(b"snapshot status\r\n").unwrap();
+        match request {
+            Request::SnapshotStatus => {}
+            _ => panic!("Expected SnapshotStatus request"),
+        }
+
+        let request = read_request(b"lastsave\r\n").unwrap();
+        match request {
+            Request::LastSave => {}
+            _ => panic!("Expected LastSave request"),
+        }
+
+        let request = read_request(b"stats\r\n").unwrap();
+        match request {
+            Request::Stats => {}
+            _ => panic!("Expected Stats request"),
+        }
+
+        let request = read_request(b"reshard 4\r\n").unwrap();
+        match request {
+            Request::Reshard { shards: 4 } => {}
+            _ => panic!("Expected Reshard request"),
+        }
+        assert!(read_request(b"reshard many\r\n").is_err());
     }
 
-    #[tokio::test]
-    async fn test_read_request_invalid() {
+    #[test]
+    fn test_read_request_invalid() {
         let data = "invalid request\r\n";
-        let mut socket = create_mock_socket(data).await;
-
-        let result = read_request(&mut socket).await;
+        let result = read_request(data.as_bytes());
         assert!(result.is_err());
         match result.err().unwrap() {
+            HorcruxError::UnknownCommand => {} // expected
+            _ => panic!("Expected UnknownCommand error"),
+        }
+    }
+
+    #[test]
+    fn test_decode_pipelined_requests() {
+        let mut codec = MemcacheCodec::new(1024);
+        let mut buf = BytesMut::from(&b"set a 0 0 1\r\n1\r\nget a\r\ndelete a\r\n"[..]);
+
+        match codec.decode(&mut buf).unwrap().unwrap().unwrap() {
+            Request::Set { key, data, .. } => {
+                assert_eq!(key, "a");
+                assert_eq!(&data[..], b"1");
+            }
+            _ => panic!("Expected Set request"),
+        }
+        match codec.decode(&mut buf).unwrap().unwrap().unwrap() {
+            Request::Get { keys } => assert_eq!(keys, vec!["a"]),
+            _ => panic!("Expected Get request"),
+        }
+        match codec.decode(&mut buf).unwrap().unwrap().unwrap() {
+            Request::Delete { key, .. } => assert_eq!(key, "a"),
+            _ => panic!("Expected Delete request"),
+        }
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_partial_reads() {
+        let mut codec = MemcacheCodec::new(1024);
+        let mut buf = BytesMut::new();
+
+        // feed the request one byte at a time
+        let request = b"set key 0 0 10\r\nhello\r\nabc\r\n";
+        for (i, b) in request.iter().enumerate() {
+            buf.extend_from_slice(&[*b]);
+            let decoded = codec.decode(&mut buf).unwrap();
+            if i < request.len() - 1 {
+                assert!(decoded.is_none());
+                continue;
+            }
+            match decoded.unwrap().unwrap() {
+                Request::Set { data, .. } => assert_eq!(&data[..], b"hello\r\nabc"),
+                _ => panic!("Expected Set request"),
+            }
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_bad_data_chunk() {
+        let mut codec = MemcacheCodec::new(1024);
+        let mut buf = BytesMut::from(&b"set key 0 0 3\r\nhello\r\nget key\r\n"[..]);
+
+        match codec.decode(&mut buf).unwrap().unwrap() {
+            Err(HorcruxError::BadDataChunk) => {} // expected
+            _ => panic!("Expected BadDataChunk error"),
+        }
+    }
+
+    #[test]
+    fn test_decode_too_large() {
+        let mut codec = MemcacheCodec::new(4);
+        let mut buf = BytesMut::from(&b"set key 0 0 5\r\nhel"[..]);
+
+        match codec.decode(&mut buf).unwrap().unwrap() {
+            Err(HorcruxError::TooLarge) => {} // expected
+            _ => panic!("Expected TooLarge error"),
+        }
+
+        // the rest of the value is swallowed before the next request
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"lo\r\nget key\r\n");
+        match codec.decode(&mut buf).unwrap().unwrap().unwrap() {
+            Request::Get { keys } => assert_eq!(keys, vec!["key"]),
+            _ => panic!("Expected Get request"),
+        }
+    }
+
+    #[test]
+    fn test_decode_skips_data_of_invalid_line() {
+        let mut codec = MemcacheCodec::new(1024);
+        let lines: [&[u8]; 4] = [
+            b"set bad\x01key 0 0 10\r\n",
+            b"set key 0 0 10 noreplyy\r\n",
+            b"ms key 10 X\r\n",
+            b"set \xff 0 0 10\r\n",
+        ];
+        for line in lines {
+            // the data block holds a command that must not run
+            let mut buf = BytesMut::from(line);
+            buf.extend_from_slice(b"delete k\r\n\r\nget key\r\n");
+            match codec.decode(&mut buf).unwrap().unwrap() {
+                Err(HorcruxError::ParseRequest(_)) => {} // expected
+                _ => panic!("Expected ParseRequest error"),
+            }
+            match codec.decode(&mut buf).unwrap().unwrap().unwrap() {
+                Request::Get { keys } => assert_eq!(keys, vec!["key"]),
+                _ => panic!("Expected Get request"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_request_noreply() {
+        let data = "set key 0 0 5 noreply\r\nvalue\r\n";
+        match read_request(data.as_bytes()).unwrap() {
+            Request::Set { data, noreply, .. } => {
+                assert_eq!(data, "value".as_bytes());
+                assert!(noreply);
+            }
+            _ => panic!("Expected Set request"),
+        }
+
+        let data = "incr key 1 noreply\r\n";
+        match read_request(data.as_bytes()).unwrap() {
+            Request::Incr { noreply, .. } => assert!(noreply),
+            _ => panic!("Expected Incr request"),
+        }
+
+        let data = "delete key later\r\n";
+        match read_request(data.as_bytes()).err().unwrap() {
             HorcruxError::ParseRequest(_) => {} // expected
             _ => panic!("Expected ParseRequest error"),
         }
     }
+
+    #[test]
+    fn test_error_responses() {
+        let res = Response::from(HorcruxError::ParseRequest("invalid exptime".to_string()));
+        assert_eq!(res.as_bytes(), b"CLIENT_ERROR invalid exptime\r\n");
+
+        let res = Response::from(HorcruxError::BadDataChunk);
+        assert_eq!(res.as_bytes(), b"CLIENT_ERROR bad data chunk\r\n");
+
+        let res = Response::from(HorcruxError::TooLarge);
+        assert_eq!(
+            res.as_bytes(),
+            b"SERVER_ERROR object too large for cache\r\n"
+        );
+
+        let res = Response::from(HorcruxError::UnknownCommand);
+        assert_eq!(res.as_bytes(), b"ERROR\r\n");
+    }
 }
@@ -0,0 +1,465 @@
+use bytes::Bytes;
+use std::str::FromStr;
+
+use super::handler::Handler;
+use super::memcache::parse_key;
+use super::worker::{Arithmetic, SetMode};
+use db::db::{unix_now, CounterResult, Lease, LeaseOptions, StoreResult, Value};
+use types::types::HorcruxError;
+
+// -----------------------------------------------------------------------------
+// Request
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Get,
+    Set,
+    Delete,
+    Arithmetic,
+    Noop,
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    pub key: String,
+    // single letter flags with their token, e.g. ('T', "30") or ('v', "")
+    pub flags: Vec<(char, String)>,
+    // length of the data block that follows an ms request line
+    pub data_len: usize,
+    pub data: Bytes,
+}
+
+impl Request {
+    fn has(&self, flag: char) -> bool {
+        self.flags.iter().any(|(f, _)| *f == flag)
+    }
+
+    fn token(&self, flag: char) -> Option<&str> {
+        self.flags
+            .iter()
+            .find(|(f, _)| *f == flag)
+            .map(|(_, token)| token.as_str())
+    }
+
+    fn parse_token<T: FromStr>(&self, flag: char) -> Result<Option<T>, HorcruxError> {
+        match self.token(flag) {
+            Some(token) => match token.parse::<T>() {
+                Ok(val) => Ok(Some(val)),
+                Err(_) => Err(HorcruxError::ParseRequest(format!(
+                    "invalid token for flag {}",
+                    flag
+                ))),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+// format: mg <key> <flag>*
+//         ms <key> <datalen> <flag>*
+//         md <key> <flag>*
+//         ma <key> <flag>*
+//         mn
+pub fn parse_request(parts: &[&str]) -> Result<Request, HorcruxError> {
+    let (command, allowed) = match parts[0].to_lowercase().as_str() {
+        "mg" => (Command::Get, "cfkOqstvNRT"),
+        "ms" => (Command::Set, "cCFkMOqT"),
+        "md" => (Command::Delete, "IkOqT"),
+        "ma" => (Command::Arithmetic, "cDJkMNOqtTv"),
+        "mn" => (Command::Noop, ""),
+        _ => return Err(HorcruxError::ParseRequest("invalid command".to_string())),
+    };
+    if command == Command::Noop {
+        return Ok(Request {
+            command,
+            key: String::new(),
+            flags: Vec::new(),
+            data_len: 0,
+            data: Bytes::new(),
+        });
+    }
+
+    // validate request
+    let flags_from = if command == Command::Set { 3 } else { 2 };
+    if parts.len() < flags_from {
+        return Err(HorcruxError::ParseRequest(
+            "bad command line format".to_string(),
+        ));
+    }
+    let data_len = if command == Command::Set {
+        match parts[2].parse::<usize>() {
+            Ok(len) => len,
+            Err(_) => {
+                return Err(HorcruxError::ParseRequest(
+                    "invalid data length".to_string(),
+                ));
+            }
+        }
+    } else {
+        0
+    };
+
+    let mut flags = Vec::new();
+    for part in &parts[flags_from..] {
+        let mut chars = part.chars();
+        let flag = chars.next().unwrap_or_default();
+        if !allowed.contains(flag) {
+            return Err(HorcruxError::ParseRequest(format!("invalid flag {}", part)));
+        }
+        flags.push((flag, chars.as_str().to_string()));
+    }
+
+    Ok(Request {
+        command,
+        key: parse_key(parts[1].as_bytes())?,
+        flags,
+        data_len,
+        data: Bytes::new(),
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Response
+// -----------------------------------------------------------------------------
+
+#[derive(Debug)]
+pub struct Response {
+    code: &'static str,
+    flags: Vec<String>,
+    value: Option<Bytes>,
+}
+
+impl Response {
+    fn new(code: &'static str, req: &Request) -> Self {
+        // opaque and key are echoed so that pipelined replies can be matched
+        let mut flags = Vec::new();
+        if let Some(opaque) = req.token('O') {
+            flags.push(format!("O{}", opaque));
+        }
+        if req.has('k') {
+            flags.push(format!("k{}", req.key));
+        }
+        Response {
+            code,
+            flags,
+            value: None,
+        }
+    }
+
+    // a reply line without flags, such as MN or an error
+    fn bare(line: &'static str) -> Self {
+        Response {
+            code: line,
+            flags: Vec::new(),
+            value: None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut line = match &self.value {
+            Some(value) => format!("VA {}", value.len()),
+            None => self.code.to_string(),
+        };
+        for flag in self.flags.iter() {
+            line.push(' ');
+            line.push_str(flag);
+        }
+        line.push_str("\r\n");
+        let mut buf = line.into_bytes();
+        if let Some(value) = &self.value {
+            buf.extend_from_slice(value);
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Dispatch
+// -----------------------------------------------------------------------------
+
+// runs a request against the handler, returns None when the q flag hides
+// the reply
+pub fn handle<T: Handler>(handler: &T, req: Request) -> Option<Response> {
+    let res = match req.command {
+        Command::Get => get(handler, &req),
+        Command::Set => set(handler, &req),
+        Command::Delete => delete(handler, &req),
+        Command::Arithmetic => arithmetic(handler, &req),
+        Command::Noop => return Some(Response::bare("MN")),
+    };
+    let res = match res {
+        Ok(res) => res,
+        Err(HorcruxError::ParseRequest(_)) => {
+            Response::bare("CLIENT_ERROR bad command line format")
+        }
+        Err(HorcruxError::OutOfMemory) => Response::bare("SERVER_ERROR out of memory"),
+        Err(HorcruxError::LogFailed) => {
+            Response::bare("SERVER_ERROR failed to write to append-only log")
+        }
+        Err(_) => Response::bare("SERVER_ERROR internal error"),
+    };
+
+    // q hides the replies that a pipelining client does not need to see
+    let quiet = match req.command {
+        Command::Get => res.code == "EN",
+        Command::Set => res.code == "HD",
+        _ => res.code == "HD" || res.code == "NF",
+    };
+    if req.has('q') && quiet {
+        return None;
+    }
+    Some(res)
+}
+
+fn get<T: Handler>(handler: &T, req: &Request) -> Result<Response, HorcruxError> {
+    let opts = LeaseOptions {
+        touch: req.parse_token('T')?,
+        vivify: req.parse_token('N')?,
+        recache: req.parse_token('R')?,
+    };
+    let (value, lease) = match handler.get_with_lease(req.key.clone(), opts)? {
+        Some(hit) => hit,
+        None => return Ok(Response::new("EN", req)),
+    };
+
+    let mut res = Response::new("HD", req);
+    res.flags.extend(value_flags(req, &value));
+    match lease {
+        Lease::Win => res.flags.push("W".to_string()),
+        Lease::Lost => res.flags.push("Z".to_string()),
+        Lease::None => {}
+    }
+    if value.stale {
+        res.flags.push("X".to_string());
+    }
+    if req.has('v') {
+        res.value = Some(value.data);
+    }
+    Ok(res)
+}
+
+fn set<T: Handler>(handler: &T, req: &Request) -> Result<Response, HorcruxError> {
+    let key = req.key.clone();
+    let flags = req.parse_token('F')?.unwrap_or(0);
+    let exptime = req.parse_token('T')?.unwrap_or(0);
+    let cas = req.parse_token::<u64>('C')?;
+    let data = req.data.clone();
+
+    let mode = match req.token('M').unwrap_or("S") {
+        "S" | "s" => SetMode::Set,
+        "E" | "e" => SetMode::Add,
+        "R" | "r" => SetMode::Replace,
+        "A" | "a" => SetMode::Append,
+        "P" | "p" => SetMode::Prepend,
+        _ => return Ok(Response::bare("CLIENT_ERROR invalid mode for ms")),
+    };
+    let (result, value) = handler.meta_set(key, mode, flags, exptime, data, cas)?;
+
+    let mut res = match result {
+        StoreResult::Stored => Response::new("HD", req),
+        StoreResult::NotStored => Response::new("NS", req),
+        StoreResult::Exists => Response::new("EX", req),
+        StoreResult::NotFound => Response::new("NF", req),
+        StoreResult::OutOfMemory => return Err(HorcruxError::OutOfMemory),
+        StoreResult::LogFailed => return Err(HorcruxError::LogFailed),
+    };
+    if let (Some(value), true) = (value, req.has('c')) {
+        res.flags.push(format!("c{}", value.cas));
+    }
+    Ok(res)
+}
+
+fn delete<T: Handler>(handler: &T, req: &Request) -> Result<Response, HorcruxError> {
+    let found = if req.has('I') {
+        handler.invalidate(req.key.clone(), req.parse_token('T')?)?
+    } else {
+        handler.delete(req.key.clone())?
+    };
+    if found {
+        Ok(Response::new("HD", req))
+    } else {
+        Ok(Response::new("NF", req))
+    }
+}
+
+fn arithmetic<T: Handler>(handler: &T, req: &Request) -> Result<Response, HorcruxError> {
+    let delta = req.parse_token('D')?.unwrap_or(1);
+    let incr = match req.token('M').unwrap_or("I") {
+        "I" | "i" | "+" => true,
+        "D" | "d" | "-" => false,
+        _ => return Ok(Response::bare("CLIENT_ERROR invalid mode for ma")),
+    };
+    let vivify = match req.parse_token('N')? {
+        Some(exptime) => Some((req.parse_token('J')?.unwrap_or(0), exptime)),
+        None => None,
+    };
+    let op = Arithmetic {
+        incr,
+        delta,
+        vivify,
+        touch: req.parse_token('T')?,
+    };
+
+    let (result, value) = handler.meta_arithmetic(req.key.clone(), op)?;
+    let n = match result {
+        CounterResult::Value(n) => n,
+        CounterResult::NotFound => return Ok(Response::new("NF", req)),
+        CounterResult::OutOfMemory => return Err(HorcruxError::OutOfMemory),
+        CounterResult::LogFailed => return Err(HorcruxError::LogFailed),
+        CounterResult::NonNumeric => {
+            return Ok(Response::bare(
+                "CLIENT_ERROR cannot increment or decrement non-numeric value",
+            ))
+        }
+    };
+
+    let mut res = Response::new("HD", req);
+    if let (Some(value), true) = (value, req.has('c') || req.has('t')) {
+        res.flags.extend(value_flags(req, &value));
+    }
+    if req.has('v') {
+        res.value = Some(n.to_string().into());
+    }
+    Ok(res)
+}
+
+// the c, f, s and t flags ask for metadata of the value
+fn value_flags(req: &Request, value: &Value) -> Vec<String> {
+    let mut flags = Vec::new();
+    if req.has('c') {
+        flags.push(format!("c{}", value.cas));
+    }
+    if req.has('f') {
+        flags.push(format!("f{}", value.flags));
+    }
+    if req.has('s') {
+        flags.push(format!("s{}", value.data.len()));
+    }
+    if req.has('t') {
+        // -1 means the value never expires
+        let ttl = match value.exptime {
+            0 => -1,
+            exptime => exptime.saturating_sub(unix_now()) as i64,
+        };
+        flags.push(format!("t{}", ttl));
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::BaseHandler;
+    use crate::worker::{JobQueue, Worker};
+    use db::db::DB;
+    use std::thread;
+
+    fn start_handler() -> BaseHandler {
+        let job_queue = JobQueue::new();
+        let mut worker = Worker::new(job_queue.clone(), DB::new("/tmp".to_string()), vec![]);
+        thread::spawn(move || {
+            worker.run();
+        });
+        BaseHandler::new(job_queue)
+    }
+
+    fn run(handler: &BaseHandler, line: &str, data: &str) -> String {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        let mut req = parse_request(&parts).unwrap();
+        req.data = Bytes::copy_from_slice(data.as_bytes());
+        match handle(handler, req) {
+            Some(res) => String::from_utf8(res.as_bytes()).unwrap(),
+            None => String::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let req = parse_request(&["ms", "key", "5", "T30", "F7", "q"]).unwrap();
+        assert_eq!(req.command, Command::Set);
+        assert_eq!(req.key, "key");
+        assert_eq!(req.data_len, 5);
+        assert_eq!(req.parse_token::<u32>('T').unwrap(), Some(30));
+        assert_eq!(req.parse_token::<u32>('F').unwrap(), Some(7));
+        assert!(req.has('q'));
+
+        assert!(parse_request(&["mg", "key", "x"]).is_err());
+        assert!(parse_request(&["ms", "key"]).is_err());
+    }
+
+    #[test]
+    fn test_set_and_get() {
+        let handler = start_handler();
+
+        assert_eq!(run(&handler, "ms key 5 F3 O1", "value"), "HD O1\r\n");
+        assert_eq!(run(&handler, "ms key 5 F3 q", "value"), "");
+        assert_eq!(run(&handler, "ms key 5 ME", "value"), "NS\r\n");
+        assert_eq!(
+            run(&handler, "mg key v f s k", ""),
+            "VA 5 kkey f3 s5\r\nvalue\r\n"
+        );
+        assert_eq!(run(&handler, "mg missing v q", ""), "");
+        assert_eq!(run(&handler, "mg missing v", ""), "EN\r\n");
+        assert_eq!(run(&handler, "mn", ""), "MN\r\n");
+    }
+
+    #[test]
+    fn test_stale_and_win() {
+        let handler = start_handler();
+
+        // the first client to miss wins the right to fill the value
+        assert_eq!(run(&handler, "mg key N30 s", ""), "HD s0 W\r\n");
+        assert_eq!(run(&handler, "mg key N30 s", ""), "HD s0 Z\r\n");
+        assert_eq!(run(&handler, "ms key 5", "value"), "HD\r\n");
+        assert_eq!(run(&handler, "mg key v", ""), "VA 5\r\nvalue\r\n");
+
+        // invalidated values are served as stale to everyone but the winner
+        assert_eq!(run(&handler, "md key I", ""), "HD\r\n");
+        assert_eq!(run(&handler, "mg key v", ""), "VA 5 W X\r\nvalue\r\n");
+        assert_eq!(run(&handler, "mg key v", ""), "VA 5 Z X\r\nvalue\r\n");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let handler = start_handler();
+
+        assert_eq!(run(&handler, "ma counter", ""), "NF\r\n");
+        assert_eq!(run(&handler, "ma counter N0 J10 v", ""), "VA 2\r\n10\r\n");
+        assert_eq!(run(&handler, "ma counter D5 v", ""), "VA 2\r\n15\r\n");
+        assert_eq!(run(&handler, "ma counter MD D20 v", ""), "VA 1\r\n0\r\n");
+    }
+
+    #[test]
+    fn test_return_flags() {
+        let handler = start_handler();
+
+        // the token returned by a set is the one a get sees
+        let res = run(&handler, "ms key 5 c", "value");
+        let cas = res.trim_end().strip_prefix("HD c").unwrap().to_string();
+        assert_eq!(run(&handler, "mg key c", ""), format!("HD c{}\r\n", cas));
+        let res = run(&handler, &format!("ms key 5 C{} c", cas), "other");
+        assert!(res.starts_with("HD c"));
+        assert_ne!(res, format!("HD c{}\r\n", cas));
+        assert_eq!(
+            run(&handler, &format!("ms key 5 C{} c", cas), "stale"),
+            "EX\r\n"
+        );
+
+        // a counter created with an exptime reports it with its token
+        let res = run(&handler, "ma counter N0 J5 T100 c t v", "");
+        let (head, n) = res.split_once("\r\n").unwrap();
+        assert_eq!(n, "5\r\n");
+        let flags = head.split_whitespace().collect::<Vec<_>>();
+        assert_eq!(flags[..2], ["VA", "1"]);
+        let cas = flags[2].strip_prefix('c').unwrap();
+        assert_eq!(
+            run(&handler, "mg counter c", ""),
+            format!("HD c{}\r\n", cas)
+        );
+        let ttl: u32 = flags[3].strip_prefix('t').unwrap().parse().unwrap();
+        assert!((99..=100).contains(&ttl));
+    }
+}
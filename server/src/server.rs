@@ -1,58 +1,304 @@
-use db::db::DB;
+use db::aof::FsyncPolicy;
+use db::db::{RestoreMode, DB};
+use db::eviction::Eviction;
+use db::generation::{self, Retention};
+use db::shard::{self, ShardLayout, SHARD_HASH};
+use db::store::{LocalStore, S3Store, SnapshotStore};
 use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
 use std::thread;
 use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::{time, time::Duration};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
 
-use super::handler::{BaseHandler, Handler, SnapshotHandler};
-use super::memcache::{read_request, send_response, Request, Response};
-use super::worker::{JobQueue, Worker};
+use super::binary;
+use super::handler::{BaseHandler, Handler, ShardHandler, ShardHost};
+use super::memcache::{self, send_response, MemcacheCodec, Response};
+use super::supervisor::SaveRule;
+use super::worker::{JobQueue, SnapshotEngine, Worker};
 use types::types::HorcruxError;
 
+// how the DB is persisted and restored
+#[derive(Clone)]
+pub struct SnapshotConfig {
+    pub path: String,
+    // snapshots are only taken on demand when there are no save rules
+    pub save_rules: Vec<SaveRule>,
+    // mutations are only logged when a fsync policy is set
+    pub appendfsync: Option<FsyncPolicy>,
+    pub restore_mode: RestoreMode,
+    // generation to roll back to instead of the latest one
+    pub restore_from: Option<u64>,
+    pub retention: Retention,
+    // deltas written between full snapshots
+    pub max_deltas: usize,
+    // snapshots are kept next to the snapshot path when not set
+    pub s3: Option<S3Config>,
+    pub engine: SnapshotEngine,
+}
+
+// an S3-compatible bucket to keep snapshot generations in
+#[derive(Clone)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    // prepended to every stored file name
+    pub prefix: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+// how much memory the values may take
+#[derive(Clone, Default)]
+pub struct MemoryConfig {
+    // in bytes, split evenly between the shards. none lets the DB grow
+    // without bound
+    pub limit: Option<usize>,
+    // writes fail with out of memory instead of evicting values
+    pub no_evict: bool,
+    // which values are evicted first
+    pub eviction: Eviction,
+}
+
 #[derive(Clone)]
 pub struct Config {
     addr: String,
-    snapshot_path: String,
-    snapshot_interval_secs: u64,
+    snapshot: SnapshotConfig,
+    max_item_size: usize,
+    // each shard has its own worker thread, DB and snapshots
+    shards: usize,
+    memory: MemoryConfig,
 }
 
 impl Config {
     pub fn new(
         addr: String,
-        snapshot_path: String,
-        snapshot_interval_secs: u64,
+        snapshot: SnapshotConfig,
+        max_item_size: usize,
+        shards: usize,
+        memory: MemoryConfig,
     ) -> Result<Self, String> {
-        if snapshot_path == "" {
+        if snapshot.path.is_empty() {
             return Err("Snapshot directory cannot be empty".to_string());
         }
-        if snapshot_interval_secs == 0 {
-            return Err("Snapshot interval cannot be 0".to_string());
+        if snapshot.save_rules.iter().any(|rule| rule.secs == 0) {
+            return Err("Save rule interval cannot be 0".to_string());
+        }
+        if snapshot.retention.keep == 0 {
+            return Err("Number of snapshot generations to keep cannot be 0".to_string());
+        }
+        if snapshot.restore_from.is_some() && snapshot.restore_mode == RestoreMode::Empty {
+            return Err("Cannot restore from a generation with the empty restore mode".to_string());
+        }
+        if let Some(s3) = &snapshot.s3 {
+            if s3.endpoint.is_empty() || s3.bucket.is_empty() {
+                return Err("S3 endpoint and bucket cannot be empty".to_string());
+            }
+            if s3.access_key.is_empty() || s3.secret_key.is_empty() {
+                return Err("S3 credentials cannot be empty".to_string());
+            }
+        }
+        if max_item_size == 0 {
+            return Err("Max item size cannot be 0".to_string());
+        }
+        if shards == 0 {
+            return Err("Number of shards cannot be 0".to_string());
+        }
+        // every shard has generations of its own
+        if snapshot.restore_from.is_some() && shards > 1 {
+            return Err("Cannot restore from a generation with more than one shard".to_string());
+        }
+        // an item that does not fit in a shard could never be stored
+        if memory
+            .limit
+            .is_some_and(|limit| limit / shards < max_item_size)
+        {
+            return Err("Memory limit per shard cannot be below the max item size".to_string());
         }
 
         Ok(Config {
             addr,
-            snapshot_path,
-            snapshot_interval_secs,
+            snapshot,
+            max_item_size,
+            shards,
+            memory,
         })
     }
 }
 
 pub async fn serve(config: &Config) -> Result<(), Box<dyn Error>> {
-    let job_queue = JobQueue::new();
-    let job_queue_for_worker = job_queue.clone();
-    let config_for_worker = config.clone();
+    // restore before accepting connections so that a strict restore failure
+    // stops the server instead of serving an empty DB
+    let path = &config.snapshot.path;
+    let layout = ShardLayout {
+        shards: config.shards,
+        hash: SHARD_HASH.to_string(),
+    };
+    let base = generation::base_name(path);
+    let store = snapshot_store(&config.snapshot, path);
+    if config.snapshot.restore_mode == RestoreMode::Empty {
+        // the stored snapshots are discarded, so their layout does not matter
+        shard::write_layout(store.as_ref(), &base, &layout)?;
+    } else {
+        shard::check_layout(store.as_ref(), &base, &layout)?;
+    }
+    let dbs = (0..config.shards)
+        .map(|shard_id| restore_shard(config, shard_id, config.shards))
+        .collect::<Result<Vec<_>, _>>()?;
+    let job_queues = dbs
+        .into_iter()
+        .map(|db| start_worker(&config.snapshot, db))
+        .collect::<Vec<_>>();
 
-    thread::spawn(move || {
-        let mut db = DB::new(config_for_worker.snapshot_path.clone());
-        db.restore();
+    if config.shards == 1 {
+        let handler = BaseHandler::new(job_queues[0].clone());
+        run(config, handler).await
+    } else {
+        let mut handler = ShardHandler::new(job_queues);
+        handler.set_host(Arc::new(Shards {
+            config: config.snapshot.clone(),
+            memory: config.memory.clone(),
+            max_item_size: config.max_item_size,
+        }));
+        run(config, handler).await
+    }
+}
+
+// where the snapshots of the DB at path are stored
+fn snapshot_store(config: &SnapshotConfig, path: &str) -> Box<dyn SnapshotStore> {
+    let s3 = match &config.s3 {
+        Some(s3) => s3,
+        None => return Box::new(LocalStore::for_snapshot(path)),
+    };
+    // snapshots are written next to the snapshot path before upload
+    let local_dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.display().to_string(),
+        _ => ".".to_string(),
+    };
+    Box::new(S3Store::new(
+        &s3.endpoint,
+        &s3.bucket,
+        &s3.prefix,
+        &s3.region,
+        &s3.access_key,
+        &s3.secret_key,
+        &local_dir,
+    ))
+}
 
-        let mut worker = Worker::new(job_queue_for_worker.clone(), db);
+fn new_db(config: &SnapshotConfig, path: &str) -> DB {
+    let mut db = DB::new(path.to_string());
+    // restored straight into the maps the engine needs
+    db.set_persistent(config.engine == SnapshotEngine::Thread);
+    db.set_retention(config.retention);
+    db.set_max_deltas(config.max_deltas);
+    db.set_store(snapshot_store(config, path));
+    db
+}
+
+fn start_worker(config: &SnapshotConfig, db: DB) -> JobQueue {
+    let job_queue = JobQueue::new();
+    let mut worker = Worker::new(job_queue.clone(), db, config.save_rules.clone());
+    worker.set_snapshot_engine(config.engine);
+    thread::spawn(move || {
         worker.run();
     });
+    job_queue
+}
 
-    let handler = BaseHandler::new(job_queue.clone());
+fn set_memory_limit(db: &mut DB, memory: &MemoryConfig, shards: usize) {
+    db.set_eviction_policy(memory.eviction.policy());
+    db.set_memory_limit(memory.limit.map(|limit| limit / shards), memory.no_evict);
+}
 
+fn restore_shard(config: &Config, shard_id: usize, shards: usize) -> Result<DB, Box<dyn Error>> {
+    let memory = &config.memory;
+    let config = &config.snapshot;
+    let path = shard::shard_path(&config.path, shard_id, shards);
+    let mut db = new_db(config, &path);
+    // values that do not fit are evicted once restored
+    set_memory_limit(&mut db, memory, shards);
+    let stats = match config.restore_from {
+        Some(id) => db.restore_from(config.restore_mode, id)?,
+        None => db.restore(config.restore_mode)?,
+    };
+    println!(
+        "Restored {} records, skipped {}, expired {}, applied {} deltas, replayed {} log entries from {}",
+        stats.loaded, stats.skipped, stats.expired, stats.deltas, stats.replayed, path
+    );
+    // copies left behind by a reshard that stopped before dropping them
+    if shards > 1 {
+        let dropped = db.drop_foreign(shard_id, shards);
+        if dropped > 0 {
+            println!(
+                "Dropped {} records owned by other shards from {}",
+                dropped, path
+            );
+        }
+    }
+    if let Some(policy) = config.appendfsync {
+        db.open_log(policy)?;
+    }
+    Ok(db)
+}
+
+// starts and retires shards for an online reshard. the shards it starts
+// get their share of the memory limit with the new number of shards
+struct Shards {
+    config: SnapshotConfig,
+    memory: MemoryConfig,
+    max_item_size: usize,
+}
+
+impl ShardHost for Shards {
+    fn start_shard(&self, shard_id: usize, shards: usize) -> Result<JobQueue, HorcruxError> {
+        if self
+            .memory
+            .limit
+            .is_some_and(|limit| limit / shards < self.max_item_size)
+        {
+            return Err(HorcruxError::Reshard(
+                "memory limit per shard would be below the max item size".to_string(),
+            ));
+        }
+        let path = shard::shard_path(&self.config.path, shard_id, shards);
+        let mut db = new_db(&self.config, &path);
+        set_memory_limit(&mut db, &self.memory, shards);
+        let reshard_error =
+            |err: std::io::Error| HorcruxError::Reshard(format!("cannot start {}: {}", path, err));
+        db.discard().map_err(reshard_error)?;
+        if let Some(policy) = self.config.appendfsync {
+            db.open_log(policy).map_err(reshard_error)?;
+        }
+        Ok(start_worker(&self.config, db))
+    }
+
+    fn write_layout(&self, shards: usize) -> Result<(), HorcruxError> {
+        let path = &self.config.path;
+        let layout = ShardLayout {
+            shards,
+            hash: SHARD_HASH.to_string(),
+        };
+        let store = snapshot_store(&self.config, path);
+        shard::write_layout(store.as_ref(), &generation::base_name(path), &layout)
+            .map_err(|err| HorcruxError::Reshard(format!("cannot write layout: {}", err)))
+    }
+
+    fn remove_shard(&self, shard_id: usize, shards: usize) -> Result<(), HorcruxError> {
+        let path = shard::shard_path(&self.config.path, shard_id, shards);
+        new_db(&self.config, &path)
+            .discard()
+            .map_err(|err| HorcruxError::Reshard(format!("cannot remove {}: {}", path, err)))
+    }
+}
+
+async fn run<T: Handler + Send + 'static>(
+    config: &Config,
+    handler: T,
+) -> Result<(), Box<dyn Error>> {
     let listener = TcpListener::bind(&config.addr).await?;
     println!("Server running on {}", &config.addr);
 
@@ -80,8 +326,8 @@ pub async fn serve(config: &Config) -> Result<(), Box<dyn Error>> {
                     Ok(_) => {
                         println!("Snapshot taken successfully");
                     }
-                    Err(_) => {
-                        println!("Failed to take snapshot");
+                    Err(err) => {
+                        println!("{}", err);
                     }
                 }
                 println!("Shutting down...");
@@ -92,37 +338,15 @@ pub async fn serve(config: &Config) -> Result<(), Box<dyn Error>> {
         }
     });
 
-    // take snapshot at regular intervals
-    let handler_for_interval = handler.clone();
-    let interval = config.snapshot_interval_secs;
-    let interval_task = tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(interval));
-        // ignore the first tick, which is triggered immediately
-        interval.tick().await;
-
-        loop {
-            interval.tick().await;
-            println!("Start taking snapshot");
-            match handler_for_interval.snapshot(false) {
-                Ok(_) => {
-                    println!("Snapshot taken successfully");
-                }
-                Err(_) => {
-                    println!("Failed to take snapshot");
-                }
-            }
-            println!("Finished to send snapshot request");
-        }
-    });
-
     // main loop
+    let max_item_size = config.max_item_size;
     loop {
         tokio::select! {
             Ok((socket, _)) = listener.accept() => {
                 let h = handler.clone();
 
                 tokio::spawn(async move {
-                    process(socket, h).await;
+                    process(socket, h, max_item_size).await;
                 });
             }
             _ = &mut sigterm_task => {
@@ -134,81 +358,77 @@ pub async fn serve(config: &Config) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    interval_task.abort();
     Ok(())
 }
 
-pub async fn process<T: Handler>(mut socket: tokio::net::TcpStream, handler: T) {
+pub async fn process<T: Handler>(socket: tokio::net::TcpStream, handler: T, max_item_size: usize) {
+    // like memcached, pick the protocol from the first byte of the connection
+    let mut first = [0; 1];
+    match socket.peek(&mut first).await {
+        Ok(0) | Err(_) => {}
+        Ok(_) if first[0] == binary::MAGIC_REQUEST => {
+            process_binary(socket, handler, max_item_size).await
+        }
+        Ok(_) => process_text(socket, handler, max_item_size).await,
+    }
+}
+
+async fn process_binary<T: Handler>(
+    mut socket: tokio::net::TcpStream,
+    handler: T,
+    max_item_size: usize,
+) {
     loop {
-        let req = match read_request(&mut socket).await {
+        let req = match binary::read_request(&mut socket, max_item_size).await {
             Ok(req) => req,
-            Err(err) => match err {
-                HorcruxError::Connection(s) => {
-                    println!("Connection error: {}", s);
-                    return;
-                }
-                HorcruxError::ParseRequest(s) => {
-                    println!("Failed to parse request: {}", s);
-                    if send_response(&mut socket, Response::Error).await.is_err() {
-                        println!("Failed to send response");
-                        return;
-                    }
-                    continue;
-                }
-                HorcruxError::Ignorable => {
-                    continue;
-                }
-                _ => {
-                    return;
-                }
-            },
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
         };
 
-        match req {
-            Request::Set {
-                key,
-                flags,
-                _exptime,
-                data,
-            } => match handler.set(key, flags, _exptime, data) {
-                Ok(_) => {
-                    if send_response(&mut socket, Response::Stored).await.is_err() {
-                        println!("Failed to send response");
-                        return;
-                    }
-                }
-                Err(_) => {
-                    println!("Failed to handle set request");
-                    return;
-                }
-            },
-            Request::Get { key } => {
-                let val = handler.get(&key);
-                if send_response(&mut socket, Response::Value(key, val))
-                    .await
-                    .is_err()
-                {
-                    println!("Failed to send response");
-                    return;
-                }
+        let quit = req.is_quit();
+        if let Some(res) = binary::handle(&handler, req) {
+            if binary::send_response(&mut socket, res).await.is_err() {
+                return;
+            }
+        }
+        if quit {
+            return;
+        }
+    }
+}
+
+async fn process_text<T: Handler>(
+    mut socket: tokio::net::TcpStream,
+    handler: T,
+    max_item_size: usize,
+) {
+    let (reader, mut writer) = socket.split();
+    let mut requests = FramedRead::new(reader, MemcacheCodec::new(max_item_size));
+    loop {
+        let res = match requests.next().await {
+            None => return,
+            Some(Err(err)) => {
+                println!("{}", err);
+                return;
+            }
+            Some(Ok(Ok(req))) => memcache::handle(&handler, req),
+            Some(Ok(Err(HorcruxError::Connection(s)))) => {
+                println!("Connection error: {}", s);
+                return;
+            }
+            Some(Ok(Err(HorcruxError::Ignorable))) => continue,
+            Some(Ok(Err(err))) => {
+                println!("{}", err);
+                Some(Response::from(err))
+            }
+        };
+
+        if let Some(res) = res {
+            if send_response(&mut writer, res).await.is_err() {
+                return;
             }
-            Request::Snapshot => match handler.snapshot(false) {
-                Ok(_) => {
-                    if send_response(&mut socket, Response::SnapshotFinished)
-                        .await
-                        .is_err()
-                    {
-                        println!("Failed to send response");
-                        return;
-                    }
-                }
-                Err(_) => {
-                    if send_response(&mut socket, Response::Error).await.is_err() {
-                        println!("Failed to send response");
-                        return;
-                    }
-                }
-            },
         }
     }
 }
@@ -0,0 +1,307 @@
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use nix::errno::Errno;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::worker::Response;
+use db::db::unix_now;
+
+// what clients see of the snapshot lifecycle
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotStatus {
+    // pid of the snapshot process still running, none for a snapshot thread
+    pub pid: Option<i32>,
+    // unix time the running snapshot started
+    pub started_at: Option<u32>,
+    // unix time of the last successful snapshot
+    pub last_success: Option<u32>,
+    // how long the last finished snapshot took
+    pub last_duration: Option<Duration>,
+    // ok, exit=<code>, signal=<name> or fork_failed for a snapshot process,
+    // ok, error, panicked or spawn_failed for a snapshot thread
+    pub last_status: Option<String>,
+    // mutations not yet covered by a successful snapshot
+    pub changes: u64,
+}
+
+// redis-style save rule: snapshot once `secs` have passed since the last
+// successful snapshot if there have been at least `changes` mutations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub secs: u64,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    pub fn is_due(&self, elapsed: Duration, changes: u64) -> bool {
+        elapsed.as_secs() >= self.secs && changes >= self.changes
+    }
+}
+
+// parses "<secs> <changes>", as in redis' save directive
+impl FromStr for SaveRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts.as_slice() {
+            [secs, changes] => match (secs.parse::<u64>(), changes.parse::<u64>()) {
+                (Ok(secs), Ok(changes)) if secs > 0 => Ok(SaveRule { secs, changes }),
+                _ => Err(format!("Invalid save rule: {}", s)),
+            },
+            _ => Err(format!("Invalid save rule: {}", s)),
+        }
+    }
+}
+
+// the rules set by repeated save directives. an empty directive drops the
+// rules before it, so `--save ""` turns automatic snapshots off as in redis
+pub fn parse_save_rules<S: AsRef<str>>(directives: &[S]) -> Result<Vec<SaveRule>, String> {
+    let mut rules = Vec::new();
+    for directive in directives {
+        let directive = directive.as_ref();
+        if directive.trim().is_empty() {
+            rules.clear();
+        } else {
+            rules.push(directive.parse()?);
+        }
+    }
+    Ok(rules)
+}
+
+// how long to wait before retrying a save rule after a failed snapshot
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// what is writing the running snapshot
+pub enum Task {
+    // a forked process, its exit code tells whether it succeeded
+    Process(Pid),
+    // a thread writing a frozen copy of the DB, it sends whether it succeeded
+    Thread(Receiver<bool>),
+}
+
+impl Task {
+    // how the snapshot ended, none while it is still running
+    fn status(&self) -> Option<String> {
+        match self {
+            Task::Process(pid) => match waitpid(*pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, 0)) => Some("ok".to_string()),
+                Ok(WaitStatus::Exited(_, code)) => Some(format!("exit={}", code)),
+                Ok(WaitStatus::Signaled(_, signal, _)) => {
+                    Some(format!("signal={}", signal.as_str()))
+                }
+                Ok(_) | Err(Errno::EINTR) => None,
+                Err(err) => Some(format!("waitpid={}", err)),
+            },
+            Task::Thread(done) => match done.try_recv() {
+                Ok(true) => Some("ok".to_string()),
+                Ok(false) => Some("error".to_string()),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => Some("panicked".to_string()),
+            },
+        }
+    }
+}
+
+struct Child {
+    task: Task,
+    started: Instant,
+    started_at: u32,
+    // mutation count of the DB when the snapshot started
+    covered: u64,
+    // clients waiting for the snapshot to finish
+    waiters: Vec<Sender<Response>>,
+}
+
+// keeps track of the snapshot process or thread so that only one runs at a
+// time and a process is always reaped
+pub struct Supervisor {
+    child: Option<Child>,
+    // the last successful snapshot, or startup when there is none yet
+    last_save: Instant,
+    last_failure: Option<Instant>,
+    last_success: Option<u32>,
+    last_duration: Option<Duration>,
+    last_status: Option<String>,
+    // clients waiting for a snapshot to start once the running one is done,
+    // it would miss the writes they made after it started
+    queued: Vec<Sender<Response>>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Supervisor {
+            child: None,
+            last_save: Instant::now(),
+            last_failure: None,
+            last_success: None,
+            last_duration: None,
+            last_status: None,
+            queued: Vec::new(),
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.child.is_some()
+    }
+
+    pub fn start(&mut self, task: Task, covered: u64) {
+        self.child = Some(Child {
+            task,
+            started: Instant::now(),
+            started_at: unix_now(),
+            covered,
+            waiters: Vec::new(),
+        });
+    }
+
+    // whether any save rule asks for a snapshot now
+    pub fn should_save(&self, rules: &[SaveRule], changes: u64) -> bool {
+        if self.in_progress() {
+            return false;
+        }
+        if let Some(failure) = self.last_failure {
+            if failure.elapsed() < RETRY_DELAY {
+                return false;
+            }
+        }
+        let elapsed = self.last_save.elapsed();
+        rules.iter().any(|rule| rule.is_due(elapsed, changes))
+    }
+
+    // lets a client wait for the snapshot already running
+    pub fn wait(&mut self, waiter: Sender<Response>) {
+        match self.child.as_mut() {
+            Some(child) => child.waiters.push(waiter),
+            None => {
+                let _ = waiter.send(Response::SnapshotFailed);
+            }
+        }
+    }
+
+    // lets a client wait for a snapshot started after the running one
+    pub fn queue(&mut self, waiter: Sender<Response>) {
+        self.queued.push(waiter);
+    }
+
+    // the clients waiting for a new snapshot, once none is running
+    pub fn take_queued(&mut self) -> Vec<Sender<Response>> {
+        if self.in_progress() {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.queued)
+    }
+
+    pub fn start_failed(&mut self, status: &str) {
+        self.last_failure = Some(Instant::now());
+        self.last_status = Some(status.to_string());
+    }
+
+    // reaps the snapshot process or thread if it has finished, without
+    // blocking, and returns the mutation count a successful snapshot covers
+    pub fn poll(&mut self) -> Option<u64> {
+        let status = self.child.as_ref()?.task.status()?;
+        let child = self.child.take()?;
+
+        let ok = status == "ok";
+        if ok {
+            self.last_save = Instant::now();
+            self.last_failure = None;
+            self.last_success = Some(unix_now());
+        } else {
+            println!("Snapshot failed: {}", status);
+            self.last_failure = Some(Instant::now());
+        }
+        self.last_duration = Some(child.started.elapsed());
+        self.last_status = Some(status);
+
+        let response = if ok {
+            Response::SnapshotFinished
+        } else {
+            Response::SnapshotFailed
+        };
+        for waiter in child.waiters {
+            let _ = waiter.send(response.clone());
+        }
+        if ok {
+            Some(child.covered)
+        } else {
+            None
+        }
+    }
+
+    pub fn status(&self, changes: u64) -> SnapshotStatus {
+        SnapshotStatus {
+            pid: self.child.as_ref().and_then(|child| match child.task {
+                Task::Process(pid) => Some(pid.as_raw()),
+                Task::Thread(_) => None,
+            }),
+            started_at: self.child.as_ref().map(|child| child.started_at),
+            last_success: self.last_success,
+            last_duration: self.last_duration,
+            last_status: self.last_status.clone(),
+            changes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_rule() {
+        let rule: SaveRule = "900 1".parse().unwrap();
+        assert_eq!(
+            rule,
+            SaveRule {
+                secs: 900,
+                changes: 1
+            }
+        );
+        assert!("900".parse::<SaveRule>().is_err());
+        assert!("0 1".parse::<SaveRule>().is_err());
+        assert!("900 x".parse::<SaveRule>().is_err());
+
+        assert!(!rule.is_due(Duration::from_secs(899), 10));
+        assert!(!rule.is_due(Duration::from_secs(900), 0));
+        assert!(rule.is_due(Duration::from_secs(900), 1));
+    }
+
+    #[test]
+    fn test_parse_save_rules() {
+        let rules = parse_save_rules(&["900 1", "60 100"]).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].changes, 100);
+
+        // an empty directive turns the rules before it off
+        assert!(parse_save_rules(&[""]).unwrap().is_empty());
+        assert_eq!(
+            parse_save_rules(&["900 1", " ", "60 100"]).unwrap(),
+            [rules[1]]
+        );
+        assert!(parse_save_rules(&["900 1", "60"]).is_err());
+    }
+
+    #[test]
+    fn test_should_save() {
+        let supervisor = Supervisor::new();
+        assert!(!supervisor.should_save(&[], 100));
+
+        // due as soon as there are enough changes
+        let rules = [SaveRule {
+            secs: 0,
+            changes: 10,
+        }];
+        assert!(!supervisor.should_save(&rules, 9));
+        assert!(supervisor.should_save(&rules, 10));
+    }
+}
@@ -1,26 +1,188 @@
-use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use bytes::Bytes;
+use crossbeam_channel::{bounded, select, tick, unbounded, Receiver, Sender};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
-use db::db::{Value, DB};
+use db::db::{CounterResult, Lease, LeaseOptions, StoreResult, Value, DB};
+use db::memory::MemoryStats;
+use db::shard::shard_for;
 use nix::{
     libc::_exit,
-    sys::wait::waitpid,
     unistd::{fork, ForkResult},
 };
 
+use super::supervisor::{SaveRule, SnapshotStatus, Supervisor, Task};
+
 #[derive(Debug)]
 pub enum Request {
-    Set { key: String, value: Value },
-    Get { key: String },
-    Snapshot { wait: bool },
+    Set {
+        key: String,
+        value: Value,
+    },
+    Add {
+        key: String,
+        value: Value,
+    },
+    Replace {
+        key: String,
+        value: Value,
+    },
+    Append {
+        key: String,
+        data: Bytes,
+    },
+    Prepend {
+        key: String,
+        data: Bytes,
+    },
+    Cas {
+        key: String,
+        value: Value,
+        cas: u64,
+    },
+    Get {
+        key: String,
+    },
+    GetMany {
+        keys: Vec<String>,
+    },
+    GetAndTouch {
+        keys: Vec<String>,
+        exptime: u32,
+    },
+    GetWithLease {
+        key: String,
+        opts: LeaseOptions,
+    },
+    Delete {
+        key: String,
+    },
+    Invalidate {
+        key: String,
+        exptime: Option<u32>,
+    },
+    Incr {
+        key: String,
+        delta: u64,
+    },
+    Decr {
+        key: String,
+        delta: u64,
+    },
+    Touch {
+        key: String,
+        exptime: u32,
+    },
+    // the writes of the meta protocol, answered with the value they left
+    MetaSet {
+        key: String,
+        value: Value,
+        mode: SetMode,
+        cas: Option<u64>,
+    },
+    MetaArithmetic {
+        key: String,
+        op: Arithmetic,
+    },
+    Snapshot {
+        wait: bool,
+    },
+    SnapshotStatus,
+    MemoryStats,
+    // copies of the values this shard does not own with `shards` shards
+    Export {
+        shard_id: usize,
+        shards: usize,
+    },
+    Import {
+        entries: Vec<(String, Value)>,
+    },
+    // deletes the values this shard does not own with `shards` shards
+    DropForeign {
+        shard_id: usize,
+        shards: usize,
+    },
+    // stops the worker once its running snapshot is done
+    Stop,
 }
 
-#[derive(Debug)]
+// how a meta set stores its value, a set with a cas token stores like cas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetMode {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+}
+
+// a meta arithmetic command, the exptimes are absolute
+#[derive(Debug, Clone, Copy)]
+pub struct Arithmetic {
+    pub incr: bool,
+    pub delta: u64,
+    // creates a missing counter with this value and exptime
+    pub vivify: Option<(u64, u32)>,
+    // new exptime of the counter
+    pub touch: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
 pub enum Response {
     Stored,
+    NotStored,
+    Exists,
+    NotFound,
     Value(Option<Value>),
+    Values(Vec<Option<Value>>),
+    Leased(Option<(Value, Lease)>),
+    Deleted,
+    Touched,
+    Number(u64),
+    NonNumeric,
+    OutOfMemory,
+    // the mutation could not be written to the append-only log
+    LogFailed,
+    // the response of a meta write and the value it left, none unless the
+    // write succeeded
+    Written(Box<Response>, Option<Value>),
     SnapshotAccepted,
     SnapshotFinished,
     SnapshotFailed,
+    SnapshotStatus(SnapshotStatus),
+    MemoryStats(MemoryStats),
+    // the exported values, in batches sent as they are copied
+    Entries(Receiver<Vec<(String, Value)>>),
+    ExportFailed,
+    // how many values were imported or dropped
+    Moved(usize),
+    Stopped,
+}
+
+impl From<StoreResult> for Response {
+    fn from(res: StoreResult) -> Self {
+        match res {
+            StoreResult::Stored => Response::Stored,
+            StoreResult::NotStored => Response::NotStored,
+            StoreResult::Exists => Response::Exists,
+            StoreResult::NotFound => Response::NotFound,
+            StoreResult::OutOfMemory => Response::OutOfMemory,
+            StoreResult::LogFailed => Response::LogFailed,
+        }
+    }
+}
+
+impl From<CounterResult> for Response {
+    fn from(res: CounterResult) -> Self {
+        match res {
+            CounterResult::Value(n) => Response::Number(n),
+            CounterResult::NotFound => Response::NotFound,
+            CounterResult::NonNumeric => Response::NonNumeric,
+            CounterResult::OutOfMemory => Response::OutOfMemory,
+            CounterResult::LogFailed => Response::LogFailed,
+        }
+    }
 }
 
 pub struct JobQueue {
@@ -28,6 +190,12 @@ pub struct JobQueue {
     request_receiver: Receiver<(Request, Sender<Response>)>,
 }
 
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl JobQueue {
     pub fn new() -> Self {
         let (req_tx, req_rx) = unbounded();
@@ -53,72 +221,436 @@ impl Clone for JobQueue {
     }
 }
 
+// how a snapshot gets a consistent view of the DB while it keeps serving
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotEngine {
+    // a forked child writes the DB, sharing its pages with the worker until
+    // either side modifies them
+    #[default]
+    Fork,
+    // a thread writes a copy of the DB frozen in constant time, without
+    // forking a process that may need as much memory as the worker
+    Thread,
+}
+
+impl FromStr for SnapshotEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fork" => Ok(SnapshotEngine::Fork),
+            "thread" => Ok(SnapshotEngine::Thread),
+            _ => Err(format!("Invalid snapshot engine: {}", s)),
+        }
+    }
+}
+
+// how often expired keys are swept from memory and save rules are checked
+const EXPIRE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// how often a running snapshot process is checked for completion
+const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how many values an export sends at once
+const EXPORT_BATCH_SIZE: usize = 1000;
+
 pub struct Worker {
     job_queue: JobQueue,
     db: DB,
+    supervisor: Supervisor,
+    save_rules: Vec<SaveRule>,
+    engine: SnapshotEngine,
 }
 
 impl Worker {
-    pub fn new(job_queue: JobQueue, db: DB) -> Self {
-        Worker { job_queue, db }
+    pub fn new(job_queue: JobQueue, db: DB, save_rules: Vec<SaveRule>) -> Self {
+        Worker {
+            job_queue,
+            db,
+            supervisor: Supervisor::new(),
+            save_rules,
+            engine: SnapshotEngine::default(),
+        }
+    }
+
+    pub fn set_snapshot_engine(&mut self, engine: SnapshotEngine) {
+        self.engine = engine;
+        // only a thread needs a copy of the DB, a forked child shares it
+        self.db.set_persistent(engine == SnapshotEngine::Thread);
     }
 
     pub fn run(&mut self) {
+        let ticker = tick(EXPIRE_SWEEP_INTERVAL);
+        let snapshot_ticker = tick(SNAPSHOT_POLL_INTERVAL);
         loop {
-            let (req, res_tx) = self.job_queue.request_receiver.recv().unwrap();
+            let (req, res_tx) = select! {
+                recv(self.job_queue.request_receiver) -> job => job.unwrap(),
+                recv(snapshot_ticker) -> _ => {
+                    self.poll_snapshot();
+                    continue;
+                }
+                recv(ticker) -> _ => {
+                    self.db.remove_expired();
+                    if let Err(err) = self.db.sync_log() {
+                        println!("Failed to sync append-only log: {}", err);
+                    }
+                    if self.supervisor.should_save(&self.save_rules, self.db.dirty()) {
+                        println!("Start taking snapshot after {} changes", self.db.dirty());
+                        self.start_snapshot();
+                    }
+                    continue;
+                }
+            };
             match req {
                 Request::Set { key, value } => {
-                    self.db.insert(key, value);
-                    res_tx.send(Response::Stored).unwrap();
+                    let res = self.db.insert(key, value);
+                    res_tx.send(Response::from(res)).unwrap();
+                }
+                Request::Add { key, value } => {
+                    let res = self.db.add(key, value);
+                    res_tx.send(Response::from(res)).unwrap();
+                }
+                Request::Replace { key, value } => {
+                    let res = self.db.replace(key, value);
+                    res_tx.send(Response::from(res)).unwrap();
+                }
+                Request::Append { key, data } => {
+                    let res = self.db.append(&key, &data);
+                    res_tx.send(Response::from(res)).unwrap();
+                }
+                Request::Prepend { key, data } => {
+                    let res = self.db.prepend(&key, &data);
+                    res_tx.send(Response::from(res)).unwrap();
+                }
+                Request::Cas { key, value, cas } => {
+                    let res = self.db.cas(key, value, cas);
+                    res_tx.send(Response::from(res)).unwrap();
                 }
                 Request::Get { key } => {
                     let res = self.db.get(&key).cloned();
                     res_tx.send(Response::Value(res)).unwrap();
                 }
+                Request::GetMany { keys } => {
+                    let res = keys.iter().map(|key| self.db.get(key).cloned()).collect();
+                    res_tx.send(Response::Values(res)).unwrap();
+                }
+                Request::GetAndTouch { keys, exptime } => {
+                    let res = keys
+                        .iter()
+                        .map(|key| {
+                            self.db.touch(key, exptime)?;
+                            Ok(self.db.get(key).cloned())
+                        })
+                        .collect::<Result<_, std::io::Error>>();
+                    let res = match res {
+                        Ok(values) => Response::Values(values),
+                        Err(_) => Response::LogFailed,
+                    };
+                    res_tx.send(res).unwrap();
+                }
+                Request::GetWithLease { key, opts } => {
+                    let res = match self.db.get_with_lease(&key, opts) {
+                        Ok(res) => Response::Leased(res),
+                        Err(_) => Response::LogFailed,
+                    };
+                    res_tx.send(res).unwrap();
+                }
+                Request::Invalidate { key, exptime } => {
+                    let res = match self.db.invalidate(&key, exptime) {
+                        Ok(true) => Response::Deleted,
+                        Ok(false) => Response::NotFound,
+                        Err(_) => Response::LogFailed,
+                    };
+                    res_tx.send(res).unwrap();
+                }
+                Request::Delete { key } => {
+                    let res = match self.db.delete(&key) {
+                        Ok(true) => Response::Deleted,
+                        Ok(false) => Response::NotFound,
+                        Err(_) => Response::LogFailed,
+                    };
+                    res_tx.send(res).unwrap();
+                }
+                Request::Incr { key, delta } => {
+                    let res = self.db.incr(&key, delta);
+                    res_tx.send(Response::from(res)).unwrap();
+                }
+                Request::Decr { key, delta } => {
+                    let res = self.db.decr(&key, delta);
+                    res_tx.send(Response::from(res)).unwrap();
+                }
+                Request::Touch { key, exptime } => {
+                    let res = match self.db.touch(&key, exptime) {
+                        Ok(true) => Response::Touched,
+                        Ok(false) => Response::NotFound,
+                        Err(_) => Response::LogFailed,
+                    };
+                    res_tx.send(res).unwrap();
+                }
+                Request::MetaSet {
+                    key,
+                    value,
+                    mode,
+                    cas,
+                } => {
+                    let res = match (mode, cas) {
+                        (SetMode::Set, Some(cas)) => self.db.cas(key.clone(), value, cas),
+                        (SetMode::Set, None) => self.db.insert(key.clone(), value),
+                        (SetMode::Add, _) => self.db.add(key.clone(), value),
+                        (SetMode::Replace, _) => self.db.replace(key.clone(), value),
+                        (SetMode::Append, _) => self.db.append(&key, &value.data),
+                        (SetMode::Prepend, _) => self.db.prepend(&key, &value.data),
+                    };
+                    let value = match res {
+                        StoreResult::Stored => self.db.get(&key).cloned(),
+                        _ => None,
+                    };
+                    res_tx
+                        .send(Response::Written(Box::new(Response::from(res)), value))
+                        .unwrap();
+                }
+                Request::MetaArithmetic { key, op } => {
+                    let res = self.arithmetic(&key, op);
+                    let value = match res {
+                        CounterResult::Value(_) => self.db.get(&key).cloned(),
+                        _ => None,
+                    };
+                    res_tx
+                        .send(Response::Written(Box::new(Response::from(res)), value))
+                        .unwrap();
+                }
+                Request::Snapshot { wait } if self.supervisor.in_progress() => {
+                    // the running snapshot misses the writes made since it
+                    // started, a waiting client gets one started after it
+                    if wait {
+                        self.supervisor.queue(res_tx);
+                    } else {
+                        res_tx.send(Response::SnapshotAccepted).unwrap();
+                    }
+                }
                 Request::Snapshot { wait } => {
-                    // fork and snapshot
-                    match unsafe { fork() } {
-                        Ok(ForkResult::Parent { child, .. }) => {
-                            if !wait {
-                                res_tx.send(Response::SnapshotAccepted).unwrap();
-                                return;
-                            }
-                            if waitpid(child, None).is_err() {
-                                println!("Failed to wait for snapshot process");
-                                res_tx.send(Response::SnapshotFailed).unwrap();
-                            }
-                            res_tx.send(Response::SnapshotFinished).unwrap();
-                        }
-                        Ok(ForkResult::Child) => {
-                            match self.db.snapshot() {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    println!("Failed to snapshot: {}", err);
-                                    unsafe { _exit(1) }
-                                }
-                            }
-                            unsafe { _exit(0) };
-                        }
-                        Err(_) => {
-                            println!("Failed to fork");
-                            res_tx.send(Response::SnapshotFailed).unwrap();
+                    if !self.start_snapshot() {
+                        res_tx.send(Response::SnapshotFailed).unwrap();
+                    } else if wait {
+                        self.supervisor.wait(res_tx);
+                    } else {
+                        res_tx.send(Response::SnapshotAccepted).unwrap();
+                    }
+                }
+                Request::SnapshotStatus => {
+                    let status = self.supervisor.status(self.db.dirty());
+                    res_tx.send(Response::SnapshotStatus(status)).unwrap();
+                }
+                Request::MemoryStats => {
+                    let stats = self.db.memory_stats();
+                    res_tx.send(Response::MemoryStats(stats)).unwrap();
+                }
+                Request::Export { shard_id, shards } => {
+                    let res = match self.spawn_export(shard_id, shards) {
+                        Some(batches) => Response::Entries(batches),
+                        None => Response::ExportFailed,
+                    };
+                    res_tx.send(res).unwrap();
+                }
+                Request::Import { entries } => {
+                    let n = entries.len();
+                    let mut res = Response::Moved(n);
+                    for (key, value) in entries {
+                        match self.db.import(key, value) {
+                            StoreResult::OutOfMemory => res = Response::OutOfMemory,
+                            StoreResult::LogFailed => res = Response::LogFailed,
+                            _ => continue,
                         }
+                        break;
                     }
+                    res_tx.send(res).unwrap();
+                }
+                Request::DropForeign { shard_id, shards } => {
+                    let n = self.db.drop_foreign(shard_id, shards);
+                    res_tx.send(Response::Moved(n)).unwrap();
+                }
+                Request::Stop => {
+                    // a snapshot process must still be reaped
+                    while self.supervisor.in_progress() {
+                        thread::sleep(SNAPSHOT_POLL_INTERVAL);
+                        self.poll_snapshot();
+                    }
+                    res_tx.send(Response::Stopped).unwrap();
+                    return;
                 }
             }
         }
     }
+
+    // reaps a finished snapshot and starts the one clients queued for
+    fn poll_snapshot(&mut self) {
+        if let Some(covered) = self.supervisor.poll() {
+            self.db.mark_saved(covered);
+        }
+        let queued = self.supervisor.take_queued();
+        if queued.is_empty() {
+            return;
+        }
+        if self.start_snapshot() {
+            for waiter in queued {
+                self.supervisor.wait(waiter);
+            }
+        } else {
+            for waiter in queued {
+                let _ = waiter.send(Response::SnapshotFailed);
+            }
+        }
+    }
+
+    // starts writing a snapshot in the background, it is tracked by the
+    // supervisor. returns false if it could not be started
+    fn start_snapshot(&mut self) -> bool {
+        // the snapshot will cover every log segment before the new one
+        if let Err(err) = self.db.rotate_log() {
+            println!("Failed to rotate append-only log: {}", err);
+        }
+
+        match self.engine {
+            SnapshotEngine::Fork => self.fork_snapshot(),
+            SnapshotEngine::Thread => self.spawn_snapshot(),
+        }
+    }
+
+    fn fork_snapshot(&mut self) -> bool {
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child, .. }) => {
+                self.supervisor
+                    .start(Task::Process(child), self.db.mutations());
+                true
+            }
+            Ok(ForkResult::Child) => {
+                match self.db.snapshot() {
+                    Ok(_) => {}
+                    Err(err) => {
+                        println!("Failed to snapshot: {}", err);
+                        unsafe { _exit(1) }
+                    }
+                }
+                unsafe { _exit(0) };
+            }
+            Err(_) => {
+                println!("Failed to fork");
+                self.supervisor.start_failed("fork_failed");
+                false
+            }
+        }
+    }
+
+    // updates a counter, creating it first if it is missing and op asks for
+    // it, then sets its exptime if op asks for it
+    fn arithmetic(&mut self, key: &str, op: Arithmetic) -> CounterResult {
+        let update = |db: &mut DB| {
+            if op.incr {
+                db.incr(key, op.delta)
+            } else {
+                db.decr(key, op.delta)
+            }
+        };
+        let mut res = update(&mut self.db);
+        if let (CounterResult::NotFound, Some((initial, exptime))) = (&res, op.vivify) {
+            let value = Value {
+                flags: 0,
+                exptime,
+                cas: 0,
+                data: initial.to_string().into(),
+                stale: false,
+                token_sent: false,
+            };
+            res = match self.db.add(key.to_string(), value) {
+                StoreResult::Stored => CounterResult::Value(initial),
+                StoreResult::OutOfMemory => CounterResult::OutOfMemory,
+                StoreResult::LogFailed => CounterResult::LogFailed,
+                _ => update(&mut self.db),
+            };
+        }
+        if let (CounterResult::Value(_), Some(exptime)) = (&res, op.touch) {
+            if self.db.touch(key, exptime).is_err() {
+                return CounterResult::LogFailed;
+            }
+        }
+        res
+    }
+
+    // copies the values of a frozen copy of the DB from another thread, so
+    // that the shard keeps serving while they are imported elsewhere. the
+    // next batch is only copied once the previous one was taken. with the
+    // fork engine the DB is copied before the thread starts
+    fn spawn_export(
+        &self,
+        shard_id: usize,
+        shards: usize,
+    ) -> Option<Receiver<Vec<(String, Value)>>> {
+        let frozen = self.db.freeze();
+        let (batch_tx, batch_rx) = bounded(1);
+        let spawned = thread::Builder::new()
+            .name("export".to_string())
+            .spawn(move || {
+                let mut batch = Vec::with_capacity(EXPORT_BATCH_SIZE);
+                for (key, value) in frozen.entries() {
+                    if shard_for(key, shards) == shard_id {
+                        continue;
+                    }
+                    batch.push((key.clone(), value.clone()));
+                    if batch.len() == EXPORT_BATCH_SIZE
+                        && batch_tx.send(batch.split_off(0)).is_err()
+                    {
+                        return;
+                    }
+                }
+                if !batch.is_empty() {
+                    let _ = batch_tx.send(batch);
+                }
+            });
+        match spawned {
+            Ok(_) => Some(batch_rx),
+            Err(err) => {
+                println!("Failed to start export thread: {}", err);
+                None
+            }
+        }
+    }
+
+    fn spawn_snapshot(&mut self) -> bool {
+        let frozen = self.db.freeze();
+        let (done_tx, done_rx) = bounded(1);
+        let spawned = thread::Builder::new()
+            .name("snapshot".to_string())
+            .spawn(move || {
+                let ok = match frozen.snapshot() {
+                    Ok(_) => true,
+                    Err(err) => {
+                        println!("Failed to snapshot: {}", err);
+                        false
+                    }
+                };
+                let _ = done_tx.send(ok);
+            });
+        match spawned {
+            Ok(_) => {
+                self.supervisor
+                    .start(Task::Thread(done_rx), self.db.mutations());
+                true
+            }
+            Err(err) => {
+                println!("Failed to start snapshot thread: {}", err);
+                self.supervisor.start_failed("spawn_failed");
+                false
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
 
     #[tokio::test]
     async fn test_worker_set_and_get() {
         let job_queue = JobQueue::new();
-        let mut worker = Worker::new(job_queue.clone(), DB::new("/tmp".to_string()));
+        let mut worker = Worker::new(job_queue.clone(), DB::new("/tmp".to_string()), vec![]);
 
         // Start the worker in a separate task
         thread::spawn(move || {
@@ -129,7 +661,11 @@ mod tests {
         let key = "key1".to_string();
         let value = Value {
             flags: 0,
-            data: "value1".to_string(),
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from("value1"),
+            stale: false,
+            token_sent: false,
         };
         let _ = job_queue
             .send_request(Request::Set {
@@ -150,4 +686,246 @@ mod tests {
         };
         assert_eq!(actual.data, value.data);
     }
+
+    #[test]
+    fn test_worker_snapshot_lifecycle() {
+        let job_queue = JobQueue::new();
+        let path = "/tmp/test_worker_snapshot_lifecycle";
+        let mut worker = Worker::new(job_queue.clone(), DB::new(path.to_string()), vec![]);
+        thread::spawn(move || {
+            worker.run();
+        });
+
+        // the second request waits for a snapshot started after the first
+        let first = job_queue.send_request(Request::Snapshot { wait: true });
+        let second = job_queue.send_request(Request::Snapshot { wait: true });
+        for receiver in [first, second] {
+            match receiver.recv().unwrap() {
+                Response::SnapshotFinished => {}
+                _ => panic!("Unexpected response"),
+            }
+        }
+
+        // the child has been reaped and its outcome recorded
+        let status = match job_queue
+            .send_request(Request::SnapshotStatus)
+            .recv()
+            .unwrap()
+        {
+            Response::SnapshotStatus(status) => status,
+            _ => panic!("Unexpected response"),
+        };
+        assert!(status.pid.is_none());
+        assert!(status.last_success.is_some());
+        assert_eq!(status.last_status.as_deref(), Some("ok"));
+
+        // the worker keeps serving after a non-waiting snapshot
+        match job_queue
+            .send_request(Request::Snapshot { wait: false })
+            .recv()
+            .unwrap()
+        {
+            Response::SnapshotAccepted => {}
+            _ => panic!("Unexpected response"),
+        }
+        match job_queue
+            .send_request(Request::Get {
+                key: "key".to_string(),
+            })
+            .recv()
+            .unwrap()
+        {
+            Response::Value(None) => {}
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    #[test]
+    fn test_worker_snapshot_covers_waiters() {
+        let dir = "/tmp/test_worker_snapshot_covers_waiters";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let job_queue = JobQueue::new();
+        let db = DB::new(format!("{}/snapshot", dir));
+        let mut worker = Worker::new(job_queue.clone(), db, vec![]);
+        thread::spawn(move || {
+            worker.run();
+        });
+
+        let set = |key: &str| {
+            let value = Value {
+                flags: 0,
+                exptime: 0,
+                cas: 0,
+                data: Bytes::from("value"),
+                stale: false,
+                token_sent: false,
+            };
+            job_queue
+                .send_request(Request::Set {
+                    key: key.to_string(),
+                    value,
+                })
+                .recv()
+                .unwrap();
+        };
+
+        // the second snapshot is asked for while the first one runs, after
+        // a write the first one cannot cover
+        set("key1");
+        let first = job_queue.send_request(Request::Snapshot { wait: true });
+        set("key2");
+        let second = job_queue.send_request(Request::Snapshot { wait: true });
+        for receiver in [first, second] {
+            match receiver.recv().unwrap() {
+                Response::SnapshotFinished => {}
+                _ => panic!("Unexpected response"),
+            }
+        }
+
+        let mut db = DB::new(format!("{}/snapshot", dir));
+        db.restore(db::db::RestoreMode::Strict).unwrap();
+        assert!(db.get("key1").is_some());
+        assert!(db.get("key2").is_some());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_worker_save_rules() {
+        let job_queue = JobQueue::new();
+        let path = "/tmp/test_worker_save_rules";
+        let rules = vec![SaveRule {
+            secs: 0,
+            changes: 2,
+        }];
+        let mut worker = Worker::new(job_queue.clone(), DB::new(path.to_string()), rules);
+        thread::spawn(move || {
+            worker.run();
+        });
+
+        let status = || match job_queue
+            .send_request(Request::SnapshotStatus)
+            .recv()
+            .unwrap()
+        {
+            Response::SnapshotStatus(status) => status,
+            _ => panic!("Unexpected response"),
+        };
+        let set = |key: &str| {
+            let value = Value {
+                flags: 0,
+                exptime: 0,
+                cas: 0,
+                data: Bytes::from("value"),
+                stale: false,
+                token_sent: false,
+            };
+            job_queue
+                .send_request(Request::Set {
+                    key: key.to_string(),
+                    value,
+                })
+                .recv()
+                .unwrap();
+        };
+
+        // one change is not enough
+        set("key1");
+        thread::sleep(Duration::from_millis(1500));
+        let before = status();
+        assert_eq!(before.changes, 1);
+        assert!(before.last_success.is_none());
+
+        // the second one triggers a snapshot, which resets the counter
+        set("key2");
+        for _ in 0..50 {
+            let after = status();
+            if after.last_success.is_some() {
+                assert_eq!(after.changes, 0);
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("No snapshot was taken");
+    }
+
+    #[test]
+    fn test_worker_thread_snapshot() {
+        let dir = "/tmp/test_worker_thread_snapshot";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let job_queue = JobQueue::new();
+        let db = DB::new(format!("{}/snapshot", dir));
+        let mut worker = Worker::new(job_queue.clone(), db, vec![]);
+        worker.set_snapshot_engine(SnapshotEngine::Thread);
+        thread::spawn(move || {
+            worker.run();
+        });
+
+        let value = Value {
+            flags: 0,
+            exptime: 0,
+            cas: 0,
+            data: Bytes::from("value"),
+            stale: false,
+            token_sent: false,
+        };
+        job_queue
+            .send_request(Request::Set {
+                key: "key".to_string(),
+                value: value.clone(),
+            })
+            .recv()
+            .unwrap();
+
+        // the persistent maps are counted, and so is the changed key until
+        // the snapshot covers it
+        let used = || match job_queue.send_request(Request::MemoryStats).recv() {
+            Ok(Response::MemoryStats(stats)) => stats.used,
+            _ => panic!("Unexpected response"),
+        };
+        let mut memory = db::memory::Memory::default();
+        let plain = memory.item_size("key", &value);
+        memory.set_persistent(true);
+        let size = memory.item_size("key", &value);
+        assert!(size > plain);
+        assert_eq!(used(), size + memory.changed_size("key"));
+
+        match job_queue
+            .send_request(Request::Snapshot { wait: true })
+            .recv()
+            .unwrap()
+        {
+            Response::SnapshotFinished => {}
+            _ => panic!("Unexpected response"),
+        }
+        assert_eq!(used(), size);
+
+        // no process was forked for it
+        let status = match job_queue
+            .send_request(Request::SnapshotStatus)
+            .recv()
+            .unwrap()
+        {
+            Response::SnapshotStatus(status) => status,
+            _ => panic!("Unexpected response"),
+        };
+        assert!(status.pid.is_none());
+        assert_eq!(status.last_status.as_deref(), Some("ok"));
+        assert_eq!(status.changes, 0);
+
+        let mut db = DB::new(format!("{}/snapshot", dir));
+        db.restore(db::db::RestoreMode::Strict).unwrap();
+        assert_eq!(db.get("key").unwrap().data, "value".as_bytes());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_engine() {
+        assert_eq!("fork".parse(), Ok(SnapshotEngine::Fork));
+        assert_eq!("thread".parse(), Ok(SnapshotEngine::Thread));
+        assert!("cow".parse::<SnapshotEngine>().is_err());
+    }
 }
@@ -10,9 +10,7 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 async fn start_server(snapshot_path: &str) -> Child {
-    Command::new("cargo")
-        .arg("run")
-        .arg("--")
+    Command::new(env!("CARGO_BIN_EXE_horcrux"))
         .arg("--snapshot-path")
         .arg(snapshot_path)
         .arg("--port")
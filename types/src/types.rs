@@ -1,8 +1,19 @@
 #[derive(Debug)]
 pub enum HorcruxError {
     ParseRequest(String),
+    UnknownCommand,
     RestoreDB(String),
+    Snapshot(String),
+    Reshard(String),
+    // mutations are refused while keys move between shards
+    Resharding,
     Connection(String),
+    BadDataChunk,
+    TooLarge,
+    // the memory limit is reached and eviction is disabled
+    OutOfMemory,
+    // the mutation was applied but could not be written to the append-only log
+    LogFailed,
     Ignorable,
     Internal,
 }
@@ -11,8 +22,16 @@ impl std::fmt::Display for HorcruxError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             HorcruxError::ParseRequest(msg) => write!(f, "Failed to parse request: {}", msg),
+            HorcruxError::UnknownCommand => write!(f, "Unknown command"),
             HorcruxError::RestoreDB(msg) => write!(f, "Failed to restore DB: {}", msg),
+            HorcruxError::Snapshot(msg) => write!(f, "Failed to take snapshot: {}", msg),
+            HorcruxError::Reshard(msg) => write!(f, "Failed to reshard: {}", msg),
+            HorcruxError::Resharding => write!(f, "Resharding in progress"),
             HorcruxError::Connection(msg) => write!(f, "Connection error: {}", msg),
+            HorcruxError::BadDataChunk => write!(f, "Bad data chunk"),
+            HorcruxError::TooLarge => write!(f, "Object too large for cache"),
+            HorcruxError::OutOfMemory => write!(f, "Out of memory"),
+            HorcruxError::LogFailed => write!(f, "Failed to write to append-only log"),
             HorcruxError::Ignorable => write!(f, "Ignorable error"),
             HorcruxError::Internal => write!(f, "Internal error"),
         }
@@ -20,3 +39,9 @@ impl std::fmt::Display for HorcruxError {
 }
 
 impl std::error::Error for HorcruxError {}
+
+impl From<std::io::Error> for HorcruxError {
+    fn from(err: std::io::Error) -> Self {
+        HorcruxError::Connection(err.to_string())
+    }
+}